@@ -0,0 +1,411 @@
+/*
+ * `cert` subcommand: inspect, export, rotate and install the proxy's CA.
+ *
+ * The CA is created on first start in `~/.local/share/anime-games-proxy/ca`.
+ * Games only accept the proxy's certificates once the CA is trusted in their
+ * Wine prefix, so rotating it means installing it again.
+ */
+
+use openssl::{asn1::Asn1Time, hash::MessageDigest, x509::X509};
+use std::{
+    error::Error,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use crate::config::Config;
+use crate::get_wine::WineRunner;
+use crate::proxy::{
+    DEFAULT_CA_VALID_DAYS, MAX_CA_VALID_DAYS, ca_dir, generate_ca_files, install_cert_into_wine,
+    leaf_dir,
+};
+use crate::umu_run::UmuRun;
+
+/// The CA expires soon if it has fewer days left than this.
+const EXPIRY_WARNING_DAYS: i32 = 30;
+
+#[derive(clap::Subcommand, Debug)]
+pub enum CertCommand {
+    /// Show the CA certificate's subject, fingerprints and expiry
+    Show,
+    /// Write the CA certificate (never the key) to a file
+    Export {
+        file: PathBuf,
+
+        /// Defaults to DER for `.der` and `.cer` files, PEM otherwise
+        #[arg(long, value_enum)]
+        format: Option<CertFormat>,
+    },
+    /// Replace the CA with a new key pair; the old one is kept in a backup directory
+    Rotate {
+        /// How long the new CA is valid
+        #[arg(long, default_value_t = DEFAULT_CA_VALID_DAYS)]
+        days: u32,
+    },
+    /// Install the CA certificate into Wine prefixes (by default those in the config)
+    Trust {
+        /// Wine prefix to install into; can be given several times
+        #[arg(long = "prefix", value_name = "PATH")]
+        prefixes: Vec<PathBuf>,
+
+        /// Wine binary, Wine runner or Proton directory to use. Defaults to the runner saved
+        /// for the prefix's game, then `wine`
+        #[arg(long, value_name = "PATH")]
+        runner: Option<PathBuf>,
+
+        /// Install again even if the prefix already has this CA
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertFormat {
+    Pem,
+    Der,
+}
+
+fn ca_paths() -> Result<(PathBuf, PathBuf), Box<dyn Error>> {
+    let dir = ca_dir().ok_or("Could not determine data directory")?;
+    Ok((dir.join("cert.crt"), dir.join("private.key")))
+}
+
+/// Reads the CA certificate. Fails if there is no CA yet.
+fn read_ca() -> Result<(PathBuf, X509), Box<dyn Error>> {
+    let (cert_path, key_path) = ca_paths()?;
+    if !cert_path.exists() || !key_path.exists() {
+        return Err(format!(
+            "There is no CA in {} yet. It's created when the proxy first starts, or by `anime-games-linux cert rotate`.",
+            cert_path.parent().unwrap().display()
+        )
+        .into());
+    }
+    let pem = std::fs::read(&cert_path)
+        .map_err(|e| format!("Could not read {}: {}", cert_path.display(), e))?;
+    Ok((cert_path, X509::from_pem(&pem)?))
+}
+
+/// Loads the CA certificate, generating the CA first if there is none yet.
+fn load_ca() -> Result<(PathBuf, X509), Box<dyn Error>> {
+    let (cert_path, key_path) = ca_paths()?;
+    if !cert_path.exists() || !key_path.exists() {
+        println!("No CA certificate yet, generating one.");
+        generate_ca_files(
+            cert_path.parent().and_then(Path::parent).unwrap(),
+            DEFAULT_CA_VALID_DAYS,
+        )?;
+    }
+    read_ca()
+}
+
+fn fingerprint(cert: &X509, digest: MessageDigest) -> Result<String, Box<dyn Error>> {
+    Ok(cert
+        .digest(digest)?
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":"))
+}
+
+fn subject(cert: &X509) -> String {
+    cert.subject_name()
+        .entries()
+        .map(|entry| {
+            let value = entry
+                .data()
+                .as_utf8()
+                .map(|v| v.to_string())
+                .unwrap_or_default();
+            format!(
+                "{}={}",
+                entry.object().nid().short_name().unwrap_or("?"),
+                value
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn show() -> Result<(), Box<dyn Error>> {
+    let (cert_path, cert) = read_ca()?;
+    let (_, key_path) = ca_paths()?;
+    let days_left = Asn1Time::days_from_now(0)?.diff(cert.not_after())?.days;
+    let key_mode = std::fs::metadata(&key_path)?.permissions().mode() & 0o777;
+
+    println!("CA certificate: {}", cert_path.display());
+    println!("  Subject:     {}", subject(&cert));
+    println!(
+        "  SHA-256:     {}",
+        fingerprint(&cert, MessageDigest::sha256())?
+    );
+    println!(
+        "  SHA-1:       {} (thumbprint shown by Windows)",
+        fingerprint(&cert, MessageDigest::sha1())?
+    );
+    println!("  Valid from:  {}", cert.not_before());
+    println!(
+        "  Valid until: {} ({} days left)",
+        cert.not_after(),
+        days_left
+    );
+    println!(
+        "  Private key: {} (mode {:o})",
+        key_path.display(),
+        key_mode
+    );
+
+    if days_left < 0 {
+        println!("\nThe CA has expired. Run `anime-games-linux cert rotate` and `cert trust`.");
+    } else if days_left < EXPIRY_WARNING_DAYS {
+        println!("\nThe CA expires soon. Run `anime-games-linux cert rotate` and `cert trust`.");
+    }
+    if key_mode & 0o077 != 0 {
+        println!(
+            "\nThe private key can be read by other users. It's fixed the next time the proxy starts."
+        );
+    }
+    Ok(())
+}
+
+fn export(file: &Path, format: Option<CertFormat>) -> Result<(), Box<dyn Error>> {
+    let (_, cert) = load_ca()?;
+    let format = format.unwrap_or_else(|| {
+        match file
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_lowercase)
+            .as_deref()
+        {
+            Some("der" | "cer") => CertFormat::Der,
+            _ => CertFormat::Pem,
+        }
+    });
+    let data = match format {
+        CertFormat::Pem => cert.to_pem()?,
+        CertFormat::Der => cert.to_der()?,
+    };
+    std::fs::write(file, data).map_err(|e| format!("Could not write {}: {}", file.display(), e))?;
+    println!("Wrote CA certificate ({:?}) to {}", format, file.display());
+    Ok(())
+}
+
+/// Moves `files` from `from` to `to`, moving them back if one fails.
+fn move_files(files: &[&str], from: &Path, to: &Path) -> std::io::Result<()> {
+    for (i, name) in files.iter().enumerate() {
+        if let Err(e) = std::fs::rename(from.join(name), to.join(name)) {
+            for name in &files[..i] {
+                let _ = std::fs::rename(to.join(name), from.join(name));
+            }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the CA in `dir` with a new one valid for `days`. The new pair is
+/// generated next to it first, so the old CA stays in place if that fails.
+/// Returns the backup directory of the old pair, if there was one.
+fn replace_ca(dir: &Path, days: u32) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    const FILES: [&str; 2] = ["cert.crt", "private.key"];
+
+    std::fs::create_dir_all(dir)?;
+    let staging = tempfile::Builder::new()
+        .prefix(".rotate-")
+        .tempdir_in(dir)?;
+    generate_ca_files(staging.path(), days)?;
+    let new = staging.path().join("ca");
+
+    // Keep the old pair, in case something still needs it.
+    let old: Vec<&str> = FILES
+        .into_iter()
+        .filter(|name| dir.join(name).exists())
+        .collect();
+    let backup = if old.is_empty() {
+        None
+    } else {
+        let backup = dir.join(format!(
+            "backup-{}",
+            chrono::Local::now().format("%Y%m%d-%H%M%S")
+        ));
+        std::fs::create_dir_all(&backup)?;
+        std::fs::set_permissions(&backup, std::fs::Permissions::from_mode(0o700))?;
+        move_files(&old, dir, &backup)?;
+        Some(backup)
+    };
+
+    if let Err(e) = move_files(&FILES, &new, dir) {
+        if let Some(backup) = &backup {
+            move_files(&old, backup, dir).map_err(|restore| {
+                format!(
+                    "Could not install the new CA ({}) nor restore the old one from {} ({})",
+                    e,
+                    backup.display(),
+                    restore
+                )
+            })?;
+        }
+        return Err(format!("Could not install the new CA: {}", e).into());
+    }
+    Ok(backup)
+}
+
+fn rotate(days: u32) -> Result<(), Box<dyn Error>> {
+    if !(1..=MAX_CA_VALID_DAYS).contains(&days) {
+        return Err(format!("--days must be between 1 and {}", MAX_CA_VALID_DAYS).into());
+    }
+    let (cert_path, _) = ca_paths()?;
+    let dir = cert_path.parent().unwrap();
+
+    if let Some(backup) = replace_ca(dir, days)? {
+        println!("Moved the old CA to {}", backup.display());
+    }
+
+    // Leaf certificates signed by the old CA are useless now.
+    let leaves = leaf_dir(dir);
+    if leaves.exists() {
+        std::fs::remove_dir_all(&leaves)
+            .map_err(|e| format!("Could not remove {}: {}", leaves.display(), e))?;
+        println!("Removed the leaf certificates of the old CA");
+    }
+    println!();
+    show()?;
+    println!(
+        "\nGames only accept the new CA once it's installed in their prefix: run `anime-games-linux cert trust`."
+    );
+    println!("A proxy that is already running keeps using the old CA until it's restarted.");
+    Ok(())
+}
+
+/// The Wine prefixes of the games in the config and `WINEPREFIX`.
+fn configured_prefixes(config: &Config) -> Vec<PathBuf> {
+    let mut prefixes: Vec<PathBuf> = config
+        .games
+        .iter()
+        .map(|game| game.wineprefix.trim())
+        .chain(std::env::var("WINEPREFIX").ok().as_deref().map(str::trim))
+        .filter(|prefix| !prefix.is_empty())
+        .map(PathBuf::from)
+        .collect();
+    prefixes.sort();
+    prefixes.dedup();
+    prefixes
+}
+
+/// The runner saved for the game using `prefix`, or `wine`.
+fn runner_for_prefix(config: &Config, prefix: &Path) -> WineRunner {
+    let saved = config
+        .games
+        .iter()
+        .find(|game| Path::new(game.wineprefix.trim()) == prefix)
+        .map(|game| game.proton_wine_path.trim())
+        .filter(|runner| !runner.is_empty());
+    match saved {
+        Some(runner) => WineRunner::from_path(Path::new(runner), &UmuRun::default().binary),
+        None => WineRunner::Wine(PathBuf::from("wine")),
+    }
+}
+
+fn trust(prefixes: &[PathBuf], runner: Option<&Path>, force: bool) -> Result<(), Box<dyn Error>> {
+    let (cert_path, _) = load_ca()?;
+    let config = Config::load()?;
+    let prefixes = if prefixes.is_empty() {
+        configured_prefixes(&config)
+    } else {
+        prefixes.to_vec()
+    };
+    if prefixes.is_empty() {
+        return Err(
+            "No Wine prefixes found in the config or WINEPREFIX; name one with --prefix".into(),
+        );
+    }
+
+    let (mut installed, mut failed) = (0, 0);
+    for prefix in &prefixes {
+        if !prefix.is_dir() {
+            println!("[SKIP] {}: not a directory", prefix.display());
+            continue;
+        }
+        let runner = match runner {
+            Some(runner) => WineRunner::from_path(runner, &UmuRun::default().binary),
+            None => runner_for_prefix(&config, prefix),
+        };
+        match install_cert_into_wine(&cert_path, prefix, &runner, force) {
+            Ok(true) => {
+                println!("[ OK ] {}", prefix.display());
+                installed += 1;
+            }
+            Ok(false) => {
+                println!("[ OK ] {}: already installed", prefix.display());
+                installed += 1;
+            }
+            Err(e) => {
+                println!("[FAIL] {}: {}", prefix.display(), e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(format!("Failed to install the CA into {} prefixes", failed).into());
+    }
+    if installed == 0 {
+        return Err("The CA wasn't installed into any prefix".into());
+    }
+    Ok(())
+}
+
+pub fn run_cert(command: &CertCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        CertCommand::Show => show(),
+        CertCommand::Export { file, format } => export(file, *format),
+        CertCommand::Rotate { days } => rotate(*days),
+        CertCommand::Trust {
+            prefixes,
+            runner,
+            force,
+        } => trust(prefixes, runner.as_deref(), *force),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ca_cert(dir: &Path) -> X509 {
+        X509::from_pem(&std::fs::read(dir.join("cert.crt")).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn rotate_checks_days() {
+        for days in [0, MAX_CA_VALID_DAYS + 1] {
+            let e = rotate(days).unwrap_err().to_string();
+            assert!(e.starts_with("--days must be between 1 and"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn replace_keeps_old_ca_on_failure() {
+        let data = tempfile::tempdir().unwrap();
+        let dir = data.path().join("ca");
+        generate_ca_files(data.path(), 30).unwrap();
+        let old = ca_cert(&dir).to_der().unwrap();
+
+        assert!(replace_ca(&dir, MAX_CA_VALID_DAYS + 1).is_err());
+        assert_eq!(ca_cert(&dir).to_der().unwrap(), old);
+        assert!(dir.join("private.key").exists());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+
+        let backup = replace_ca(&dir, 30).unwrap().unwrap();
+        assert_ne!(ca_cert(&dir).to_der().unwrap(), old);
+        assert_eq!(ca_cert(&backup).to_der().unwrap(), old);
+        assert!(backup.join("private.key").exists());
+    }
+
+    #[test]
+    fn replace_without_old_ca() {
+        let data = tempfile::tempdir().unwrap();
+        let dir = data.path().join("ca");
+        assert_eq!(replace_ca(&dir, 30).unwrap(), None);
+        assert!(dir.join("cert.crt").exists());
+        assert!(dir.join("private.key").exists());
+    }
+}
@@ -0,0 +1,88 @@
+use reqwest::Client;
+use serde_json::{Value, json};
+
+use crate::proxy::{ControlInfo, control_file};
+
+#[derive(clap::Subcommand, Debug)]
+pub enum CtlCommand {
+    /// Show the upstream servers, recording state and number of active requests
+    Status,
+    /// Switch the upstream server, e.g. `https://staging.example.com:443`
+    Upstream {
+        server: String,
+
+        /// Only switch the server of this game (as named in the config)
+        #[arg(long)]
+        game: Option<String>,
+    },
+    /// Start or stop HAR recording
+    Record {
+        #[command(subcommand)]
+        action: RecordAction,
+    },
+    /// List requests currently being proxied
+    Connections,
+    /// Reload the interception and rewrite rules and the host overrides from the config file
+    Reload,
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum RecordAction {
+    /// Start recording to a HAR file (a recording in progress is written first)
+    Start { file: std::path::PathBuf },
+    /// Stop recording and write the HAR file
+    Stop,
+}
+
+/// Sends a command to the running proxy and prints its JSON reply.
+pub async fn run_ctl(command: &CtlCommand) -> Result<(), Box<dyn std::error::Error>> {
+    let path = control_file().ok_or("Could not determine data directory")?;
+    let info: ControlInfo = serde_json::from_str(
+        &std::fs::read_to_string(&path)
+            .map_err(|e| format!("No running proxy found ({}): {}", path.display(), e))?,
+    )?;
+
+    let (method, endpoint, body) = match command {
+        CtlCommand::Status => ("GET", "/status", None),
+        CtlCommand::Upstream { server, game } => (
+            "POST",
+            "/upstream",
+            Some(json!({ "server": server, "game": game })),
+        ),
+        CtlCommand::Record {
+            action: RecordAction::Start { file },
+        } => {
+            // The proxy may run in another directory.
+            let file = std::path::absolute(file)?;
+            ("POST", "/record/start", Some(json!({ "path": file })))
+        }
+        CtlCommand::Record {
+            action: RecordAction::Stop,
+        } => ("POST", "/record/stop", None),
+        CtlCommand::Connections => ("GET", "/connections", None),
+        CtlCommand::Reload => ("POST", "/reload", None),
+    };
+
+    let url = format!("http://127.0.0.1:{}{}", info.port, endpoint);
+    let client = Client::new();
+    let request = match method {
+        "GET" => client.get(&url),
+        _ => client.post(&url).json(&body.unwrap_or(Value::Null)),
+    };
+
+    let response = request.bearer_auth(&info.token).send().await.map_err(|e| {
+        format!(
+            "Could not reach the proxy (pid {}), is it still running? {}",
+            info.pid, e
+        )
+    })?;
+    let status = response.status();
+    let reply: Value = response.json().await?;
+
+    println!("{}", serde_json::to_string_pretty(&reply)?);
+    if !status.is_success() {
+        return Err(format!("Proxy returned {}", status).into());
+    }
+
+    Ok(())
+}
@@ -3,6 +3,7 @@ use std::env::home_dir;
 use std::fs;
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
+use std::process::Command;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum RunnerType {
@@ -81,6 +82,20 @@ fn detect_runner_type(path: &Path) -> Option<RunnerType> {
     }
 }
 
+/// The `wine` binary of a runner directory found by `find_wine_binaries`, or
+/// `path` itself if it's already a binary.
+pub fn wine_binary(path: &Path) -> PathBuf {
+    if path.is_dir() {
+        for name in ["wine", "wine64", "wine32"] {
+            let exe = path.join("bin").join(name);
+            if is_executable(&exe) {
+                return exe;
+            }
+        }
+    }
+    path.to_path_buf()
+}
+
 pub fn find_proton_dirs() -> Vec<Runner> {
     let candidates = [
         "~/.steam/steam/steamapps/common",
@@ -146,3 +161,55 @@ pub fn find_wine_binaries() -> Vec<Runner> {
     runners.retain(|r| unique.contains(&r.path));
     runners
 }
+
+/// How to run Windows programs inside a prefix.
+#[derive(Debug, Clone)]
+pub enum WineRunner {
+    /// A `wine` binary.
+    Wine(PathBuf),
+    /// A Proton directory, run through umu-run.
+    Proton { umu_run: String, proton: PathBuf },
+}
+
+impl WineRunner {
+    /// A Proton directory (it has a `proton` script), otherwise a Wine runner
+    /// directory or binary.
+    pub fn from_path(path: &Path, umu_run: &str) -> Self {
+        if path.join("proton").is_file() {
+            WineRunner::Proton {
+                umu_run: umu_run.to_string(),
+                proton: path.to_path_buf(),
+            }
+        } else {
+            WineRunner::Wine(wine_binary(path))
+        }
+    }
+
+    /// Command running `program` in `prefix`.
+    pub fn command(&self, prefix: &Path, program: &str) -> Command {
+        let mut cmd = match self {
+            WineRunner::Wine(wine) => Command::new(wine),
+            WineRunner::Proton { umu_run, proton } => {
+                let mut cmd = Command::new(umu_run);
+                cmd.env("PROTONPATH", proton).env(
+                    "GAMEID",
+                    std::env::var("GAMEID").unwrap_or_else(|_| "umu-default".to_string()),
+                );
+                cmd
+            }
+        };
+        cmd.env("WINEPREFIX", prefix).arg(program);
+        cmd
+    }
+}
+
+impl std::fmt::Display for WineRunner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WineRunner::Wine(wine) => write!(f, "{}", wine.display()),
+            WineRunner::Proton { umu_run, proton } => {
+                write!(f, "{} with {}", umu_run, proton.display())
+            }
+        }
+    }
+}
@@ -1,17 +1,114 @@
+mod cert;
 mod config;
+mod ctl;
 mod game;
 mod get_wine;
+mod preflight;
 mod proxy;
+mod registry;
 mod run;
 mod umu_run;
 mod utils;
 
 use clap::Parser;
-use proxy::{create_proxy, set_proxy_addr};
+use proxy::{
+    ProxyPort, Shutdown, check_server, clear_failover, create_proxy, finish_recording, load_mock,
+    local_addr, log_telemetry_summary, parse_listen, remove_control_file, set_access, set_failover,
+    set_game_server, set_hosts, set_proxy_addr, set_rewrites, set_rules, set_telemetry,
+    set_upstream_headers, set_upstream_proxy, set_upstream_tls, set_websocket_logging,
+    start_control, start_recording, start_socks,
+};
+use std::net::{IpAddr, Ipv4Addr};
+
+#[derive(clap::Args, Debug, Clone)]
+struct ServerOptions {
+    #[arg(long, help = "Set server address (overrides config)")]
+    server: Option<String>,
+
+    #[arg(long, help = "Set server port (overrides config)")]
+    server_port: Option<String>,
+
+    #[arg(long, help = "Use SSL for server connection (overrides config)")]
+    use_ssl: bool,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct ProxyOptions {
+    #[command(flatten)]
+    server: ServerOptions,
+
+    #[arg(
+        long,
+        visible_alias = "port",
+        help = "Set proxy port, or \"auto\" to fall back to a free port (overrides config)"
+    )]
+    proxy_port: Option<String>,
+
+    /// Record intercepted traffic to a HAR file, written when the session ends
+    #[arg(long, value_name = "FILE")]
+    record_har: Option<std::path::PathBuf>,
+
+    /// Answer redirected requests from a fixture directory or HAR file instead of the server
+    #[arg(long, value_name = "DIR|FILE")]
+    mock: Option<std::path::PathBuf>,
+
+    /// Also accept SOCKS5 connections on this port (0 for any free port)
+    #[arg(long, value_name = "PORT")]
+    socks_port: Option<u16>,
+
+    /// Log the frames of WebSocket connections to the private server
+    #[arg(long)]
+    log_websocket: bool,
+
+    /// Address to listen on, e.g. 0.0.0.0 or :: (default 127.0.0.1). Other
+    /// addresses than loopback need `sharing` in the config
+    #[arg(long, value_name = "ADDR")]
+    listen: Option<String>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Commands {
+    /// Run the proxy without launching a game, until Ctrl-C or SIGTERM
+    Proxy {
+        #[command(flatten)]
+        options: ProxyOptions,
+    },
+    /// Check that the private server can be reached, without launching a game
+    Preflight {
+        #[command(flatten)]
+        server: ServerOptions,
+
+        /// Use the server settings of this game (as named in the config)
+        #[arg(long)]
+        game: Option<String>,
+
+        /// Also send a GET request to this path
+        #[arg(long)]
+        path: Option<String>,
+    },
+    /// Show, export, rotate or install the proxy's CA certificate
+    Cert {
+        #[command(subcommand)]
+        command: cert::CertCommand,
+    },
+    /// Control a running proxy
+    Ctl {
+        #[command(subcommand)]
+        command: ctl::CtlCommand,
+    },
+}
 
 #[derive(clap::Parser, Debug)]
-#[command(name = "anime-games-ps-linux", version)]
+#[command(
+    name = "anime-games-ps-linux",
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Cli {
+    #[command(subcommand)]
+    subcommand: Option<Commands>,
+
     /// Run as wrapper only
     #[arg(short, long)]
     wrapper: bool,
@@ -20,57 +117,55 @@ struct Cli {
     #[arg(long)]
     wineprefix: Option<String>,
 
-    #[arg(long, help = "Set server address (overrides config)")]
-    server: Option<String>,
-
-    #[arg(long, help = "Set server port (overrides config)")]
-    server_port: Option<String>,
+    /// Launch the game even if the server can't be reached
+    #[arg(long)]
+    skip_preflight: bool,
 
-    #[arg(long, help = "Use SSL for server connection (overrides config)")]
-    use_ssl: bool,
+    /// Don't install the proxy CA into the game's Wine prefix
+    #[arg(long)]
+    skip_ca_install: bool,
 
-    #[arg(long, help = "Set proxy port (overrides config)")]
-    proxy_port: Option<String>,
+    #[command(flatten)]
+    proxy: ProxyOptions,
 
     /// Command to execute. Example: `/path/to/game.exe`
     #[arg(required = true, trailing_var_arg = true)]
     command: Vec<String>,
 }
 
+use crate::config::{Config, ConfigGame};
 use crate::utils::{detect_game, modify_command_for_game};
 
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt()
-        .with_max_level(
-            #[cfg(debug_assertions)]
-            tracing::Level::DEBUG,
-            #[cfg(not(debug_assertions))]
-            tracing::Level::INFO,
-        )
-        .without_time()
-        .init();
-
-    let cli = Cli::parse();
-
-    let args: Vec<String> = cli.command;
-
-    println!("Anime Games PS Linux Wrapper");
-    println!("========================\n");
-
-    let config = config::Config::load().unwrap_or_default();
-
-    let proxy_port: String = std::env::var("PROXY_PORT").unwrap_or_else(|_| {
-        cli.proxy_port
+fn proxy_port(config: &Config, options: &ProxyOptions) -> ProxyPort {
+    let port = std::env::var("PROXY_PORT").unwrap_or_else(|_| {
+        options
+            .proxy_port
             .clone()
-            .or_else(|| Some(config.proxy_port.clone()))
-            .unwrap_or_else(|| "8080".to_string())
+            .unwrap_or_else(|| config.proxy_port.clone())
     });
+    port.parse().unwrap_or_else(|e| {
+        tracing::error!("{}", e);
+        std::process::exit(1);
+    })
+}
 
-    let game_info = detect_game(&args);
+fn socks_port(config: &Config, options: &ProxyOptions) -> Option<u16> {
+    match std::env::var("SOCKS_PORT") {
+        Ok(port) => match port.parse() {
+            Ok(port) => Some(port),
+            Err(_) => {
+                tracing::error!("Invalid SOCKS port {:?}", port);
+                std::process::exit(1);
+            }
+        },
+        Err(_) => options.socks_port.or(config.socks_port),
+    }
+}
 
+/// The default upstream: environment, then command line, then the game's config entry.
+fn server_addr(config: &Config, options: &ServerOptions, game_exe: &str) -> String {
     let (cfg_server, cfg_server_port, cfg_use_ssl) = config
-        .server_for_exe(&game_info.game_exe)
+        .server_for_exe(game_exe)
         .or_else(|| {
             config
                 .games
@@ -81,20 +176,368 @@ async fn main() {
 
     let server: String = std::env::var("SERVER")
         .ok()
-        .or_else(|| cli.server.clone())
+        .or_else(|| options.server.clone())
         .unwrap_or(cfg_server);
     let server_port: String = std::env::var("SERVER_PORT")
         .ok()
-        .or_else(|| cli.server_port.clone())
+        .or_else(|| options.server_port.clone())
         .unwrap_or(cfg_server_port.to_string());
-    let use_ssl: bool = std::env::var_os("USE_SSL").is_some() || cli.use_ssl || cfg_use_ssl;
+    let use_ssl: bool = std::env::var_os("USE_SSL").is_some() || options.use_ssl || cfg_use_ssl;
 
-    let server_addr = format!(
+    format!(
         "{}://{}:{}",
         if use_ssl { "https" } else { "http" },
         server,
         server_port
-    );
+    )
+}
+
+struct RunningProxy {
+    port: u16,
+    socks_port: Option<u16>,
+    handle: tokio::task::JoinHandle<()>,
+}
+
+/// Starts the proxy, the SOCKS5 listener and the control API. Exits if the proxy can't listen.
+/// They stop once `shutdown` is triggered.
+async fn start_proxy(
+    proxy_port: ProxyPort,
+    socks_port: Option<u16>,
+    socks_udp: bool,
+    shutdown: &Shutdown,
+) -> RunningProxy {
+    let (port, handle) = match create_proxy(proxy_port, shutdown.clone()).await {
+        Ok(proxy) => proxy,
+        Err(e) => {
+            tracing::error!("Failed to start proxy: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let socks_port = socks_port.map(|socks_port| {
+        match start_socks(socks_port, port, socks_udp, shutdown.clone()) {
+            Ok(socks_port) => socks_port,
+            Err(e) => {
+                tracing::error!("Failed to start SOCKS5 server: {}", e);
+                std::process::exit(1);
+            }
+        }
+    });
+    if let Err(e) = start_control(port) {
+        tracing::warn!("Failed to start control API: {}", e);
+    }
+    RunningProxy {
+        port,
+        socks_port,
+        handle,
+    }
+}
+
+fn configure_upstream_headers(server_addr: &str, headers: &proxy::UpstreamHeaders) {
+    if *headers == proxy::UpstreamHeaders::default() {
+        return;
+    }
+    if let Err(e) = set_upstream_headers(server_addr, headers) {
+        tracing::error!("Invalid header rules for {}: {}", server_addr, e);
+        std::process::exit(1);
+    }
+}
+
+fn configure_upstream_tls(server_addr: &str, tls: &proxy::UpstreamTls) {
+    if *tls == proxy::UpstreamTls::default() {
+        return;
+    }
+    if let Err(e) = set_upstream_tls(server_addr, tls) {
+        tracing::error!("Invalid TLS settings for {}: {}", server_addr, e);
+        std::process::exit(1);
+    }
+}
+
+/// Loads the certificate settings of every server and the upstream proxy,
+/// which all connections to the servers use.
+fn configure_connections(config: &Config, server_addr: &str, game_exe: &str) {
+    for game in &config.games {
+        configure_upstream_tls(&game.server_addr(), &game.tls);
+        configure_upstream_headers(&game.server_addr(), &game.headers);
+        for upstream in &game.upstreams {
+            if upstream.use_ssl {
+                configure_upstream_tls(
+                    &upstream.server_addr(),
+                    upstream.tls.as_ref().unwrap_or(&game.tls),
+                );
+            }
+            configure_upstream_headers(
+                &upstream.server_addr(),
+                upstream.headers.as_ref().unwrap_or(&game.headers),
+            );
+        }
+    }
+    if let Some(game) = config.game_for_exe(game_exe) {
+        configure_upstream_tls(server_addr, &game.tls);
+        configure_upstream_headers(server_addr, &game.headers);
+    }
+
+    if let Err(e) = set_hosts(&config.hosts) {
+        tracing::error!("{}", e);
+        std::process::exit(1);
+    }
+
+    let upstream_proxy = std::env::var("UPSTREAM_PROXY")
+        .ok()
+        .filter(|proxy| !proxy.is_empty())
+        .or_else(|| config.upstream_proxy.clone());
+    match upstream_proxy.map(|proxy| proxy.parse::<proxy::UpstreamProxy>()) {
+        Some(Ok(proxy)) => set_upstream_proxy(Some(proxy)),
+        Some(Err(e)) => {
+            tracing::error!("{}", e);
+            std::process::exit(1);
+        }
+        None => {}
+    }
+}
+
+/// The game whose `server` and fallbacks back the default upstream, unless
+/// the server was overridden.
+fn default_game<'a>(
+    config: &'a Config,
+    server_addr: &str,
+    game_exe: &str,
+) -> Option<&'a ConfigGame> {
+    config
+        .game_for_exe(game_exe)
+        .or_else(|| config.games.first())
+        .filter(|game| game.server_addr() == server_addr)
+}
+
+/// Loads the rules, upstreams, recording and mock settings into the proxy.
+fn configure_proxy(config: &Config, options: &ProxyOptions, server_addr: &str, game_exe: &str) {
+    if let Err(e) = check_server(server_addr) {
+        tracing::error!("{}", e);
+        std::process::exit(1);
+    }
+    // Only the servers of the game being launched have to be usable; other
+    // games with a bad server are left out, so their rules use the default.
+    let launched = default_game(config, server_addr, game_exe);
+    let games: Vec<&ConfigGame> = config
+        .games
+        .iter()
+        .filter(|game| {
+            let Err(e) = game
+                .failover_servers()
+                .iter()
+                .try_for_each(|server| check_server(server))
+            else {
+                return true;
+            };
+            if launched.is_some_and(|launched| std::ptr::eq(launched, *game)) {
+                tracing::error!("{}", e);
+                std::process::exit(1);
+            }
+            tracing::warn!("Skipping servers of {}: {}", game.name, e);
+            false
+        })
+        .collect();
+
+    // Set the target server address
+    set_proxy_addr(server_addr.to_string());
+    set_rules(&config.rules);
+    if let Err(e) = set_rewrites(&config.rewrites) {
+        tracing::error!("{}", e);
+        std::process::exit(1);
+    }
+    set_telemetry(&config.telemetry);
+
+    // Rules can route to other games' servers; the running game keeps the overrides above.
+    for game in games {
+        set_game_server(&game.name, game.server_addr());
+        set_failover(
+            Some(&game.name),
+            game.failover_servers(),
+            &game.health_check,
+        );
+    }
+    if let Some(exe_name) = std::path::Path::new(game_exe)
+        .file_name()
+        .and_then(|s| s.to_str())
+    {
+        set_game_server(exe_name, server_addr.to_string());
+    }
+
+    // Fail over between the servers of the game the default upstream came from,
+    // unless it was overridden.
+    match default_game(config, server_addr, game_exe) {
+        Some(game) => set_failover(None, game.failover_servers(), &game.health_check),
+        None => {
+            if let Some(game) = config.game_for_exe(game_exe) {
+                clear_failover(Some(&game.name));
+            }
+        }
+    }
+
+    configure_connections(config, server_addr, game_exe);
+
+    let listen = options
+        .listen
+        .as_deref()
+        .or(config.listen.as_deref())
+        .map(parse_listen)
+        .transpose()
+        .and_then(|listen| {
+            set_access(
+                listen.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                config.sharing.as_ref(),
+            )
+            .map_err(|e| e.to_string())
+        });
+    if let Err(e) = listen {
+        tracing::error!("{}", e);
+        std::process::exit(1);
+    }
+
+    set_websocket_logging(options.log_websocket);
+    if let Some(path) = &options.record_har {
+        start_recording(path);
+    }
+    if let Some(path) = &options.mock
+        && let Err(e) = load_mock(path)
+    {
+        tracing::error!(
+            "Failed to load mock fixtures from {}: {}",
+            path.display(),
+            e
+        );
+        std::process::exit(1);
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt()
+        .with_max_level(
+            #[cfg(debug_assertions)]
+            tracing::Level::DEBUG,
+            #[cfg(not(debug_assertions))]
+            tracing::Level::INFO,
+        )
+        .without_time()
+        .init();
+
+    let cli = Cli::parse();
+
+    if let Some(Commands::Ctl { command }) = &cli.subcommand {
+        if let Err(e) = ctl::run_ctl(command).await {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Commands::Cert { command }) = &cli.subcommand {
+        if let Err(e) = cert::run_cert(command) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    println!("Anime Games PS Linux Wrapper");
+    println!("========================\n");
+
+    let config = Config::load().unwrap_or_default();
+
+    let exit_code = match cli.subcommand {
+        Some(Commands::Proxy { ref options }) => run_proxy(&config, options).await,
+        Some(Commands::Preflight {
+            ref server,
+            ref game,
+            ref path,
+        }) => run_preflight(&config, server, game.as_deref(), path.as_deref()).await,
+        Some(Commands::Cert { .. } | Commands::Ctl { .. }) => unreachable!(),
+        None => run_game(&cli, &config).await,
+    };
+
+    remove_control_file();
+    log_telemetry_summary();
+
+    std::process::exit(exit_code);
+}
+
+/// The servers requests can go to: the game's `server` and its fallbacks, or
+/// only `server_addr` if it was overridden.
+fn preflight_servers(config: &Config, server_addr: &str, game_exe: &str) -> Vec<String> {
+    match default_game(config, server_addr, game_exe) {
+        Some(game) => game.failover_servers(),
+        None => vec![server_addr.to_string()],
+    }
+}
+
+/// Checks the server of `game` (or the default server) and prints a report.
+async fn run_preflight(
+    config: &Config,
+    options: &ServerOptions,
+    game: Option<&str>,
+    path: Option<&str>,
+) -> i32 {
+    let game_exe = game.unwrap_or_default();
+    let server_addr = server_addr(config, options, game_exe);
+    configure_connections(config, &server_addr, game_exe);
+
+    let servers = preflight_servers(config, &server_addr, game_exe);
+    let path = path.or(config.preflight_path.as_deref());
+    if preflight::check_servers(&servers, path).await {
+        0
+    } else {
+        1
+    }
+}
+
+/// Runs the proxy in the foreground until Ctrl-C or SIGTERM.
+async fn run_proxy(config: &Config, options: &ProxyOptions) -> i32 {
+    let proxy_port = proxy_port(config, options);
+    let server_addr = server_addr(config, &options.server, "");
+
+    tracing::info!("Server address: {}", server_addr);
+
+    configure_proxy(config, options, &server_addr, "");
+
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("Failed to install SIGTERM signal handler");
+
+    let shutdown = Shutdown::new();
+    let proxy = start_proxy(
+        proxy_port,
+        socks_port(config, options),
+        config.socks_udp,
+        &shutdown,
+    )
+    .await;
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {
+            tracing::info!("Received Ctrl-C, stopping proxy");
+        }
+        _ = sigterm.recv() => {
+            tracing::info!("Received SIGTERM, stopping proxy");
+        }
+    }
+
+    // Let requests in flight finish before the recording is written.
+    shutdown.trigger();
+    let _ = proxy.handle.await;
+
+    if let Err(e) = finish_recording() {
+        tracing::error!("Failed to write HAR file: {}", e);
+    }
+
+    0
+}
+
+async fn run_game(cli: &Cli, config: &Config) -> i32 {
+    let args: Vec<String> = cli.command.clone();
+
+    let proxy_port = proxy_port(config, &cli.proxy);
+
+    let game_info = detect_game(&args);
+
+    let server_addr = server_addr(config, &cli.proxy.server, &game_info.game_exe);
 
     let modified_args = match modify_command_for_game(&args, &game_info) {
         Ok(args) => args,
@@ -104,25 +547,57 @@ async fn main() {
         }
     };
 
-    tracing::info!("Starting proxy on port {}", proxy_port);
     tracing::info!("Server address: {}", server_addr);
 
-    // Set the target server address
-    set_proxy_addr(server_addr);
+    configure_proxy(config, &cli.proxy, &server_addr, &game_info.game_exe);
 
-    // Create and start the proxy server
-    let proxy_handle = create_proxy(proxy_port.parse().unwrap()).await;
+    // Nothing is sent to the server when answering from fixtures.
+    if !cli.skip_preflight && cli.proxy.mock.is_none() {
+        let servers = preflight_servers(config, &server_addr, &game_info.game_exe);
+        if !preflight::check_servers(&servers, config.preflight_path.as_deref()).await {
+            tracing::error!(
+                "The server can't be reached, not launching the game. Check the server settings, or launch anyway with --skip-preflight."
+            );
+            return 1;
+        }
+    }
 
-    tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+    // Stopped once the game is gone, so it can finish its last requests.
+    let shutdown = Shutdown::new();
 
-    tracing::info!("Proxy server is running...");
+    // Create and start the proxy server. It is listening once this returns.
+    let running = start_proxy(
+        proxy_port,
+        socks_port(config, &cli.proxy),
+        config.socks_udp,
+        &shutdown,
+    )
+    .await;
 
-    let proxy = format!("http://127.0.0.1:{}", proxy_port);
-    let exit_code = run::execute_command(modified_args, proxy, &game_info, cli.wrapper)
-        .await
-        .unwrap_or(1);
+    tracing::info!("Proxy server is running on port {}", running.port);
 
-    proxy_handle.abort();
+    let proxy = format!("http://{}", local_addr(running.port));
+    let socks_proxy = running
+        .socks_port
+        .map(|port| format!("socks5h://{}", local_addr(port)));
+    let exit_code = run::execute_command(
+        modified_args,
+        proxy,
+        socks_proxy,
+        &game_info,
+        cli.wrapper,
+        !cli.skip_ca_install,
+    )
+    .await
+    .unwrap_or(1);
 
-    std::process::exit(exit_code);
+    // Requests the game sent before exiting may still be in flight.
+    shutdown.trigger();
+    let _ = running.handle.await;
+
+    if let Err(e) = finish_recording() {
+        tracing::error!("Failed to write HAR file: {}", e);
+    }
+
+    exit_code
 }
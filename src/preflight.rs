@@ -0,0 +1,489 @@
+/*
+ * Connectivity check against the private server, run before the game starts.
+ *
+ * Goes through the same steps as the proxy's own connections: DNS, TCP (through
+ * the upstream proxy if one is set), TLS with the server's certificate settings
+ * and optionally an HTTP request with the server's header rules. A wrong
+ * address or certificate then shows up right away instead of as a network
+ * error in the game.
+ */
+
+use hudsucker::hyper::{self, Body, Request, Uri, header};
+use hyper_rustls::MaybeHttpsStream;
+use std::{
+    future::Future,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use crate::proxy::{apply_upstream_headers, dial, host_override, tls_connect, upstream_proxy};
+
+/// How long each step may take.
+const STEP_TIMEOUT: Duration = Duration::from_secs(5);
+
+enum Outcome {
+    Pass(String),
+    Fail(String),
+    Skip(String),
+}
+
+struct Step {
+    name: &'static str,
+    outcome: Outcome,
+    elapsed: Option<Duration>,
+}
+
+/// Result of checking one server.
+pub struct Report {
+    server: String,
+    steps: Vec<Step>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        !self
+            .steps
+            .iter()
+            .any(|step| matches!(step.outcome, Outcome::Fail(_)))
+    }
+
+    pub fn print(&self) {
+        println!("Preflight check for {}", self.server);
+        for step in &self.steps {
+            let (label, detail) = match &step.outcome {
+                Outcome::Pass(detail) => (" OK ", detail),
+                Outcome::Fail(detail) => ("FAIL", detail),
+                Outcome::Skip(detail) => ("SKIP", detail),
+            };
+            match step.elapsed {
+                Some(elapsed) => println!(
+                    "  [{}] {:<5} {} ({} ms)",
+                    label,
+                    step.name,
+                    detail,
+                    elapsed.as_millis()
+                ),
+                None => println!("  [{}] {:<5} {}", label, step.name, detail),
+            }
+        }
+        println!(
+            "  Result: {}\n",
+            if self.passed() { "PASS" } else { "FAIL" }
+        );
+    }
+
+    fn push(&mut self, name: &'static str, outcome: Outcome, elapsed: Option<Duration>) {
+        self.steps.push(Step {
+            name,
+            outcome,
+            elapsed,
+        });
+    }
+
+    /// Marks the remaining steps as skipped after `failed` failed.
+    fn skip_rest(&mut self, names: &[&'static str], failed: &str) {
+        for name in names {
+            self.push(
+                name,
+                Outcome::Skip(format!("{} check failed", failed)),
+                None,
+            );
+        }
+    }
+}
+
+/// Runs `step` with the step timeout and measures it.
+async fn timed<T, E: std::fmt::Display>(
+    step: impl Future<Output = Result<T, E>>,
+) -> (Result<T, String>, Duration) {
+    let start = Instant::now();
+    let result = match tokio::time::timeout(STEP_TIMEOUT, step).await {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(_) => Err(format!("no answer within {}s", STEP_TIMEOUT.as_secs())),
+    };
+    (result, start.elapsed())
+}
+
+/// Describes the negotiated TLS version and the server's certificate.
+fn describe_tls(stream: &tokio_rustls::client::TlsStream<tokio::net::TcpStream>) -> String {
+    let (_, connection) = stream.get_ref();
+    let version = connection
+        .protocol_version()
+        .map(|v| format!("{:?}", v))
+        .unwrap_or_else(|| "TLS".to_string());
+    let certificate = connection
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(|cert| openssl::x509::X509::from_der(&cert.0).ok())
+        .map(|cert| {
+            let subject = cert
+                .subject_name()
+                .entries_by_nid(openssl::nid::Nid::COMMONNAME)
+                .next()
+                .and_then(|cn| cn.data().as_utf8().ok())
+                .map(|cn| cn.to_string())
+                .unwrap_or_else(|| "(no common name)".to_string());
+            format!("certificate {}, valid until {}", subject, cert.not_after())
+        });
+    match certificate {
+        Some(certificate) => format!("{}, {}", version, certificate),
+        None => version,
+    }
+}
+
+/// Checks that `server` (`scheme://host:port`) can be reached, and sends a GET
+/// to `http_path` if set.
+pub async fn check(server: &str, http_path: Option<&str>) -> Report {
+    let mut report = Report {
+        server: server.to_string(),
+        steps: Vec::new(),
+    };
+
+    let uri: Uri = match server.parse() {
+        Ok(uri) => uri,
+        Err(e) => {
+            report.push(
+                "URL",
+                Outcome::Fail(format!("invalid address: {}", e)),
+                None,
+            );
+            return report;
+        }
+    };
+    let is_https = uri.scheme_str() == Some("https");
+    let host = uri
+        .host()
+        .unwrap_or_default()
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let port = uri.port_u16().unwrap_or(if is_https { 443 } else { 80 });
+
+    // DNS
+    if let Some(ip) = host_override(&host) {
+        report.push(
+            "DNS",
+            Outcome::Pass(format!("{} -> {} (from `hosts`)", host, ip)),
+            None,
+        );
+    } else if let Some(proxy) = upstream_proxy() {
+        report.push(
+            "DNS",
+            Outcome::Skip(format!("connecting through upstream proxy {}", proxy)),
+            None,
+        );
+    } else if host.parse::<IpAddr>().is_ok() {
+        report.push(
+            "DNS",
+            Outcome::Skip(format!("{} is an IP address", host)),
+            None,
+        );
+    } else {
+        let (result, elapsed) = timed(tokio::net::lookup_host((host.as_str(), port))).await;
+        match result {
+            Ok(addrs) => {
+                let addrs: Vec<String> = addrs.map(|addr| addr.ip().to_string()).collect();
+                report.push(
+                    "DNS",
+                    Outcome::Pass(format!("{} -> {}", host, addrs.join(", "))),
+                    Some(elapsed),
+                );
+            }
+            Err(e) => {
+                report.push(
+                    "DNS",
+                    Outcome::Fail(format!("could not resolve {}: {}", host, e)),
+                    Some(elapsed),
+                );
+                report.skip_rest(&["TCP", "TLS", "HTTP"], "DNS");
+                return report;
+            }
+        }
+    }
+
+    // TCP
+    let (result, elapsed) = timed(dial(&host, port)).await;
+    let tcp = match result {
+        Ok(tcp) => {
+            let detail = match (upstream_proxy(), tcp.peer_addr()) {
+                (Some(proxy), _) => format!("connected to port {} through {}", port, proxy),
+                (None, Ok(peer)) => format!("connected to {}", peer),
+                (None, Err(_)) => format!("connected to port {}", port),
+            };
+            report.push("TCP", Outcome::Pass(detail), Some(elapsed));
+            tcp
+        }
+        Err(e) => {
+            report.push(
+                "TCP",
+                Outcome::Fail(format!("could not connect to port {}: {}", port, e)),
+                Some(elapsed),
+            );
+            report.skip_rest(&["TLS", "HTTP"], "TCP");
+            return report;
+        }
+    };
+
+    // TLS
+    let stream = if is_https {
+        let (result, elapsed) = timed(tls_connect(tcp, &uri)).await;
+        match result {
+            Ok(tls) => {
+                report.push("TLS", Outcome::Pass(describe_tls(&tls)), Some(elapsed));
+                MaybeHttpsStream::Https(tls)
+            }
+            Err(e) => {
+                let hint = if e.contains("certificate") || e.contains("CertificateError") {
+                    " (see `tls` in the game's config)"
+                } else {
+                    ""
+                };
+                report.push(
+                    "TLS",
+                    Outcome::Fail(format!("handshake failed: {}{}", e, hint)),
+                    Some(elapsed),
+                );
+                report.skip_rest(&["HTTP"], "TLS");
+                return report;
+            }
+        }
+    } else {
+        report.push("TLS", Outcome::Skip("use_ssl is off".to_string()), None);
+        MaybeHttpsStream::Http(tcp)
+    };
+
+    // HTTP
+    let Some(path) = http_path else {
+        report.push("HTTP", Outcome::Skip("no probe path set".to_string()), None);
+        return report;
+    };
+    let path = if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("/{}", path)
+    };
+    let authority = uri.authority().map(|a| a.as_str()).unwrap_or_default();
+    let (result, elapsed) = timed(async {
+        let mut request = Request::get(path.as_str())
+            .header(header::HOST, authority)
+            .body(Body::empty())
+            .map_err(|e| format!("invalid path: {}", e))?;
+        // With the server's header rules, like a redirected request. There's
+        // no Host from the game to keep, so the server's address stays.
+        apply_upstream_headers(&uri, request.headers_mut());
+        let (mut sender, connection) = hyper::client::conn::handshake(stream)
+            .await
+            .map_err(|e| e.to_string())?;
+        tokio::spawn(connection);
+        sender
+            .send_request(request)
+            .await
+            .map_err(|e| e.to_string())
+    })
+    .await;
+    match result {
+        Ok(response) if response.status().is_server_error() => report.push(
+            "HTTP",
+            Outcome::Fail(format!("GET {} returned {}", path, response.status())),
+            Some(elapsed),
+        ),
+        Ok(response) => report.push(
+            "HTTP",
+            Outcome::Pass(format!("GET {} returned {}", path, response.status())),
+            Some(elapsed),
+        ),
+        Err(e) => report.push(
+            "HTTP",
+            Outcome::Fail(format!("GET {} failed: {}", path, e)),
+            Some(elapsed),
+        ),
+    }
+
+    report
+}
+
+/// Checks every server in `servers` and prints the reports. Passes if any of
+/// them can be reached, since the others are only fallbacks.
+pub async fn check_servers(servers: &[String], http_path: Option<&str>) -> bool {
+    let mut passed = false;
+    for server in servers {
+        let report = check(server, http_path).await;
+        report.print();
+        passed |= report.passed();
+    }
+    passed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::{UpstreamHeaders, UpstreamTls, set_upstream_headers, set_upstream_tls};
+    use hudsucker::hyper::{
+        Response, Server, StatusCode,
+        service::{make_service_fn, service_fn},
+    };
+    use std::convert::Infallible;
+
+    /// A server that fails requests without the shared secret.
+    fn serve() -> u16 {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let status = if req
+                    .headers()
+                    .get("x-secret")
+                    .is_some_and(|v| v == "hunter2")
+                {
+                    StatusCode::OK
+                } else {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                };
+                Ok::<_, Infallible>(
+                    Response::builder()
+                        .status(status)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+            }))
+        });
+        tokio::spawn(Server::from_tcp(listener).unwrap().serve(make_service));
+        port
+    }
+
+    /// Accepts TLS connections with a self-signed certificate for `localhost`.
+    async fn serve_tls() -> u16 {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![rustls::Certificate(cert.serialize_der().unwrap())],
+                rustls::PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(std::sync::Arc::new(config));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((tcp, _)) = listener.accept().await {
+                let _ = acceptor.accept(tcp).await;
+            }
+        });
+        port
+    }
+
+    /// A port nothing listens on.
+    fn closed_port() -> u16 {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    /// The outcome of every step, e.g. `("TCP", "pass")`.
+    fn outcomes(report: &Report) -> Vec<(&'static str, &'static str)> {
+        report
+            .steps
+            .iter()
+            .map(|step| {
+                let outcome = match step.outcome {
+                    Outcome::Pass(_) => "pass",
+                    Outcome::Fail(_) => "fail",
+                    Outcome::Skip(_) => "skip",
+                };
+                (step.name, outcome)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn dns_failure_skips_the_rest() {
+        let report = check("http://preflight-test.invalid:80", None).await;
+        assert_eq!(
+            outcomes(&report),
+            [
+                ("DNS", "fail"),
+                ("TCP", "skip"),
+                ("TLS", "skip"),
+                ("HTTP", "skip")
+            ]
+        );
+        assert!(!report.passed());
+    }
+
+    #[tokio::test]
+    async fn tcp_pass_and_fail() {
+        let open = format!("http://localhost:{}", serve());
+        let report = check(&open, None).await;
+        assert_eq!(
+            outcomes(&report),
+            [
+                ("DNS", "pass"),
+                ("TCP", "pass"),
+                ("TLS", "skip"),
+                ("HTTP", "skip")
+            ]
+        );
+        assert!(report.passed());
+
+        let closed = format!("http://127.0.0.1:{}", closed_port());
+        let report = check(&closed, Some("/status")).await;
+        assert_eq!(
+            outcomes(&report),
+            [
+                ("DNS", "skip"),
+                ("TCP", "fail"),
+                ("TLS", "skip"),
+                ("HTTP", "skip")
+            ]
+        );
+        assert!(!report.passed());
+    }
+
+    #[tokio::test]
+    async fn tls_pass_and_fail() {
+        let port = serve_tls().await;
+        let rejected = format!("https://localhost:{}", port);
+        let report = check(&rejected, Some("/status")).await;
+        assert_eq!(
+            outcomes(&report),
+            [
+                ("DNS", "pass"),
+                ("TCP", "pass"),
+                ("TLS", "fail"),
+                ("HTTP", "skip")
+            ]
+        );
+        assert!(!report.passed());
+
+        let accepted = format!("https://127.0.0.1:{}", port);
+        let tls: UpstreamTls = serde_json::from_str(r#"{"insecure": true}"#).unwrap();
+        set_upstream_tls(&accepted, &tls).unwrap();
+        let report = check(&accepted, None).await;
+        assert_eq!(
+            outcomes(&report),
+            [
+                ("DNS", "skip"),
+                ("TCP", "pass"),
+                ("TLS", "pass"),
+                ("HTTP", "skip")
+            ]
+        );
+        assert!(report.passed());
+    }
+
+    #[tokio::test]
+    async fn probe_uses_header_rules() {
+        let server = format!("http://127.0.0.1:{}", serve());
+        let report = check(&server, Some("status")).await;
+        assert_eq!(outcomes(&report).last(), Some(&("HTTP", "fail")));
+        assert!(!report.passed());
+
+        let headers: UpstreamHeaders =
+            serde_json::from_str(r#"{"set": {"X-Secret": "hunter2"}}"#).unwrap();
+        set_upstream_headers(&server, &headers).unwrap();
+        let report = check(&server, Some("/status")).await;
+        assert_eq!(outcomes(&report).last(), Some(&("HTTP", "pass")));
+        assert!(report.passed());
+    }
+}
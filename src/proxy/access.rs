@@ -0,0 +1,386 @@
+/*
+ * Who may use the proxy.
+ *
+ * The proxy signs certificates with our CA, so anyone who can reach it can
+ * read and change the traffic going through it. It listens on 127.0.0.1 by
+ * default. Listening on another address shares it with other machines, which
+ * needs `sharing` in the config: clients must come from one of the allowed
+ * networks and send the username and password (Proxy-Authorization Basic for
+ * HTTP, username/password authentication for SOCKS5). Local clients, like the
+ * game and our own SOCKS5 front end, don't need to.
+ */
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use hudsucker::hyper::{Body, HeaderMap, Response, StatusCode, header};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::RwLock,
+};
+
+const REALM: &str = "anime-games-proxy";
+
+/// Settings for sharing the proxy with other machines.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SharingConfig {
+    pub username: String,
+    pub password: String,
+    /// Networks clients may connect from, e.g. `192.168.1.0/24` or `fd00::/8`.
+    pub allow: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = addr
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid network {:?}", s))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("Invalid prefix length in {:?}", s))?,
+            None => max,
+        };
+        // Clients are compared canonically, so `::ffff:192.168.0.0/120` has to
+        // become `192.168.0.0/24`.
+        match network {
+            IpAddr::V6(v6) if prefix >= 96 => match v6.to_ipv4_mapped() {
+                Some(v4) => Ok(Cidr {
+                    network: IpAddr::V4(v4),
+                    prefix: prefix - 96,
+                }),
+                None => Ok(Cidr { network, prefix }),
+            },
+            _ => Ok(Cidr { network, prefix }),
+        }
+    }
+}
+
+impl Cidr {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), ip) => {
+                // IPv4 clients are still in IPv6 networks wider than `::ffff:0:0/96`.
+                let ip = match ip {
+                    IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                    IpAddr::V6(ip) => ip,
+                };
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+struct Access {
+    listen: IpAddr,
+    /// `username:password`, if sharing is set up.
+    credentials: Option<String>,
+    allow: Vec<Cidr>,
+}
+
+static ACCESS: Lazy<RwLock<Access>> = Lazy::new(|| {
+    RwLock::new(Access {
+        listen: IpAddr::V4(Ipv4Addr::LOCALHOST),
+        credentials: None,
+        allow: Vec::new(),
+    })
+});
+
+/// Parses a `--listen` address: an IPv4 or IPv6 address, or `localhost`.
+pub fn parse_listen(s: &str) -> Result<IpAddr, String> {
+    let s = s.trim();
+    if s.eq_ignore_ascii_case("localhost") {
+        return Ok(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+    s.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .map_err(|_| format!("Invalid listen address {:?}, expected an IP address", s))
+}
+
+/// Sets the address the proxy listens on and who may use it. Anything but a
+/// loopback address needs `sharing` with a username, password and allowed networks.
+pub fn set_access(listen: IpAddr, sharing: Option<&SharingConfig>) -> Result<(), Box<dyn Error>> {
+    *ACCESS.write().unwrap() = Access::new(listen, sharing)?;
+    Ok(())
+}
+
+impl Access {
+    fn new(listen: IpAddr, sharing: Option<&SharingConfig>) -> Result<Self, Box<dyn Error>> {
+        let mut access = Access {
+            listen,
+            credentials: None,
+            allow: Vec::new(),
+        };
+
+        if !listen.is_loopback() {
+            let sharing = sharing.ok_or_else(|| {
+                format!(
+                    "Listening on {} would open the proxy to other machines. Set `sharing` (username, password and allow) in the config to share it",
+                    listen
+                )
+            })?;
+            if sharing.username.is_empty() || sharing.password.is_empty() {
+                return Err("`sharing` needs a username and a password".into());
+            }
+            if sharing.username.contains(':') {
+                return Err("The `sharing` username can't contain ':'".into());
+            }
+            if sharing.allow.is_empty() {
+                return Err("`sharing` needs at least one allowed network in `allow`".into());
+            }
+            access.allow = sharing
+                .allow
+                .iter()
+                .map(|cidr| cidr.parse())
+                .collect::<Result<_, _>>()?;
+            access.credentials = Some(format!("{}:{}", sharing.username, sharing.password));
+            tracing::info!(
+                "[ACCESS] Sharing the proxy with {} as user {:?}",
+                sharing.allow.join(", "),
+                sharing.username
+            );
+        }
+
+        Ok(access)
+    }
+
+    fn classify(&self, client: IpAddr) -> ClientKind {
+        let client = client.to_canonical();
+        if client.is_loopback() || client == self.listen.to_canonical() {
+            ClientKind::Local
+        } else if self.allow.iter().any(|cidr| cidr.contains(client)) {
+            ClientKind::Shared
+        } else {
+            ClientKind::Refused
+        }
+    }
+}
+
+/// The address the proxy listens on.
+pub fn listen_addr() -> IpAddr {
+    ACCESS.read().unwrap().listen
+}
+
+/// The address local programs reach the proxy at, for a listener on `port`.
+pub fn local_addr(port: u16) -> SocketAddr {
+    let listen = listen_addr();
+    let ip = match listen {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    SocketAddr::new(ip, port)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum ClientKind {
+    /// On this machine; no credentials needed.
+    Local,
+    /// In an allowed network; needs credentials.
+    Shared,
+    Refused,
+}
+
+pub(super) fn classify(client: IpAddr) -> ClientKind {
+    ACCESS.read().unwrap().classify(client)
+}
+
+/// Checks a username and password against the sharing credentials.
+pub(super) fn check_credentials(username: &[u8], password: &[u8]) -> bool {
+    let access = ACCESS.read().unwrap();
+    let Some(expected) = &access.credentials else {
+        return false;
+    };
+    let mut given = username.to_vec();
+    given.push(b':');
+    given.extend_from_slice(password);
+    expected.len() == given.len() && openssl::memcmp::eq(expected.as_bytes(), &given)
+}
+
+fn basic_credentials(headers: &HeaderMap) -> Option<(Vec<u8>, Vec<u8>)> {
+    let value = headers.get(header::PROXY_AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, encoded) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = BASE64.decode(encoded.trim()).ok()?;
+    let colon = decoded.iter().position(|b| *b == b':')?;
+    Some((decoded[..colon].to_vec(), decoded[colon + 1..].to_vec()))
+}
+
+/// Checks whether `client` may send a request with `headers`. Returns the
+/// response to send instead if not.
+pub(super) fn refuse_request(client: SocketAddr, headers: &HeaderMap) -> Option<Response<Body>> {
+    match classify(client.ip()) {
+        ClientKind::Local => None,
+        ClientKind::Shared => match basic_credentials(headers) {
+            Some((username, password)) if check_credentials(&username, &password) => None,
+            given => {
+                if given.is_some() {
+                    tracing::warn!(
+                        "[ACCESS] Wrong proxy credentials from {}",
+                        client.ip().to_canonical()
+                    );
+                }
+                Some(
+                    Response::builder()
+                        .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+                        .header(
+                            header::PROXY_AUTHENTICATE,
+                            format!("Basic realm=\"{}\"", REALM),
+                        )
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+            }
+        },
+        ClientKind::Refused => {
+            tracing::warn!(
+                "[ACCESS] Refused {}, not in an allowed network",
+                client.ip().to_canonical()
+            );
+            Some(
+                Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_masks() {
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.7")));
+        assert!(cidr("0.0.0.0/0").contains(ip("::ffff:203.0.113.7")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+
+        assert!(cidr("192.168.1.10/32").contains(ip("192.168.1.10")));
+        assert!(!cidr("192.168.1.10/32").contains(ip("192.168.1.11")));
+        assert!(cidr("192.168.1.10").contains(ip("192.168.1.10")));
+
+        assert!(cidr("fd00::1/128").contains(ip("fd00::1")));
+        assert!(!cidr("fd00::1/128").contains(ip("fd00::2")));
+        assert!(cidr("fd00::/8").contains(ip("fd12:3456::1")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+
+        assert!(!cidr("192.168.1.0/24").contains(ip("fd00::1")));
+        assert!(!cidr("fd00::/8").contains(ip("192.168.1.1")));
+    }
+
+    #[test]
+    fn cidr_mapped_networks() {
+        let mapped = cidr("::ffff:192.168.0.0/120");
+        assert_eq!(mapped, cidr("192.168.0.0/24"));
+        assert!(mapped.contains(ip("192.168.0.42")));
+        assert!(mapped.contains(ip("::ffff:192.168.0.42")));
+        assert!(!mapped.contains(ip("192.168.1.42")));
+
+        assert!(cidr("::ffff:0:0/96").contains(ip("10.0.0.1")));
+        assert!(cidr("::/0").contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn cidr_rejects_bad_input() {
+        for bad in [
+            "192.168.1.0/33",
+            "fd00::/129",
+            "192.168.1/24",
+            "host/24",
+            "10.0.0.0/x",
+        ] {
+            assert!(bad.parse::<Cidr>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn malformed_authorization() {
+        fn credentials(value: &str) -> Option<(Vec<u8>, Vec<u8>)> {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::PROXY_AUTHORIZATION, value.parse().unwrap());
+            basic_credentials(&headers)
+        }
+
+        assert_eq!(basic_credentials(&HeaderMap::new()), None);
+        assert_eq!(
+            credentials(&format!("Basic {}", BASE64.encode("user:pa:ss"))),
+            Some((b"user".to_vec(), b"pa:ss".to_vec()))
+        );
+        assert_eq!(
+            credentials(&format!("basic {}", BASE64.encode("user:"))),
+            Some((b"user".to_vec(), Vec::new()))
+        );
+        assert_eq!(credentials("Basic"), None);
+        assert_eq!(credentials("Basic !!!"), None);
+        assert_eq!(
+            credentials(&format!("Basic {}", BASE64.encode("user"))),
+            None
+        );
+        assert_eq!(
+            credentials(&format!("Bearer {}", BASE64.encode("user:pass"))),
+            None
+        );
+    }
+
+    #[test]
+    fn classify_clients() {
+        let sharing = SharingConfig {
+            username: "user".to_string(),
+            password: "pass".to_string(),
+            allow: vec!["192.168.1.0/24".to_string()],
+        };
+        let access = Access::new(ip("192.168.1.10"), Some(&sharing)).unwrap();
+
+        assert_eq!(access.classify(ip("127.0.0.1")), ClientKind::Local);
+        assert_eq!(access.classify(ip("::1")), ClientKind::Local);
+        assert_eq!(access.classify(ip("192.168.1.10")), ClientKind::Local);
+        assert_eq!(
+            access.classify(ip("::ffff:192.168.1.10")),
+            ClientKind::Local
+        );
+        assert_eq!(access.classify(ip("192.168.1.20")), ClientKind::Shared);
+        assert_eq!(access.classify(ip("192.168.2.20")), ClientKind::Refused);
+
+        assert!(Access::new(ip("192.168.1.10"), None).is_err());
+        let local = Access::new(ip("127.0.0.1"), None).unwrap();
+        assert_eq!(local.classify(ip("192.168.1.20")), ClientKind::Refused);
+    }
+}
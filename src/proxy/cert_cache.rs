@@ -0,0 +1,308 @@
+/*
+ * Leaf certificates, kept on disk between sessions.
+ *
+ * Every host the proxy decrypts needs a certificate signed by our CA. Making
+ * one takes a moment, and a fresh one each session means the game sees a new
+ * certificate for the same host every time. They're stored in
+ * `ca/leaf/<CA fingerprint>/<host>.der`, so rotating the CA leaves the old
+ * ones behind; those directories are removed when the proxy starts. A stored
+ * certificate is replaced when it has less than RENEW_DAYS left.
+ */
+
+use hudsucker::{
+    async_trait::async_trait, certificate_authority::CertificateAuthority,
+    hyper::http::uri::Authority,
+};
+use openssl::{asn1::Asn1Time, hash::MessageDigest, x509::X509};
+use rcgen::{DistinguishedName, DnType, KeyPair, SanType};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio_rustls::rustls::{
+    self, ServerConfig,
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+
+/// Validity of a new leaf certificate.
+const LEAF_VALID_DAYS: u64 = 365;
+/// Certificates closer than this to expiring are replaced.
+const RENEW_DAYS: u32 = 30;
+/// Leaf certificates kept in memory before the in-memory cache is cleared.
+const MEMORY_ENTRIES: usize = 1_000;
+
+/// Directory holding the leaf certificates of every CA.
+pub fn leaf_dir(ca_dir: &Path) -> PathBuf {
+    ca_dir.join("leaf")
+}
+
+pub struct CachedAuthority {
+    leaves: Arc<LeafStore>,
+    memory: Mutex<HashMap<String, Arc<ServerConfig>>>,
+}
+
+/// Makes and stores the leaf certificates. Its work blocks, so it runs on the
+/// blocking thread pool.
+struct LeafStore {
+    private_key: rustls::PrivateKey,
+    ca_cert: rustls::Certificate,
+    /// Holds the certificates signed by this CA.
+    dir: PathBuf,
+}
+
+impl CachedAuthority {
+    pub fn new(
+        private_key: rustls::PrivateKey,
+        ca_cert: rustls::Certificate,
+        ca_dir: &Path,
+    ) -> Result<Self, Box<dyn Error>> {
+        // Fails if the key doesn't belong to the certificate.
+        let key_pair = KeyPair::from_der(&private_key.0)?;
+        rcgen::CertificateParams::from_ca_cert_der(&ca_cert.0, key_pair)?;
+
+        let digest = X509::from_der(&ca_cert.0)?.digest(MessageDigest::sha256())?;
+        let fingerprint = hex::encode(&digest[..8]);
+        let leaves = leaf_dir(ca_dir);
+        remove_other_cas(&leaves, &fingerprint);
+
+        Ok(Self {
+            leaves: Arc::new(LeafStore {
+                private_key,
+                ca_cert,
+                dir: leaves.join(fingerprint),
+            }),
+            memory: Mutex::new(HashMap::new()),
+        })
+    }
+
+    async fn server_config(&self, host: &str) -> Result<ServerConfig, Box<dyn Error>> {
+        let leaves = Arc::clone(&self.leaves);
+        let for_host = host.to_string();
+        let cert = tokio::task::spawn_blocking(move || {
+            leaves.certificate(&for_host).map_err(|e| e.to_string())
+        })
+        .await??;
+
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], self.leaves.private_key.clone())?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
+
+/// Has no certificate for anyone, so the handshake of a connection whose
+/// certificate couldn't be made fails.
+struct NoCertificate;
+
+impl ResolvesServerCert for NoCertificate {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        None
+    }
+}
+
+impl LeafStore {
+    fn path(&self, host: &str) -> PathBuf {
+        self.dir.join(format!("{}.der", file_name(host)))
+    }
+
+    /// The stored certificate for `host`, if it isn't about to expire.
+    fn load(&self, host: &str) -> Option<rustls::Certificate> {
+        let der = fs::read(self.path(host)).ok()?;
+        let cert = X509::from_der(&der).ok()?;
+        let renew_at = Asn1Time::days_from_now(RENEW_DAYS).ok()?;
+        if cert.not_after() <= renew_at {
+            tracing::debug!("[CERT] Stored certificate for {} expires soon", host);
+            return None;
+        }
+        Some(rustls::Certificate(der))
+    }
+
+    fn store(&self, host: &str, cert: &rustls::Certificate) -> Result<(), Box<dyn Error>> {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::create_dir_all(&self.dir)?;
+        fs::set_permissions(&self.dir, fs::Permissions::from_mode(0o700))?;
+        super::write_private(&self.path(host), &cert.0, 0o600)
+    }
+
+    fn generate(&self, host: &str) -> Result<rustls::Certificate, Box<dyn Error>> {
+        let mut params = rcgen::CertificateParams::default();
+        let mut serial = [0u8; 8];
+        openssl::rand::rand_bytes(&mut serial)?;
+        params.serial_number = Some(u64::from_be_bytes(serial) >> 1);
+
+        // Valid from yesterday, in case the game's clock is a little behind.
+        let date = |date: chrono::NaiveDate| {
+            use chrono::Datelike;
+            rcgen::date_time_ymd(date.year(), date.month() as u8, date.day() as u8)
+        };
+        let today = chrono::Utc::now().date_naive();
+        params.not_before = date(today - chrono::Days::new(1));
+        params.not_after = date(today + chrono::Days::new(LEAF_VALID_DAYS));
+
+        let mut details = DistinguishedName::new();
+        details.push(DnType::CommonName, host);
+        params.distinguished_name = details;
+        let ip = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>();
+        params.subject_alt_names.push(match ip {
+            Ok(ip) => SanType::IpAddress(ip),
+            Err(_) => SanType::DnsName(host.to_string()),
+        });
+
+        // Leaf certificates use the CA's key, like hudsucker's own authority.
+        let key_pair = KeyPair::from_der(&self.private_key.0)?;
+        params.alg = key_pair
+            .compatible_algs()
+            .next()
+            .ok_or("No signature algorithm for the CA key")?;
+        params.key_pair = Some(key_pair);
+
+        let ca_params = rcgen::CertificateParams::from_ca_cert_der(
+            &self.ca_cert.0,
+            KeyPair::from_der(&self.private_key.0)?,
+        )?;
+        let ca = rcgen::Certificate::from_params(ca_params)?;
+        let cert = rcgen::Certificate::from_params(params)?;
+        Ok(rustls::Certificate(cert.serialize_der_with_signer(&ca)?))
+    }
+
+    fn certificate(&self, host: &str) -> Result<rustls::Certificate, Box<dyn Error>> {
+        if let Some(cert) = self.load(host) {
+            tracing::debug!("[CERT] Using stored certificate for {}", host);
+            return Ok(cert);
+        }
+
+        tracing::debug!("[CERT] Generating certificate for {}", host);
+        let cert = self.generate(host)?;
+        if let Err(e) = self.store(host, &cert) {
+            tracing::warn!("[CERT] Failed to store certificate for {}: {}", host, e);
+        }
+        Ok(cert)
+    }
+}
+
+#[async_trait]
+impl CertificateAuthority for CachedAuthority {
+    async fn gen_server_config(&self, authority: &Authority) -> Arc<ServerConfig> {
+        let host = authority.host().to_ascii_lowercase();
+        if let Some(config) = self.memory.lock().unwrap().get(&host) {
+            return Arc::clone(config);
+        }
+
+        let config = match self.server_config(&host).await {
+            Ok(config) => Arc::new(config),
+            Err(e) => {
+                tracing::error!("[CERT] Failed to make a certificate for {}: {}", host, e);
+                // Not kept, so the next connection tries again.
+                return Arc::new(
+                    ServerConfig::builder()
+                        .with_safe_defaults()
+                        .with_no_client_auth()
+                        .with_cert_resolver(Arc::new(NoCertificate)),
+                );
+            }
+        };
+
+        let mut memory = self.memory.lock().unwrap();
+        if memory.len() >= MEMORY_ENTRIES {
+            memory.clear();
+        }
+        memory.insert(host, Arc::clone(&config));
+        config
+    }
+}
+
+/// Escapes a host name for use as a file name. Letters, digits, `.`, `-` and
+/// `_` are kept, anything else (like the `[`, `:` and `]` of IPv6 addresses)
+/// becomes `%XX`.
+fn file_name(host: &str) -> String {
+    let mut name = String::with_capacity(host.len());
+    for byte in host.bytes() {
+        match byte {
+            b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' => name.push(byte as char),
+            // Keep names like `..` out of the path.
+            b'.' if !name.is_empty() => name.push('.'),
+            _ => name.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    name
+}
+
+/// Removes the certificates signed by other (older) CAs.
+fn remove_other_cas(leaves: &Path, fingerprint: &str) {
+    let Ok(entries) = fs::read_dir(leaves) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if entry.file_name() == fingerprint {
+            continue;
+        }
+        let path = entry.path();
+        tracing::info!(
+            "[CERT] Removing certificates of an old CA in {}",
+            path.display()
+        );
+        if let Err(e) = fs::remove_dir_all(&path) {
+            tracing::warn!("[CERT] Failed to remove {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn authority(ca_dir: &Path) -> CachedAuthority {
+        let mut params = rcgen::CertificateParams::default();
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "Test CA");
+        let ca = rcgen::Certificate::from_params(params).unwrap();
+        CachedAuthority::new(
+            rustls::PrivateKey(ca.serialize_private_key_der()),
+            rustls::Certificate(ca.serialize_der().unwrap()),
+            ca_dir,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn stores_and_reuses_certificates() {
+        let dir = tempfile::tempdir().unwrap();
+        let authority = authority(dir.path());
+        let host: Authority = "Game.Example.com:443".parse().unwrap();
+
+        let config = authority.gen_server_config(&host).await;
+        assert!(Arc::ptr_eq(
+            &config,
+            &authority.gen_server_config(&host).await
+        ));
+        let path = authority.leaves.path("game.example.com");
+        let stored = fs::read(&path).unwrap();
+
+        // A new session reads it back instead of making another.
+        authority.memory.lock().unwrap().clear();
+        authority.gen_server_config(&host).await;
+        assert_eq!(fs::read(&path).unwrap(), stored);
+        let cert = X509::from_der(&stored).unwrap();
+        assert!(cert.not_after() > Asn1Time::days_from_now(RENEW_DAYS).unwrap());
+    }
+
+    #[test]
+    fn escapes_file_names() {
+        assert_eq!(file_name("game.example.com"), "game.example.com");
+        assert_eq!(file_name("[::1]"), "%5B%3A%3A1%5D");
+        assert_eq!(file_name("../x"), "%2E.%2Fx");
+    }
+}
@@ -0,0 +1,443 @@
+/*
+ * Local control API for a running proxy, used by `anime-games-linux ctl`.
+ *
+ * Listens on a random loopback port. The port and a random token are written to
+ * `control.json` in the data directory (readable only by the user); every request
+ * must carry the token as `Authorization: Bearer <token>`.
+ */
+
+use hudsucker::hyper::{
+    Body, Method, Request, Response, Server, StatusCode, header,
+    service::{make_service_fn, service_fn},
+};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    error::Error,
+    net::SocketAddr,
+    os::unix::fs::OpenOptionsExt,
+    path::PathBuf,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Instant,
+};
+
+use crate::config::Config;
+
+use super::{
+    failover, har,
+    hosts::{self, Hosts},
+    rewrite::{self, RewriteSet},
+    rules::{self, RuleSet},
+    telemetry::{self, Blocklist},
+};
+
+/// Where a running proxy publishes its control endpoint.
+#[derive(Serialize, Deserialize)]
+pub struct ControlInfo {
+    pub port: u16,
+    pub token: String,
+    pub pid: u32,
+}
+
+pub fn control_file() -> Option<PathBuf> {
+    Some(
+        super::data_dir()?
+            .join("anime-games-proxy")
+            .join("control.json"),
+    )
+}
+
+struct ActiveRequest {
+    client: SocketAddr,
+    method: String,
+    uri: String,
+    started: Instant,
+}
+
+static ACTIVE: Lazy<Mutex<HashMap<u64, ActiveRequest>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Keeps a request in the active list until dropped.
+pub struct ActiveGuard(u64);
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        ACTIVE.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Requests that haven't been answered yet.
+pub fn active_requests() -> usize {
+    ACTIVE.lock().unwrap().len()
+}
+
+pub fn track_request(client: SocketAddr, req: &Request<Body>) -> ActiveGuard {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    ACTIVE.lock().unwrap().insert(
+        id,
+        ActiveRequest {
+            client,
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+            started: Instant::now(),
+        },
+    );
+    ActiveGuard(id)
+}
+
+struct ControlState {
+    token: String,
+    proxy_port: u16,
+    started: Instant,
+}
+
+static STATE: Lazy<Mutex<Option<ControlState>>> = Lazy::new(|| Mutex::new(None));
+
+fn json_response(status: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, message: impl std::fmt::Display) -> Response<Body> {
+    json_response(status, json!({ "error": message.to_string() }))
+}
+
+fn status() -> Value {
+    let state = STATE.lock().unwrap();
+    let (proxy_port, uptime) = state
+        .as_ref()
+        .map(|s| (s.proxy_port, s.started.elapsed().as_secs()))
+        .unwrap_or_default();
+
+    json!({
+        "pid": std::process::id(),
+        "proxy_port": proxy_port,
+        "uptime_secs": uptime,
+        "server": super::default_server(),
+        "game_servers": super::game_servers(),
+        "recording": har::recording_path().map(|p| p.display().to_string()),
+        "active_requests": active_requests(),
+        "failover": failover::failover_status(),
+        "telemetry_blocked": super::telemetry::telemetry_hits(),
+    })
+}
+
+fn connections() -> Value {
+    let active = ACTIVE.lock().unwrap();
+    let mut list: Vec<(&u64, &ActiveRequest)> = active.iter().collect();
+    list.sort_by_key(|(id, _)| **id);
+
+    Value::Array(
+        list.into_iter()
+            .map(|(id, req)| {
+                json!({
+                    "id": id,
+                    "client": req.client.to_string(),
+                    "method": req.method,
+                    "uri": req.uri,
+                    "age_ms": req.started.elapsed().as_millis() as u64,
+                })
+            })
+            .collect(),
+    )
+}
+
+#[derive(Deserialize)]
+struct UpstreamRequest {
+    server: String,
+    #[serde(default)]
+    game: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RecordRequest {
+    path: PathBuf,
+}
+
+fn reload() -> Result<Value, Box<dyn Error>> {
+    apply_config(&Config::load()?)
+}
+
+/// Replaces the rules, rewrites, telemetry blocklist and host overrides. All of
+/// them are compiled first, so an invalid section leaves every one unchanged.
+fn apply_config(config: &Config) -> Result<Value, Box<dyn Error>> {
+    let rewrites = RewriteSet::compile(&config.rewrites)?;
+    let hosts = Hosts::compile(&config.hosts)?;
+    let rules = RuleSet::compile(&config.rules);
+    let blocklist = Blocklist::compile(&config.telemetry);
+
+    rewrite::replace_rewrites(rewrites);
+    rules::replace_rules(rules);
+    telemetry::replace_telemetry(blocklist);
+    hosts::replace_hosts(hosts);
+    Ok(json!({
+        "rules": config.rules.len(),
+        "rewrites": config.rewrites.len(),
+        "hosts": config.hosts.len(),
+    }))
+}
+
+async fn route(req: Request<Body>) -> Response<Body> {
+    let authorized = {
+        let state = STATE.lock().unwrap();
+        let expected = state.as_ref().map(|s| format!("Bearer {}", s.token));
+        match (
+            expected,
+            req.headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|v| v.to_str().ok()),
+        ) {
+            (Some(expected), Some(given)) => {
+                expected.len() == given.len()
+                    && openssl::memcmp::eq(expected.as_bytes(), given.as_bytes())
+            }
+            _ => false,
+        }
+    };
+    if !authorized {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid or missing token");
+    }
+
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let body = match hudsucker::hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+
+    match (method, path.as_str()) {
+        (Method::GET, "/status") => json_response(StatusCode::OK, status()),
+        (Method::GET, "/connections") => json_response(StatusCode::OK, connections()),
+        (Method::POST, "/upstream") => match serde_json::from_slice::<UpstreamRequest>(&body) {
+            Ok(upstream) => {
+                if let Err(e) = super::check_server(&upstream.server) {
+                    return error_response(StatusCode::BAD_REQUEST, e);
+                }
+                tracing::info!("[CONTROL] Switching upstream to {}", upstream.server);
+                failover::clear_failover(upstream.game.as_deref());
+                match &upstream.game {
+                    Some(game) => super::set_game_server(game, upstream.server),
+                    None => super::set_proxy_addr(upstream.server),
+                }
+                json_response(StatusCode::OK, status())
+            }
+            Err(e) => error_response(StatusCode::BAD_REQUEST, e),
+        },
+        (Method::POST, "/record/start") => match serde_json::from_slice::<RecordRequest>(&body) {
+            Ok(record) => {
+                if let Err(e) = har::finish_recording() {
+                    tracing::error!("[CONTROL] Failed to write HAR file: {}", e);
+                }
+                har::start_recording(&record.path);
+                json_response(StatusCode::OK, status())
+            }
+            Err(e) => error_response(StatusCode::BAD_REQUEST, e),
+        },
+        (Method::POST, "/record/stop") => {
+            let path = har::recording_path();
+            match har::finish_recording() {
+                Ok(()) => json_response(
+                    StatusCode::OK,
+                    json!({ "written": path.map(|p| p.display().to_string()) }),
+                ),
+                Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+            }
+        }
+        (Method::POST, "/reload") => match reload() {
+            Ok(result) => {
+                tracing::info!("[CONTROL] Reloaded rules from config");
+                json_response(StatusCode::OK, result)
+            }
+            Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+        },
+        _ => error_response(StatusCode::NOT_FOUND, "unknown endpoint"),
+    }
+}
+
+fn write_control_file(info: &ControlInfo) -> Result<PathBuf, Box<dyn Error>> {
+    let path = control_file().ok_or("Could not determine data directory")?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    // Replace rather than truncate, so the file is created with the right mode.
+    let _ = std::fs::remove_file(&path);
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?;
+    std::io::Write::write_all(&mut file, serde_json::to_string_pretty(info)?.as_bytes())?;
+    Ok(path)
+}
+
+/// Removes the control file if it still belongs to this process.
+pub fn remove_control_file() {
+    let Some(path) = control_file() else {
+        return;
+    };
+    let owned = std::fs::read_to_string(&path)
+        .ok()
+        .and_then(|data| serde_json::from_str::<ControlInfo>(&data).ok())
+        .is_some_and(|info| info.pid == std::process::id());
+    if owned {
+        let _ = std::fs::remove_file(&path);
+    }
+}
+
+/// Starts the control API for the proxy listening on `proxy_port`.
+pub fn start_control(proxy_port: u16) -> Result<(), Box<dyn Error>> {
+    let mut token = [0u8; 32];
+    openssl::rand::rand_bytes(&mut token)?;
+    let token = hex::encode(token);
+
+    let listener = std::net::TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))?;
+    listener.set_nonblocking(true)?;
+    let port = listener.local_addr()?.port();
+
+    *STATE.lock().unwrap() = Some(ControlState {
+        token: token.clone(),
+        proxy_port,
+        started: Instant::now(),
+    });
+
+    let path = write_control_file(&ControlInfo {
+        port,
+        token,
+        pid: std::process::id(),
+    })?;
+
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|req| async {
+            Ok::<_, Infallible>(route(req).await)
+        }))
+    });
+    let server = Server::from_tcp(listener)?.serve(make_service);
+
+    tracing::info!(
+        "[CONTROL] Listening on 127.0.0.1:{} ({})",
+        port,
+        path.display()
+    );
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            tracing::error!("[CONTROL] Error running control API: {}", e);
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::rules::{HostMatch, InterceptRule, RuleAction};
+
+    const TOKEN: &str = "0123456789abcdef";
+
+    async fn call(
+        method: Method,
+        path: &str,
+        auth: Option<&str>,
+        body: &str,
+    ) -> (StatusCode, Value) {
+        *STATE.lock().unwrap() = Some(ControlState {
+            token: TOKEN.to_string(),
+            proxy_port: 8080,
+            started: Instant::now(),
+        });
+        let mut req = Request::builder().method(method).uri(path);
+        if let Some(auth) = auth {
+            req = req.header(header::AUTHORIZATION, auth);
+        }
+        let res = route(req.body(Body::from(body.to_string())).unwrap()).await;
+        let status = res.status();
+        let body = hudsucker::hyper::body::to_bytes(res.into_body())
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_tokens() {
+        let bearer = format!("Bearer {}", TOKEN);
+        for auth in [
+            None,
+            Some(""),
+            Some(TOKEN),
+            Some("Bearer"),
+            Some("Bearer 0123456789abcdeF"),
+            Some("Bearer 0123456789abcde"),
+            Some("Basic 0123456789abcdef"),
+        ] {
+            let (status, body) = call(Method::GET, "/status", auth, "").await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{:?}", auth);
+            assert_eq!(body["error"], "invalid or missing token");
+        }
+        let (status, _) = call(Method::GET, "/status", Some(&bearer), "").await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn routes_commands() {
+        let auth = format!("Bearer {}", TOKEN);
+        let auth = Some(auth.as_str());
+
+        let (status, body) = call(Method::GET, "/status", auth, "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["proxy_port"], 8080);
+        assert_eq!(body["pid"], std::process::id());
+
+        let (status, body) = call(Method::GET, "/connections", auth, "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.is_array());
+
+        let (status, _) = call(Method::POST, "/upstream", auth, "{").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = call(
+            Method::POST,
+            "/upstream",
+            auth,
+            r#"{"server": "ftp://example.com"}"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = call(Method::POST, "/record/start", auth, "{}").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = call(Method::POST, "/status", auth, "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = call(Method::GET, "/reload", auth, "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = call(Method::GET, "/nothing", auth, "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn invalid_config_changes_nothing() {
+        let config = Config {
+            rules: vec![InterceptRule {
+                host: HostMatch::Exact("reload.test".to_string()),
+                port: None,
+                path_prefix: None,
+                action: RuleAction::Block,
+                game: None,
+                transforms: Vec::new(),
+            }],
+            hosts: [("reload.test".to_string(), "not an address".to_string())].into(),
+            ..Config::default()
+        };
+
+        assert!(apply_config(&config).is_err());
+        assert!(rules::match_rule("reload.test", 443, None).is_none());
+    }
+}
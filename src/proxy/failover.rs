@@ -0,0 +1,551 @@
+/*
+ * Failover between several private servers of one game.
+ *
+ * A game with fallback `upstreams` gets a pool of servers in priority order.
+ * Each pool is probed in the background (TCP connect or HTTP GET), and
+ * redirected requests go to the first healthy server. A request that can't
+ * connect marks its server down right away, so the next requests fail over
+ * without waiting for a probe. Servers come back once a probe succeeds.
+ */
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use hudsucker::{
+    futures::future::join_all,
+    hyper::{Client, Uri},
+};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Value, json};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use super::upstream::UpstreamConnector;
+
+/// Failover events kept for the control API.
+const MAX_EVENTS: usize = 20;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProbeKind {
+    /// Open a TCP connection.
+    #[default]
+    Tcp,
+    /// Send a GET to `path`; any status below 500 counts as up.
+    Http,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HealthCheck {
+    #[serde(rename = "type")]
+    pub kind: ProbeKind,
+    /// Path `http` probes send their GET to. A missing leading `/` is added.
+    #[serde(deserialize_with = "absolute_path")]
+    pub path: String,
+    pub interval_secs: u64,
+    pub timeout_secs: u64,
+    /// Failed probes in a row before a server is taken out.
+    pub failures: u32,
+}
+
+fn absolute_path<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let path = String::deserialize(deserializer)?;
+    Ok(if path.starts_with('/') {
+        path
+    } else {
+        format!("/{}", path)
+    })
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            kind: ProbeKind::Tcp,
+            path: "/".to_string(),
+            interval_secs: 10,
+            timeout_secs: 3,
+            failures: 2,
+        }
+    }
+}
+
+struct Endpoint {
+    addr: String,
+    healthy: bool,
+    failures: u32,
+    last_error: Option<String>,
+    last_check: Option<Instant>,
+}
+
+struct Pool {
+    endpoints: Vec<Endpoint>,
+    check: HealthCheck,
+    active: usize,
+    all_down: bool,
+    /// Changes when the pool is replaced, so the old prober stops.
+    generation: u64,
+}
+
+impl Pool {
+    /// Switches to the first healthy server. Stays on the current one if all are down.
+    fn reselect(&mut self, name: &str, reason: &str) {
+        let Some(next) = self.endpoints.iter().position(|e| e.healthy) else {
+            if !self.all_down {
+                tracing::error!(
+                    "[FAILOVER] {}: all {} servers are down",
+                    name,
+                    self.endpoints.len()
+                );
+                self.all_down = true;
+            }
+            return;
+        };
+        self.all_down = false;
+        if next == self.active {
+            return;
+        }
+
+        let from = self.endpoints[self.active].addr.clone();
+        let to = self.endpoints[next].addr.clone();
+        if next < self.active {
+            tracing::info!(
+                "[FAILOVER] {}: {} is back, switching from {}",
+                name,
+                to,
+                from
+            );
+        } else {
+            tracing::warn!(
+                "[FAILOVER] {}: switching from {} to {} ({})",
+                name,
+                from,
+                to,
+                reason
+            );
+        }
+        self.active = next;
+
+        let mut events = EVENTS.lock().unwrap();
+        if events.len() == MAX_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(Event {
+            time: Utc::now(),
+            pool: name.to_string(),
+            from,
+            to,
+            reason: reason.to_string(),
+        });
+    }
+}
+
+struct Event {
+    time: DateTime<Utc>,
+    pool: String,
+    from: String,
+    to: String,
+    reason: String,
+}
+
+// Pools keyed by game (see `game_key`), "" for the default upstream.
+static POOLS: Lazy<Mutex<BTreeMap<String, Pool>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+static EVENTS: Lazy<Mutex<VecDeque<Event>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+fn pool_key(game: Option<&str>) -> String {
+    game.map(super::game_key).unwrap_or_default()
+}
+
+fn pool_name(key: &str) -> &str {
+    if key.is_empty() { "default" } else { key }
+}
+
+/// Fails over between `servers` (in priority order) for the given game, or for
+/// the default upstream if `game` is `None`. Must be called inside the runtime.
+pub fn set_failover(game: Option<&str>, servers: Vec<String>, check: &HealthCheck) {
+    let key = pool_key(game);
+    if servers.len() < 2 {
+        clear_failover(game);
+        return;
+    }
+
+    let generation = NEXT_GENERATION.fetch_add(1, Ordering::Relaxed);
+    tracing::info!(
+        "[FAILOVER] {}: {} servers, {:?} probe every {}s",
+        pool_name(&key),
+        servers.len(),
+        check.kind,
+        check.interval_secs
+    );
+    let endpoints = servers
+        .into_iter()
+        .map(|addr| Endpoint {
+            addr: addr.replace(' ', ""),
+            healthy: true,
+            failures: 0,
+            last_error: None,
+            last_check: None,
+        })
+        .collect();
+    POOLS.lock().unwrap().insert(
+        key.clone(),
+        Pool {
+            endpoints,
+            check: check.clone(),
+            active: 0,
+            all_down: false,
+            generation,
+        },
+    );
+
+    tokio::spawn(probe_loop(key, generation));
+}
+
+/// Stops failing over for the game, e.g. when its server is set by hand.
+pub fn clear_failover(game: Option<&str>) {
+    if POOLS.lock().unwrap().remove(&pool_key(game)).is_some() {
+        tracing::info!(
+            "[FAILOVER] {}: failover disabled",
+            pool_name(&pool_key(game))
+        );
+    }
+}
+
+/// The server requests for the game should go to, if it has a pool.
+pub(super) fn current(game: Option<&str>) -> Option<String> {
+    let pools = POOLS.lock().unwrap();
+    let pool = pools.get(&pool_key(game))?;
+    Some(pool.endpoints[pool.active].addr.clone())
+}
+
+/// Takes `addr` out of every pool after a request to it couldn't connect.
+pub(super) fn report_failure(addr: &str, error: &str) {
+    let mut pools = POOLS.lock().unwrap();
+    for (key, pool) in pools.iter_mut() {
+        let Some(endpoint) = pool.endpoints.iter_mut().find(|e| e.addr == addr) else {
+            continue;
+        };
+        if !endpoint.healthy {
+            continue;
+        }
+        endpoint.healthy = false;
+        endpoint.last_error = Some(error.to_string());
+        tracing::warn!("[FAILOVER] {}: {} is down: {}", pool_name(key), addr, error);
+        pool.reselect(pool_name(key), error);
+    }
+}
+
+async fn probe(addr: String, check: HealthCheck) -> Result<(), String> {
+    let uri: Uri = addr
+        .parse()
+        .map_err(|e| format!("invalid address: {}", e))?;
+    let timeout = Duration::from_secs(check.timeout_secs.max(1));
+
+    let result = match check.kind {
+        ProbeKind::Tcp => {
+            let host = uri.host().unwrap_or_default();
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            let port = uri
+                .port_u16()
+                .unwrap_or(if uri.scheme_str() == Some("https") {
+                    443
+                } else {
+                    80
+                });
+            tokio::time::timeout(timeout, super::chain::dial(host, port))
+                .await
+                .map(|connected| connected.map(|_| ()).map_err(|e| e.to_string()))
+        }
+        ProbeKind::Http => {
+            let url: Uri = format!("{}{}", addr, check.path)
+                .parse()
+                .map_err(|e| format!("invalid health check path: {}", e))?;
+            let client =
+                Client::builder().build::<_, hudsucker::hyper::Body>(UpstreamConnector::new());
+            tokio::time::timeout(timeout, client.get(url))
+                .await
+                .map(|response| match response {
+                    Ok(response) if response.status().is_server_error() => {
+                        Err(format!("status {}", response.status()))
+                    }
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(e.to_string()),
+                })
+        }
+    };
+
+    result.unwrap_or_else(|_| Err(format!("no answer within {}s", timeout.as_secs())))
+}
+
+async fn probe_loop(key: String, generation: u64) {
+    loop {
+        let Some((addrs, check)) = ({
+            let pools = POOLS.lock().unwrap();
+            pools
+                .get(&key)
+                .filter(|pool| pool.generation == generation)
+                .map(|pool| {
+                    let addrs: Vec<String> =
+                        pool.endpoints.iter().map(|e| e.addr.clone()).collect();
+                    (addrs, pool.check.clone())
+                })
+        }) else {
+            return;
+        };
+
+        let results = join_all(addrs.iter().map(|addr| probe(addr.clone(), check.clone()))).await;
+
+        {
+            let mut pools = POOLS.lock().unwrap();
+            let Some(pool) = pools
+                .get_mut(&key)
+                .filter(|pool| pool.generation == generation)
+            else {
+                return;
+            };
+            let name = pool_name(&key);
+            let mut reason = None;
+            for (endpoint, result) in pool.endpoints.iter_mut().zip(results) {
+                endpoint.last_check = Some(Instant::now());
+                match result {
+                    Ok(()) => {
+                        if !endpoint.healthy {
+                            tracing::info!("[FAILOVER] {}: {} is up", name, endpoint.addr);
+                        }
+                        endpoint.healthy = true;
+                        endpoint.failures = 0;
+                        endpoint.last_error = None;
+                    }
+                    Err(e) => {
+                        endpoint.failures += 1;
+                        if endpoint.healthy && endpoint.failures >= check.failures.max(1) {
+                            tracing::warn!("[FAILOVER] {}: {} is down: {}", name, endpoint.addr, e);
+                            endpoint.healthy = false;
+                            reason.get_or_insert_with(|| format!("{}: {}", endpoint.addr, e));
+                        }
+                        endpoint.last_error = Some(e);
+                    }
+                }
+            }
+            pool.reselect(name, reason.as_deref().unwrap_or("health check"));
+        }
+
+        tokio::time::sleep(Duration::from_secs(check.interval_secs.max(1))).await;
+    }
+}
+
+/// Health of every pool and the latest failover events, for the control API.
+pub fn failover_status() -> Value {
+    let pools: serde_json::Map<String, Value> = POOLS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(key, pool)| {
+            let servers: Vec<Value> = pool
+                .endpoints
+                .iter()
+                .enumerate()
+                .map(|(i, e)| {
+                    json!({
+                        "server": e.addr,
+                        "active": i == pool.active,
+                        "healthy": e.healthy,
+                        "failures": e.failures,
+                        "last_error": e.last_error,
+                        "checked_secs_ago": e.last_check.map(|t| t.elapsed().as_secs()),
+                    })
+                })
+                .collect();
+            (pool_name(key).to_string(), Value::Array(servers))
+        })
+        .collect();
+
+    let events: Vec<Value> = EVENTS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|event| {
+            json!({
+                "time": event.time.to_rfc3339_opts(SecondsFormat::Secs, true),
+                "game": event.pool,
+                "from": event.from,
+                "to": event.to,
+                "reason": event.reason,
+            })
+        })
+        .collect();
+
+    json!({ "pools": pools, "events": events })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ConfigGame, UpstreamServer};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    fn pool(addrs: &[&str]) -> Pool {
+        Pool {
+            endpoints: addrs
+                .iter()
+                .map(|addr| Endpoint {
+                    addr: addr.to_string(),
+                    healthy: true,
+                    failures: 0,
+                    last_error: None,
+                    last_check: None,
+                })
+                .collect(),
+            check: HealthCheck::default(),
+            active: 0,
+            all_down: false,
+            generation: 0,
+        }
+    }
+
+    fn fallback(port: u16, priority: i32) -> UpstreamServer {
+        UpstreamServer {
+            server: "127.0.0.1".to_string(),
+            server_port: port.into(),
+            use_ssl: false,
+            priority,
+            tls: None,
+            headers: None,
+        }
+    }
+
+    /// A port nothing listens on.
+    fn closed_port() -> u16 {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[test]
+    fn reselect_prefers_first_healthy() {
+        let mut pool = pool(&["http://a:80", "http://b:80", "http://c:80"]);
+        pool.endpoints[0].healthy = false;
+        pool.reselect("test", "a is down");
+        assert_eq!(pool.active, 1);
+
+        pool.endpoints[1].healthy = false;
+        pool.endpoints[2].healthy = false;
+        pool.reselect("test", "all down");
+        assert_eq!(pool.active, 1);
+        assert!(pool.all_down);
+
+        pool.endpoints[2].healthy = true;
+        pool.reselect("test", "c is up");
+        assert_eq!((pool.active, pool.all_down), (2, false));
+
+        pool.endpoints[0].healthy = true;
+        pool.reselect("test", "a is up");
+        assert_eq!(pool.active, 0);
+    }
+
+    #[test]
+    fn servers_in_priority_order() {
+        let game = ConfigGame {
+            server: "primary".to_string(),
+            upstreams: vec![
+                UpstreamServer {
+                    server: "late".to_string(),
+                    ..fallback(80, 5)
+                },
+                UpstreamServer {
+                    server: "early".to_string(),
+                    ..fallback(80, -1)
+                },
+                UpstreamServer {
+                    server: "first-tie".to_string(),
+                    ..fallback(80, 1)
+                },
+                UpstreamServer {
+                    server: "second-tie".to_string(),
+                    ..fallback(80, 1)
+                },
+            ],
+            ..ConfigGame::default()
+        };
+        assert_eq!(
+            game.failover_servers(),
+            [
+                "http://early:80",
+                "http://primary:80",
+                "http://first-tie:80",
+                "http://second-tie:80",
+                "http://late:80",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_over_from_down_primary() {
+        let healthy = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let healthy_port = healthy.local_addr().unwrap().port();
+        let game = ConfigGame {
+            name: "failover test".to_string(),
+            server_port: closed_port().into(),
+            upstreams: vec![fallback(closed_port(), 5), fallback(healthy_port, 2)],
+            ..ConfigGame::default()
+        };
+        let check = HealthCheck {
+            interval_secs: 1,
+            failures: 1,
+            ..HealthCheck::default()
+        };
+
+        set_failover(Some(&game.name), game.failover_servers(), &check);
+        let expected = format!("http://127.0.0.1:{}", healthy_port);
+        for _ in 0..50 {
+            if current(Some(&game.name)).as_deref() == Some(expected.as_str()) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(current(Some(&game.name)), Some(expected));
+        clear_failover(Some(&game.name));
+    }
+
+    #[tokio::test]
+    async fn http_probe_path() {
+        let check: HealthCheck =
+            serde_json::from_str(r#"{"type": "http", "path": "health", "timeout_secs": 2}"#)
+                .unwrap();
+        assert_eq!(check.path, "/health");
+        let check_default: HealthCheck = serde_json::from_str(r#"{"path": ""}"#).unwrap();
+        assert_eq!(check_default.path, "/");
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await.unwrap());
+            }
+            stream
+                .write_all(b"HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(head).unwrap()
+        });
+
+        let result = probe(addr, check).await;
+        assert_eq!(result, Err("status 503 Service Unavailable".to_string()));
+        assert!(
+            server
+                .await
+                .unwrap()
+                .starts_with("GET /health HTTP/1.1\r\n")
+        );
+    }
+}
@@ -0,0 +1,694 @@
+/*
+ * Records intercepted traffic as a HAR 1.2 file.
+ * Spec: http://www.softwareishard.com/blog/har-12-spec/
+ */
+
+use base64::Engine;
+use chrono::{DateTime, SecondsFormat, Utc};
+use hudsucker::hyper::{
+    Body, HeaderMap, Request, Response,
+    body::{Bytes, HttpBody},
+};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Instant,
+};
+
+use super::transform::decode_content;
+
+/// Bodies larger than this are cut off in the recording.
+const MAX_BODY_SIZE: usize = 512 * 1024;
+
+const REDACTED: &str = "[redacted]";
+
+#[derive(Serialize)]
+struct Har {
+    log: Log,
+}
+
+#[derive(Serialize)]
+struct Log {
+    version: &'static str,
+    creator: Creator,
+    entries: Vec<Entry>,
+}
+
+#[derive(Serialize)]
+struct Creator {
+    name: &'static str,
+    version: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    started_date_time: String,
+    time: f64,
+    request: HarRequest,
+    response: HarResponse,
+    cache: Value,
+    timings: Timings,
+    /// Where the proxy sent the request (custom field).
+    #[serde(rename = "_redirectedTo", skip_serializing_if = "Option::is_none")]
+    redirected_to: Option<String>,
+    /// Messages of a WebSocket connection, in Chrome's format (custom field).
+    #[serde(rename = "_webSocketMessages", skip_serializing_if = "Option::is_none")]
+    web_socket_messages: Option<Vec<WebSocketMessage>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+}
+
+/// A WebSocket message as recorded by Chrome's DevTools.
+#[derive(Serialize)]
+pub struct WebSocketMessage {
+    /// `send` (client to server) or `receive`.
+    #[serde(rename = "type")]
+    kind: &'static str,
+    /// Seconds since the Unix epoch.
+    time: f64,
+    /// 1 for text, 2 for binary (base64 encoded).
+    opcode: u8,
+    data: String,
+}
+
+impl WebSocketMessage {
+    pub fn new(sent: bool, binary: bool, data: &[u8]) -> Self {
+        let data = if binary {
+            base64::engine::general_purpose::STANDARD.encode(&data[..data.len().min(MAX_BODY_SIZE)])
+        } else {
+            body_to_text(data, "").0
+        };
+        Self {
+            kind: if sent { "send" } else { "receive" },
+            time: Utc::now().timestamp_micros() as f64 / 1_000_000.0,
+            opcode: if binary { 2 } else { 1 },
+            data,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HarRequest {
+    method: String,
+    url: String,
+    http_version: String,
+    cookies: Vec<NameValue>,
+    headers: Vec<NameValue>,
+    query_string: Vec<NameValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    post_data: Option<PostData>,
+    headers_size: i64,
+    body_size: i64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HarResponse {
+    status: u16,
+    status_text: String,
+    http_version: String,
+    cookies: Vec<NameValue>,
+    headers: Vec<NameValue>,
+    content: Content,
+    #[serde(rename = "redirectURL")]
+    redirect_url: String,
+    headers_size: i64,
+    body_size: i64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PostData {
+    mime_type: String,
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Content {
+    size: i64,
+    mime_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encoding: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Timings {
+    send: f64,
+    wait: f64,
+    receive: f64,
+}
+
+#[derive(Serialize)]
+struct NameValue {
+    name: String,
+    value: String,
+}
+
+struct Recorder {
+    path: PathBuf,
+    entries: Vec<Entry>,
+}
+
+static RECORDER: Lazy<Mutex<Option<Recorder>>> = Lazy::new(|| Mutex::new(None));
+
+/// Starts recording intercepted traffic. It's written to `path` by `finish_recording`.
+pub fn start_recording(path: &Path) {
+    tracing::info!("Recording intercepted traffic to {}", path.display());
+    *RECORDER.lock().unwrap() = Some(Recorder {
+        path: path.to_path_buf(),
+        entries: Vec::new(),
+    });
+}
+
+pub fn is_recording() -> bool {
+    RECORDER.lock().unwrap().is_some()
+}
+
+pub fn recording_path() -> Option<PathBuf> {
+    RECORDER.lock().unwrap().as_ref().map(|r| r.path.clone())
+}
+
+/// Stops recording and writes the HAR file. Does nothing if not recording.
+pub fn finish_recording() -> Result<(), Box<dyn std::error::Error>> {
+    let Some(recorder) = RECORDER.lock().unwrap().take() else {
+        return Ok(());
+    };
+
+    let har = Har {
+        log: Log {
+            version: "1.2",
+            creator: Creator {
+                name: env!("CARGO_PKG_NAME"),
+                version: env!("CARGO_PKG_VERSION"),
+            },
+            entries: recorder.entries,
+        },
+    };
+    std::fs::write(&recorder.path, serde_json::to_string_pretty(&har)?)?;
+    tracing::info!(
+        "Wrote {} recorded requests to {}",
+        har.log.entries.len(),
+        recorder.path.display()
+    );
+    Ok(())
+}
+
+// Response bodies still being copied by `record_response`.
+static PENDING_BODIES: AtomicUsize = AtomicUsize::new(0);
+
+struct PendingBody;
+
+impl Drop for PendingBody {
+    fn drop(&mut self) {
+        PENDING_BODIES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Responses whose entry hasn't been recorded yet.
+pub fn pending_bodies() -> usize {
+    PENDING_BODIES.load(Ordering::Relaxed)
+}
+
+fn push_entry(entry: Entry) {
+    if let Some(recorder) = RECORDER.lock().unwrap().as_mut() {
+        recorder.entries.push(entry);
+    }
+}
+
+fn is_sensitive(name: &str) -> bool {
+    let name = name.to_lowercase();
+    matches!(
+        name.as_str(),
+        "cookie" | "set-cookie" | "authorization" | "proxy-authorization"
+    ) || ["token", "ticket", "authkey", "password", "secret"]
+        .iter()
+        .any(|word| name.contains(word))
+}
+
+fn headers_to_har(headers: &HeaderMap) -> Vec<NameValue> {
+    headers
+        .iter()
+        .map(|(name, value)| NameValue {
+            name: name.to_string(),
+            // Values read from a secret by the header rules are marked sensitive.
+            value: if is_sensitive(name.as_str()) || value.is_sensitive() {
+                REDACTED.to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            },
+        })
+        .collect()
+}
+
+fn redact_query(query: Option<&str>) -> (String, Vec<NameValue>) {
+    let Some(query) = query else {
+        return (String::new(), Vec::new());
+    };
+
+    let params: Vec<NameValue> = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            NameValue {
+                name: name.to_string(),
+                value: if is_sensitive(name) {
+                    REDACTED.to_string()
+                } else {
+                    value.to_string()
+                },
+            }
+        })
+        .collect();
+    let query = params
+        .iter()
+        .map(|p| format!("{}={}", p.name, p.value))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    (format!("?{}", query), params)
+}
+
+fn redact_json(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if is_sensitive(key) && !value.is_object() && !value.is_array() {
+                    *value = Value::String(REDACTED.to_string());
+                } else {
+                    redact_json(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_json),
+        _ => {}
+    }
+}
+
+/// `"name": value` in JSON text, where the value may be cut off.
+static JSON_MEMBER: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#""((?:[^"\\]|\\.)*)"(\s*:\s*)("(?:[^"\\]|\\.)*"?|[^\s,:{}\[\]"]+)"#).unwrap()
+});
+
+/// Redacts JSON that can't be parsed, e.g. because it was cut off.
+fn redact_json_text(text: &str) -> String {
+    JSON_MEMBER
+        .replace_all(text, |caps: &regex::Captures| {
+            if is_sensitive(&caps[1]) {
+                format!("\"{}\"{}\"{}\"", &caps[1], &caps[2], REDACTED)
+            } else {
+                caps[0].to_string()
+            }
+        })
+        .into_owned()
+}
+
+fn looks_like_json(text: &str, mime_type: &str) -> bool {
+    mime_type.contains("json") || text.trim_start().starts_with(['{', '['])
+}
+
+/// Turns a body into HAR text: redacted JSON or form data, plain text, or
+/// base64 for binary data. Redaction happens before the text is cut off.
+fn body_to_text(body: &[u8], mime_type: &str) -> (String, Option<&'static str>, Option<String>) {
+    // Parsed JSON is whole; other text may have been cut off while recording.
+    let mut parsed = false;
+    let text = if mime_type.starts_with("application/x-www-form-urlencoded") {
+        std::str::from_utf8(body)
+            .ok()
+            .map(|text| redact_query(Some(text)).0.split_off(1))
+    } else if let Ok(mut json) = serde_json::from_slice::<Value>(body) {
+        redact_json(&mut json);
+        parsed = true;
+        Some(json.to_string())
+    } else {
+        let text = match std::str::from_utf8(body) {
+            Ok(text) => Some(text),
+            // Cut off in the middle of a character.
+            Err(e) if e.error_len().is_none() => {
+                Some(std::str::from_utf8(&body[..e.valid_up_to()]).unwrap())
+            }
+            Err(_) => None,
+        };
+        text.map(|text| {
+            if looks_like_json(text, mime_type) {
+                redact_json_text(text)
+            } else {
+                text.to_string()
+            }
+        })
+    };
+
+    match text {
+        Some(mut text) => {
+            let cut = text.len() > MAX_BODY_SIZE || (!parsed && body.len() > MAX_BODY_SIZE);
+            if text.len() > MAX_BODY_SIZE {
+                let mut end = MAX_BODY_SIZE;
+                while !text.is_char_boundary(end) {
+                    end -= 1;
+                }
+                text.truncate(end);
+            }
+            let comment = cut.then(|| format!("Body truncated to {} bytes", text.len()));
+            (text, None, comment)
+        }
+        None => {
+            let comment = (body.len() > MAX_BODY_SIZE)
+                .then(|| format!("Body truncated to {} bytes", MAX_BODY_SIZE));
+            let body = &body[..body.len().min(MAX_BODY_SIZE)];
+            (
+                base64::engine::general_purpose::STANDARD.encode(body),
+                Some("base64"),
+                comment,
+            )
+        }
+    }
+}
+
+fn content_type(headers: &HeaderMap) -> String {
+    headers
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_string()
+}
+
+/// A request waiting for its response.
+#[derive(Clone)]
+pub struct PendingEntry {
+    started: DateTime<Utc>,
+    start: Instant,
+    request: HarRequestParts,
+}
+
+#[derive(Clone)]
+struct HarRequestParts {
+    method: String,
+    url: String,
+    http_version: String,
+    headers: HeaderMap,
+    query: Option<String>,
+    body: Bytes,
+    redirected_to: Option<String>,
+}
+
+impl PendingEntry {
+    fn har_request(&self) -> HarRequest {
+        let req = &self.request;
+        let (query, query_string) = redact_query(req.query.as_deref());
+        let url = format!("{}{}", req.url, query);
+        let post_data = (!req.body.is_empty()).then(|| {
+            let mime_type = content_type(&req.headers);
+            let (text, _, comment) = body_to_text(&req.body, &mime_type);
+            PostData {
+                mime_type,
+                text,
+                comment,
+            }
+        });
+
+        HarRequest {
+            method: req.method.clone(),
+            url,
+            http_version: req.http_version.clone(),
+            cookies: Vec::new(),
+            headers: headers_to_har(&req.headers),
+            query_string,
+            post_data,
+            headers_size: -1,
+            body_size: req.body.len() as i64,
+        }
+    }
+
+    fn finish(
+        &self,
+        wait: f64,
+        receive: f64,
+        response: HarResponse,
+        web_socket_messages: Option<Vec<WebSocketMessage>>,
+    ) {
+        let comment = self
+            .request
+            .redirected_to
+            .as_ref()
+            .map(|to| format!("Redirected to {}", to));
+
+        push_entry(Entry {
+            started_date_time: self.started.to_rfc3339_opts(SecondsFormat::Millis, true),
+            time: wait + receive,
+            request: self.har_request(),
+            response,
+            cache: Value::Object(Default::default()),
+            timings: Timings {
+                send: 0.0,
+                wait,
+                receive,
+            },
+            redirected_to: self.request.redirected_to.clone(),
+            web_socket_messages,
+            comment,
+        });
+    }
+
+    /// Records a response produced by the proxy itself, e.g. for a blocked request.
+    pub fn finish_local(&self, status: u16, headers: &HeaderMap, body: &[u8]) {
+        let wait = self.start.elapsed().as_secs_f64() * 1000.0;
+        self.finish(
+            wait,
+            0.0,
+            har_response(status, "HTTP/1.1", headers, body),
+            None,
+        );
+    }
+
+    /// Records a WebSocket connection once it has closed. `wait` is the time
+    /// the handshake took, in milliseconds.
+    pub fn finish_websocket(
+        &self,
+        wait: f64,
+        headers: &HeaderMap,
+        messages: Vec<WebSocketMessage>,
+    ) {
+        let total = self.start.elapsed().as_secs_f64() * 1000.0;
+        self.finish(
+            wait,
+            total - wait,
+            har_response(101, "HTTP/1.1", headers, &[]),
+            Some(messages),
+        );
+    }
+}
+
+fn har_response(status: u16, http_version: &str, headers: &HeaderMap, body: &[u8]) -> HarResponse {
+    let (text, encoding, comment) = body_to_text(body, &content_type(headers));
+    HarResponse {
+        status,
+        status_text: hudsucker::hyper::StatusCode::from_u16(status)
+            .ok()
+            .and_then(|s| s.canonical_reason())
+            .unwrap_or("")
+            .to_string(),
+        http_version: http_version.to_string(),
+        cookies: Vec::new(),
+        headers: headers_to_har(headers),
+        content: Content {
+            size: body.len() as i64,
+            mime_type: content_type(headers),
+            text: (!body.is_empty()).then_some(text),
+            encoding,
+            comment,
+        },
+        redirect_url: headers
+            .get("location")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_string(),
+        headers_size: -1,
+        body_size: body.len() as i64,
+    }
+}
+
+/// Buffers the request body and starts a HAR entry for it.
+/// `original_url` is the URL as sent by the game, before any redirect.
+pub async fn begin_entry(req: Request<Body>, original_url: &str) -> (Request<Body>, PendingEntry) {
+    let started = Utc::now();
+    let start = Instant::now();
+
+    let (parts, body) = req.into_parts();
+    let body = match hudsucker::hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::warn!("[HAR] Failed to read request body: {}", e);
+            Bytes::new()
+        }
+    };
+
+    let (url, query) = match original_url.split_once('?') {
+        Some((url, query)) => (url.to_string(), Some(query.to_string())),
+        None => (original_url.to_string(), None),
+    };
+    let redirected_to = (parts.uri != original_url).then(|| {
+        let uri = parts.uri.to_string();
+        match uri.split_once('?') {
+            Some((url, query)) => format!("{}{}", url, redact_query(Some(query)).0),
+            None => uri,
+        }
+    });
+
+    let entry = PendingEntry {
+        started,
+        start,
+        request: HarRequestParts {
+            method: parts.method.to_string(),
+            url,
+            http_version: format!("{:?}", parts.version),
+            headers: parts.headers.clone(),
+            query,
+            body: body.clone(),
+            redirected_to,
+        },
+    };
+
+    (Request::from_parts(parts, Body::from(body)), entry)
+}
+
+/// Passes the response body through while copying it into the HAR entry.
+/// The entry is written once the body has been fully received.
+pub fn record_response(entry: PendingEntry, res: Response<Body>) -> Response<Body> {
+    let wait = entry.start.elapsed().as_secs_f64() * 1000.0;
+    let (parts, mut body) = res.into_parts();
+    let (mut sender, new_body) = Body::channel();
+
+    let status = parts.status.as_u16();
+    let http_version = format!("{:?}", parts.version);
+    let headers = parts.headers.clone();
+
+    PENDING_BODIES.fetch_add(1, Ordering::Relaxed);
+    let pending = PendingBody;
+    tokio::spawn(async move {
+        let _pending = pending;
+        let receive_start = Instant::now();
+        let mut captured: Vec<u8> = Vec::new();
+        let mut total = 0usize;
+
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    tracing::warn!("[HAR] Failed to read response body: {}", e);
+                    sender.abort();
+                    break;
+                }
+            };
+            total += chunk.len();
+            // One byte more than is kept, to know the body was cut off.
+            let room = (MAX_BODY_SIZE + 1).saturating_sub(captured.len());
+            captured.extend_from_slice(&chunk[..chunk.len().min(room)]);
+            if sender.send_data(chunk).await.is_err() {
+                break;
+            }
+        }
+
+        let receive = receive_start.elapsed().as_secs_f64() * 1000.0;
+        // HAR content is stored decoded. A truncated body can't be decoded, so it stays as is.
+        let mut content_size = total;
+        if captured.len() == total
+            && let Some(decoded) = decode_content(&captured, &headers)
+        {
+            captured = decoded;
+            content_size = captured.len();
+        }
+        let mut response = har_response(status, &http_version, &headers, &captured);
+        response.content.size = content_size as i64;
+        response.body_size = total as i64;
+        entry.finish(wait, receive, response, None);
+    });
+
+    Response::from_parts(parts, new_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_json() {
+        let body = br#"{"uid":1,"data":{"token":"abc","list":[{"password":"x"}]}}"#;
+        let (text, encoding, comment) = body_to_text(body, "application/json");
+        assert_eq!(
+            text,
+            r#"{"data":{"list":[{"password":"[redacted]"}],"token":"[redacted]"},"uid":1}"#
+        );
+        assert_eq!((encoding, comment), (None, None));
+    }
+
+    #[test]
+    fn redacts_truncated_json() {
+        let body = format!(
+            r#"{{"token":"abc","ticket":12,"uid":1,"items":["{}"],"authkey":"def"#,
+            "a".repeat(MAX_BODY_SIZE)
+        );
+        let (text, _, comment) = body_to_text(body.as_bytes(), "");
+        assert!(text.starts_with(r#"{"token":"[redacted]","ticket":"[redacted]","uid":1,"#));
+        assert!(!text.contains("abc"));
+        assert_eq!(text.len(), MAX_BODY_SIZE);
+        assert!(comment.is_some());
+
+        let (text, _, _) = body_to_text(br#"{"uid":1,"password":"hun"#, "application/json");
+        assert_eq!(text, r#"{"uid":1,"password":"[redacted]""#);
+    }
+
+    #[test]
+    fn redacts_form_bodies() {
+        let (text, _, _) = body_to_text(
+            b"account=me&password=hunter2&login_token=abc",
+            "application/x-www-form-urlencoded; charset=utf-8",
+        );
+        assert_eq!(
+            text,
+            "account=me&password=[redacted]&login_token=[redacted]"
+        );
+    }
+
+    #[test]
+    fn keeps_other_text() {
+        let (text, encoding, _) = body_to_text(b"token=abc", "text/plain");
+        assert_eq!((text.as_str(), encoding), ("token=abc", None));
+    }
+
+    #[test]
+    fn truncates_inside_characters() {
+        let mut body = "é".repeat(MAX_BODY_SIZE / 2).into_bytes();
+        body.push(0xc3);
+        let (text, encoding, comment) = body_to_text(&body, "text/plain");
+        assert_eq!(encoding, None);
+        assert_eq!(text.len(), MAX_BODY_SIZE);
+        assert!(comment.is_some());
+
+        let (text, encoding, comment) = body_to_text(&[0xff; MAX_BODY_SIZE + 1], "");
+        assert_eq!(encoding, Some("base64"));
+        assert_eq!(
+            base64::engine::general_purpose::STANDARD
+                .decode(text)
+                .unwrap()
+                .len(),
+            MAX_BODY_SIZE
+        );
+        assert!(comment.is_some());
+    }
+}
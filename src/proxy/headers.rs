@@ -0,0 +1,319 @@
+/*
+ * Header changes for requests redirected to an upstream server.
+ *
+ * Private servers may want a shared secret or a particular Host on the
+ * requests they get. Each server (a game's `server` or one of its fallbacks)
+ * can remove, set and append headers, in that order. Values can be read from
+ * an environment variable or a file, so secrets don't have to be written into
+ * the config; those are marked sensitive and left out of HAR recordings.
+ *
+ * hudsucker drops the Host of the requests it forwards, so servers get their
+ * own address. Requests that have to keep the game's Host, or get a specific
+ * one from `set`, are sent by the handler itself.
+ */
+
+use hudsucker::hyper::{
+    HeaderMap, Uri,
+    header::{self, HeaderName, HeaderValue},
+};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use super::upstream::authority_key;
+
+/// A header value, given in the config or read from elsewhere.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum HeaderSource {
+    Value(String),
+    /// Read from an environment variable when the proxy starts.
+    Env {
+        env: String,
+    },
+    /// Read from a file when the proxy starts. A trailing newline is removed.
+    File {
+        file: PathBuf,
+    },
+}
+
+/// The Host sent to the server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HostHeader {
+    /// The server's own address.
+    #[default]
+    Rewrite,
+    /// The Host the game sent, i.e. the official server's name.
+    Keep,
+}
+
+/// Header changes for requests to one upstream server.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UpstreamHeaders {
+    #[serde(skip_serializing_if = "is_rewrite")]
+    pub host: HostHeader,
+    /// Names of headers to remove.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub remove: Vec<String>,
+    /// Headers to set, replacing any with the same name. Can also set a specific Host.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub set: BTreeMap<String, HeaderSource>,
+    /// Headers to add, next to any with the same name.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub append: BTreeMap<String, HeaderSource>,
+}
+
+fn is_rewrite(host: &HostHeader) -> bool {
+    *host == HostHeader::Rewrite
+}
+
+struct HeaderRules {
+    keep_host: bool,
+    remove: Vec<HeaderName>,
+    set: Vec<(HeaderName, HeaderValue)>,
+    append: Vec<(HeaderName, HeaderValue)>,
+}
+
+/// Header rules by upstream authority (`host:port`).
+static RULES: Lazy<RwLock<HashMap<String, Arc<HeaderRules>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+fn header_name(name: &str) -> Result<HeaderName, Box<dyn Error>> {
+    HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|_| format!("Invalid header name {:?}", name).into())
+}
+
+impl HeaderSource {
+    fn resolve(&self, name: &str) -> Result<HeaderValue, Box<dyn Error>> {
+        let (value, secret) = match self {
+            HeaderSource::Value(value) => (value.clone(), false),
+            HeaderSource::Env { env } => {
+                let value = std::env::var(env).map_err(|_| {
+                    format!(
+                        "Environment variable {} for header {} is not set",
+                        env, name
+                    )
+                })?;
+                (value, true)
+            }
+            HeaderSource::File { file } => {
+                let value = std::fs::read_to_string(file).map_err(|e| {
+                    format!(
+                        "Could not read {} for header {}: {}",
+                        file.display(),
+                        name,
+                        e
+                    )
+                })?;
+                (value.trim_end_matches(['\r', '\n']).to_string(), true)
+            }
+        };
+        let mut value = HeaderValue::from_str(&value)
+            .map_err(|_| format!("Invalid value for header {}", name))?;
+        value.set_sensitive(secret);
+        Ok(value)
+    }
+}
+
+fn resolve_all(
+    headers: &BTreeMap<String, HeaderSource>,
+) -> Result<Vec<(HeaderName, HeaderValue)>, Box<dyn Error>> {
+    headers
+        .iter()
+        .map(|(name, source)| Ok((header_name(name)?, source.resolve(name)?)))
+        .collect()
+}
+
+/// Applies `headers` to requests redirected to `server` (`scheme://host:port`).
+pub fn set_upstream_headers(server: &str, headers: &UpstreamHeaders) -> Result<(), Box<dyn Error>> {
+    let uri: Uri = server.parse()?;
+    let key = authority_key(&uri).ok_or_else(|| format!("Invalid server address {}", server))?;
+
+    if *headers == UpstreamHeaders::default() {
+        RULES.write().unwrap().remove(&key);
+        return Ok(());
+    }
+
+    let rules = HeaderRules {
+        keep_host: headers.host == HostHeader::Keep,
+        remove: headers
+            .remove
+            .iter()
+            .map(|name| header_name(name))
+            .collect::<Result<_, _>>()?,
+        set: resolve_all(&headers.set)?,
+        append: resolve_all(&headers.append)?,
+    };
+    tracing::info!("[HEADERS] Using header rules for {}", key);
+    RULES.write().unwrap().insert(key, Arc::new(rules));
+    Ok(())
+}
+
+/// Changes the headers of a request redirected to `upstream`. Returns whether
+/// its Host has to reach the server as it is now.
+pub fn apply(upstream: &Uri, headers: &mut HeaderMap) -> bool {
+    let rules = authority_key(upstream).and_then(|key| RULES.read().unwrap().get(&key).cloned());
+    let Some(rules) = rules else {
+        return false;
+    };
+
+    for name in &rules.remove {
+        headers.remove(name);
+    }
+    for (name, value) in &rules.set {
+        headers.insert(name, value.clone());
+    }
+    for (name, value) in &rules.append {
+        headers.append(name, value.clone());
+    }
+    rules.keep_host || rules.set.iter().any(|(name, _)| name == header::HOST)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(s: &str) -> HeaderSource {
+        HeaderSource::Value(s.to_string())
+    }
+
+    fn values(headers: &HeaderMap, name: &str) -> Vec<String> {
+        headers
+            .get_all(name)
+            .iter()
+            .map(|v| v.to_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn remove_then_set_then_append() {
+        let server = "http://order.test:8080";
+        set_upstream_headers(
+            server,
+            &UpstreamHeaders {
+                remove: vec!["x-token".to_string(), "x-debug".to_string()],
+                set: [("x-token".to_string(), value("set"))].into(),
+                append: [("x-token".to_string(), value("appended"))].into(),
+                ..UpstreamHeaders::default()
+            },
+        )
+        .unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.append("x-token", HeaderValue::from_static("one"));
+        headers.append("x-token", HeaderValue::from_static("two"));
+        headers.insert("x-debug", HeaderValue::from_static("1"));
+        headers.insert("x-other", HeaderValue::from_static("kept"));
+
+        assert!(!apply(&server.parse().unwrap(), &mut headers));
+        assert_eq!(values(&headers, "x-token"), ["set", "appended"]);
+        assert!(!headers.contains_key("x-debug"));
+        assert_eq!(values(&headers, "x-other"), ["kept"]);
+    }
+
+    #[test]
+    fn resolves_sources() {
+        let plain = value("plain").resolve("x-plain").unwrap();
+        assert_eq!(plain, "plain");
+        assert!(!plain.is_sensitive());
+
+        // Set by cargo for the tests, so no variable has to be changed.
+        let env = HeaderSource::Env {
+            env: "CARGO_PKG_NAME".to_string(),
+        }
+        .resolve("x-env")
+        .unwrap();
+        assert_eq!(env, env!("CARGO_PKG_NAME"));
+        assert!(env.is_sensitive());
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("secret");
+        std::fs::write(&file, "s3cret\r\n\n").unwrap();
+        let secret = HeaderSource::File { file: file.clone() }
+            .resolve("x-secret")
+            .unwrap();
+        assert_eq!(secret, "s3cret");
+        assert!(secret.is_sensitive());
+
+        std::fs::write(&file, "two\nlines\n").unwrap();
+        assert!(HeaderSource::File { file }.resolve("x-secret").is_err());
+    }
+
+    #[test]
+    fn missing_sources_are_errors() {
+        let e = HeaderSource::Env {
+            env: "ANIME_GAMES_PROXY_TEST_UNSET".to_string(),
+        }
+        .resolve("x-env")
+        .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Environment variable ANIME_GAMES_PROXY_TEST_UNSET for header x-env is not set"
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let missing = HeaderSource::File {
+            file: dir.path().join("missing"),
+        };
+        assert!(missing.resolve("x-file").is_err());
+
+        let server = "http://missing.test:8080";
+        let headers = UpstreamHeaders {
+            set: [("x-file".to_string(), missing)].into(),
+            ..UpstreamHeaders::default()
+        };
+        assert!(set_upstream_headers(server, &headers).is_err());
+        assert!(!apply(&server.parse().unwrap(), &mut HeaderMap::new()));
+    }
+
+    #[test]
+    fn host_handling() {
+        let keep = "http://keep.test:8080";
+        set_upstream_headers(
+            keep,
+            &UpstreamHeaders {
+                host: HostHeader::Keep,
+                ..UpstreamHeaders::default()
+            },
+        )
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static("game.example.com"));
+        assert!(apply(&keep.parse().unwrap(), &mut headers));
+        assert_eq!(headers[header::HOST], "game.example.com");
+
+        let set = "http://set.test:8080";
+        set_upstream_headers(
+            set,
+            &UpstreamHeaders {
+                set: [("Host".to_string(), value("lobby.lan"))].into(),
+                ..UpstreamHeaders::default()
+            },
+        )
+        .unwrap();
+        assert!(apply(&set.parse().unwrap(), &mut headers));
+        assert_eq!(headers[header::HOST], "lobby.lan");
+
+        let other = "http://other.test:8080";
+        set_upstream_headers(
+            other,
+            &UpstreamHeaders {
+                set: [("x-secret".to_string(), value("s3"))].into(),
+                ..UpstreamHeaders::default()
+            },
+        )
+        .unwrap();
+        assert!(!apply(&other.parse().unwrap(), &mut headers));
+        assert!(!apply(
+            &"http://none.test:8080".parse().unwrap(),
+            &mut headers
+        ));
+    }
+}
@@ -0,0 +1,175 @@
+/*
+ * Host name overrides, like /etc/hosts but only for the proxy.
+ *
+ * Every connection the proxy opens (redirected and passed-through requests,
+ * tunnels, health checks and the pre-launch check) looks here before asking
+ * DNS, so a server can be given by a name the system can't resolve, or one
+ * that resolves differently for the rest of the machine. TLS still checks the
+ * certificate against the name.
+ *
+ * `*.example.com` matches every subdomain of example.com, but not
+ * example.com itself. An exact name wins over wildcards, and a longer
+ * wildcard over a shorter one.
+ */
+
+use once_cell::sync::Lazy;
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    net::IpAddr,
+    sync::RwLock,
+};
+
+#[derive(Default)]
+pub(super) struct Hosts {
+    exact: HashMap<String, IpAddr>,
+    /// `(".example.com", ip)`, longest first.
+    wildcards: Vec<(String, IpAddr)>,
+}
+
+static HOSTS: Lazy<RwLock<Hosts>> = Lazy::new(|| RwLock::new(Hosts::default()));
+
+fn normalize(name: &str) -> String {
+    name.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl Hosts {
+    pub(super) fn compile(hosts: &BTreeMap<String, String>) -> Result<Self, Box<dyn Error>> {
+        let mut table = Hosts::default();
+        for (name, addr) in hosts {
+            let ip: IpAddr = addr
+                .trim()
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse()
+                .map_err(|_| format!("Invalid address {:?} for {} in `hosts`", addr, name))?;
+
+            let name = normalize(name);
+            match name.strip_prefix("*.") {
+                Some(domain) if !domain.is_empty() && !domain.contains('*') => {
+                    table.wildcards.push((format!(".{}", domain), ip));
+                }
+                None if !name.is_empty() && !name.contains('*') => {
+                    table.exact.insert(name, ip);
+                }
+                _ => {
+                    return Err(format!(
+                        "Invalid name {:?} in `hosts`, expected a host name or `*.domain`",
+                        name
+                    )
+                    .into());
+                }
+            }
+        }
+        table
+            .wildcards
+            .sort_by_key(|(suffix, _)| std::cmp::Reverse(suffix.len()));
+        Ok(table)
+    }
+
+    /// Looks up a normalized host name.
+    fn lookup(&self, host: &str) -> Option<IpAddr> {
+        self.exact.get(host).copied().or_else(|| {
+            self.wildcards
+                .iter()
+                .find(|(suffix, _)| host.ends_with(suffix.as_str()))
+                .map(|(_, ip)| *ip)
+        })
+    }
+}
+
+/// Sets the host overrides from the config's `hosts`, replacing the old ones.
+pub fn set_hosts(hosts: &BTreeMap<String, String>) -> Result<(), Box<dyn Error>> {
+    replace_hosts(Hosts::compile(hosts)?);
+    Ok(())
+}
+
+pub(super) fn replace_hosts(table: Hosts) {
+    let count = table.exact.len() + table.wildcards.len();
+    if count > 0 {
+        tracing::info!("[HOSTS] Loaded {} host overrides", count);
+    }
+    *HOSTS.write().unwrap() = table;
+}
+
+/// The address `host` is overridden to, if any.
+pub fn host_override(host: &str) -> Option<IpAddr> {
+    let hosts = HOSTS.read().unwrap();
+    if hosts.exact.is_empty() && hosts.wildcards.is_empty() {
+        return None;
+    }
+
+    let host = normalize(host);
+    let ip = hosts.lookup(&host)?;
+    tracing::debug!("[HOSTS] {} -> {}", host, ip);
+    Some(ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(entries: &[(&str, &str)]) -> Result<Hosts, Box<dyn Error>> {
+        Hosts::compile(
+            &entries
+                .iter()
+                .map(|(name, addr)| (name.to_string(), addr.to_string()))
+                .collect(),
+        )
+    }
+
+    fn lookup(hosts: &Hosts, host: &str) -> Option<String> {
+        hosts.lookup(&normalize(host)).map(|ip| ip.to_string())
+    }
+
+    #[test]
+    fn exact_beats_wildcard() {
+        let hosts = compile(&[
+            ("*.example.com", "10.0.0.1"),
+            ("api.example.com", "10.0.0.2"),
+            ("Game.LAN.", " [fd00::1] "),
+        ])
+        .unwrap();
+
+        assert_eq!(lookup(&hosts, "API.example.com."), Some("10.0.0.2".into()));
+        assert_eq!(lookup(&hosts, "cdn.example.com"), Some("10.0.0.1".into()));
+        assert_eq!(lookup(&hosts, "game.lan"), Some("fd00::1".into()));
+        // A wildcard doesn't cover the domain itself.
+        assert_eq!(lookup(&hosts, "example.com"), None);
+        assert_eq!(lookup(&hosts, "badexample.com"), None);
+    }
+
+    #[test]
+    fn longest_wildcard_wins() {
+        let hosts = compile(&[
+            ("*.example.com", "10.0.0.1"),
+            ("*.eu.example.com", "10.0.0.2"),
+            ("*.a.eu.example.com", "10.0.0.3"),
+        ])
+        .unwrap();
+
+        assert_eq!(lookup(&hosts, "x.example.com"), Some("10.0.0.1".into()));
+        assert_eq!(lookup(&hosts, "eu.example.com"), Some("10.0.0.1".into()));
+        assert_eq!(lookup(&hosts, "x.eu.example.com"), Some("10.0.0.2".into()));
+        assert_eq!(
+            lookup(&hosts, "x.a.eu.example.com"),
+            Some("10.0.0.3".into())
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_entries() {
+        for (name, addr) in [
+            ("game.lan", "not an address"),
+            ("game.lan", "10.0.0"),
+            ("game.lan", "10.0.0.1:80"),
+            ("game.lan", ""),
+            ("*.", "10.0.0.1"),
+            ("*.*.example.com", "10.0.0.1"),
+            ("api.*.example.com", "10.0.0.1"),
+            ("", "10.0.0.1"),
+        ] {
+            assert!(compile(&[(name, addr)]).is_err(), "{} {}", name, addr);
+        }
+    }
+}
@@ -0,0 +1,304 @@
+/*
+ * Answers redirected requests from fixtures on disk instead of the private server.
+ *
+ * The source is either a directory with a `fixtures.json` index, or a HAR file
+ * (e.g. one written by `--record-har`) whose entries are replayed.
+ */
+
+use base64::Engine;
+use hudsucker::hyper::{Body, Response, StatusCode};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    error::Error,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+const INDEX_FILE: &str = "fixtures.json";
+
+#[derive(Debug, Deserialize)]
+struct FixtureIndex {
+    /// Status returned for requests without a fixture.
+    #[serde(default = "default_fallback_status")]
+    fallback_status: u16,
+    fixtures: Vec<Fixture>,
+}
+
+fn default_fallback_status() -> u16 {
+    404
+}
+
+#[derive(Debug, Deserialize)]
+struct Fixture {
+    /// Matches any method if not set.
+    #[serde(default)]
+    method: Option<String>,
+    path: String,
+    /// Matches any query if not set.
+    #[serde(default)]
+    query: Option<String>,
+    #[serde(default = "default_status")]
+    status: u16,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    /// File with the response body, relative to the fixture directory.
+    #[serde(default)]
+    body_file: Option<PathBuf>,
+    /// Inline body, instead of `body_file`.
+    #[serde(default, deserialize_with = "text_body")]
+    body: Option<Vec<u8>>,
+}
+
+fn text_body<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.map(String::into_bytes))
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+struct MockUpstream {
+    dir: PathBuf,
+    fallback_status: u16,
+    fixtures: Vec<Fixture>,
+    // Fall back to a fixture with a different query. Recorded queries
+    // contain timestamps and redacted tokens, so they rarely match exactly.
+    any_query: bool,
+}
+
+static MOCK: Lazy<RwLock<Option<MockUpstream>>> = Lazy::new(|| RwLock::new(None));
+
+impl MockUpstream {
+    fn from_dir(dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let index: FixtureIndex =
+            serde_json::from_str(&std::fs::read_to_string(dir.join(INDEX_FILE))?)?;
+        if let Some(fixture) = index
+            .fixtures
+            .iter()
+            .find(|f| f.body.is_some() && f.body_file.is_some())
+        {
+            return Err(format!("Fixture for {} has both body and body_file", fixture.path).into());
+        }
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            fallback_status: index.fallback_status,
+            fixtures: index.fixtures,
+            any_query: false,
+        })
+    }
+
+    fn from_har(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let har: Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        let entries = har["log"]["entries"]
+            .as_array()
+            .ok_or("HAR file has no log.entries")?;
+
+        let fixtures = entries
+            .iter()
+            .filter_map(|entry| {
+                let request = &entry["request"];
+                let response = &entry["response"];
+                // Match on what the server would have received.
+                let url: hudsucker::hyper::Uri = entry["_redirectedTo"]
+                    .as_str()
+                    .or(request["url"].as_str())?
+                    .parse()
+                    .ok()?;
+
+                // Bodies are stored decoded, so drop headers describing the original encoding.
+                let headers = response["headers"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|h| Some((h["name"].as_str()?, h["value"].as_str()?)))
+                    .filter(|(name, _)| {
+                        !name.eq_ignore_ascii_case("content-length")
+                            && !name.eq_ignore_ascii_case("content-encoding")
+                            && !name.eq_ignore_ascii_case("transfer-encoding")
+                    })
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect();
+
+                let text = response["content"]["text"].as_str().unwrap_or("");
+                let body = if response["content"]["encoding"].as_str() == Some("base64") {
+                    base64::engine::general_purpose::STANDARD
+                        .decode(text)
+                        .ok()?
+                } else {
+                    text.as_bytes().to_vec()
+                };
+
+                Some(Fixture {
+                    method: request["method"].as_str().map(|m| m.to_string()),
+                    path: url.path().to_string(),
+                    query: Some(url.query().unwrap_or("").to_string()),
+                    status: response["status"].as_u64()? as u16,
+                    headers,
+                    body_file: None,
+                    body: Some(body),
+                })
+            })
+            .collect();
+
+        Ok(Self {
+            dir: path.parent().unwrap_or(Path::new(".")).to_path_buf(),
+            fallback_status: default_fallback_status(),
+            fixtures,
+            any_query: true,
+        })
+    }
+
+    /// Finds a fixture for the request, preferring one whose query matches exactly.
+    /// `path` and `query` are the ones the server would receive, after rewrites.
+    fn find(&self, method: &str, path: &str, query: &str) -> Option<&Fixture> {
+        let candidates = || {
+            self.fixtures.iter().filter(|f| {
+                f.path == path
+                    && f.method
+                        .as_deref()
+                        .is_none_or(|m| m.eq_ignore_ascii_case(method))
+            })
+        };
+
+        candidates()
+            .find(|f| f.query.as_deref() == Some(query))
+            .or_else(|| candidates().find(|f| f.query.is_none()))
+            .or_else(|| candidates().find(|_| self.any_query))
+    }
+
+    fn respond(&self, method: &str, path: &str, query: &str) -> Response<Body> {
+        let Some(fixture) = self.find(method, path, query) else {
+            tracing::warn!("[MOCK] No fixture for {} {}?{}", method, path, query);
+            return Response::builder()
+                .status(StatusCode::from_u16(self.fallback_status).unwrap_or(StatusCode::NOT_FOUND))
+                .body(Body::empty())
+                .unwrap();
+        };
+
+        let body = match &fixture.body_file {
+            Some(file) => match std::fs::read(self.dir.join(file)) {
+                Ok(body) => body,
+                Err(e) => {
+                    tracing::error!("[MOCK] Failed to read {}: {}", file.display(), e);
+                    return Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(Body::empty())
+                        .unwrap();
+                }
+            },
+            None => fixture.body.clone().unwrap_or_default(),
+        };
+
+        let mut builder = Response::builder()
+            .status(StatusCode::from_u16(fixture.status).unwrap_or(StatusCode::OK));
+        for (name, value) in &fixture.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+
+        builder.body(Body::from(body)).unwrap_or_else(|e| {
+            tracing::error!("[MOCK] Invalid fixture for {}: {}", path, e);
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::empty())
+                .unwrap()
+        })
+    }
+}
+
+/// Answers redirected requests from `path` (a fixture directory or a HAR file)
+/// instead of forwarding them to the server.
+pub fn load_mock(path: &Path) -> Result<(), Box<dyn Error>> {
+    let mock = if path.is_dir() {
+        MockUpstream::from_dir(path)?
+    } else {
+        MockUpstream::from_har(path)?
+    };
+    tracing::info!(
+        "[MOCK] Serving {} fixtures from {}",
+        mock.fixtures.len(),
+        path.display()
+    );
+    *MOCK.write().unwrap() = Some(mock);
+    Ok(())
+}
+
+/// The canned response for a redirected request, or `None` when not in mock mode.
+pub fn mock_response(method: &str, path: &str, query: &str) -> Option<Response<Body>> {
+    MOCK.read()
+        .unwrap()
+        .as_ref()
+        .map(|mock| mock.respond(method, path, query))
+}
+
+/// The answer to a redirected WebSocket upgrade in mock mode, where nothing
+/// may reach the network, or `None` when not in mock mode.
+pub fn mock_websocket(path: &str) -> Option<Response<Body>> {
+    MOCK.read().unwrap().as_ref().map(|_| {
+        tracing::warn!("[MOCK] Not connecting WebSocket {} in mock mode", path);
+        Response::builder()
+            .status(StatusCode::BAD_GATEWAY)
+            .body(Body::empty())
+            .unwrap()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fixture directory with `index`, kept until the returned `TempDir` is dropped.
+    fn mock(index: &str) -> Result<(tempfile::TempDir, MockUpstream), Box<dyn Error>> {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(INDEX_FILE), index).unwrap();
+        std::fs::write(dir.path().join("region.json"), "{}").unwrap();
+        let mock = MockUpstream::from_dir(dir.path())?;
+        Ok((dir, mock))
+    }
+
+    async fn body(res: Response<Body>) -> Vec<u8> {
+        hudsucker::hyper::body::to_bytes(res.into_body())
+            .await
+            .unwrap()
+            .to_vec()
+    }
+
+    #[tokio::test]
+    async fn serves_inline_and_file_bodies() {
+        let (_dir, mock) = mock(
+            r#"{"fixtures": [
+                {"path": "/inline", "body": "hello"},
+                {"path": "/file", "query": "a=1", "status": 201, "body_file": "region.json"},
+                {"path": "/empty", "status": 204}
+            ]}"#,
+        )
+        .unwrap();
+
+        let res = mock.respond("GET", "/inline", "");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body(res).await, b"hello");
+
+        let res = mock.respond("POST", "/file", "a=1");
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(body(res).await, b"{}");
+
+        let res = mock.respond("GET", "/empty", "");
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(body(res).await.is_empty());
+
+        assert_eq!(
+            mock.respond("GET", "/file", "a=2").status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn rejects_fixtures_with_two_bodies() {
+        assert!(
+            mock(r#"{"fixtures": [{"path": "/", "body": "a", "body_file": "region.json"}]}"#)
+                .is_err()
+        );
+    }
+}
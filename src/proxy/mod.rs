@@ -0,0 +1,837 @@
+/*
+ * Built on example code from:
+ * https://github.com/omjadas/hudsucker/blob/main/examples/log.rs
+ */
+
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use hudsucker::{
+    async_trait::async_trait,
+    hyper::{Body, Method, Request, Response, StatusCode, Uri, header, http::uri::Scheme},
+    *,
+};
+use rcgen::*;
+
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use rustls_pemfile as pemfile;
+
+use openssl::hash::MessageDigest;
+use openssl::x509::X509;
+use std::error::Error;
+use std::io::Write;
+use tempfile::NamedTempFile;
+
+use crate::get_wine::WineRunner;
+use crate::registry::{self, Applied, RegEdit, RegValue};
+
+mod access;
+mod cert_cache;
+mod chain;
+mod control;
+mod failover;
+mod har;
+mod headers;
+mod hosts;
+mod mock;
+mod rewrite;
+mod rules;
+mod shutdown;
+mod socks;
+mod telemetry;
+mod transform;
+mod upstream;
+mod websocket;
+
+pub use access::{SharingConfig, local_addr, parse_listen, set_access};
+use cert_cache::CachedAuthority;
+pub use cert_cache::leaf_dir;
+pub use chain::{UpstreamProxy, dial, set_upstream_proxy, upstream_proxy};
+use control::{ActiveGuard, track_request};
+pub use control::{ControlInfo, control_file, remove_control_file, start_control};
+pub use failover::{HealthCheck, clear_failover, set_failover};
+pub use har::{finish_recording, start_recording};
+pub use headers::{UpstreamHeaders, apply as apply_upstream_headers, set_upstream_headers};
+pub use hosts::{host_override, set_hosts};
+pub use mock::load_mock;
+use mock::{mock_response, mock_websocket};
+use rewrite::rewrite;
+pub use rewrite::{RewriteRule, set_rewrites};
+pub use rules::{InterceptRule, default_rules, set_rules};
+use rules::{RuleAction, match_rule, should_decrypt};
+pub use shutdown::Shutdown;
+pub use socks::start_socks;
+pub use telemetry::{TelemetryConfig, log_telemetry_summary, set_telemetry};
+use transform::ResponseTransform;
+pub use upstream::{UpstreamTls, set_upstream_tls, tls_connect};
+pub use websocket::set_websocket_logging;
+
+fn data_dir() -> Option<PathBuf> {
+    if let Ok(home) = std::env::var("HOME") {
+        return Some(PathBuf::from(home).join(".local/share"));
+    }
+
+    None
+}
+
+// Global var for getting server address.
+static SERVER: Lazy<Mutex<String>> = Lazy::new(|| Mutex::new("http://localhost:443".to_string()));
+#[derive(Clone, Default)]
+struct ProxyHandler {
+    // HAR entry of the request being handled, completed in `handle_response`.
+    har: Option<har::PendingEntry>,
+    // Transforms of the rule that matched the request being handled.
+    transforms: Vec<ResponseTransform>,
+    // Lists the request being handled as active until it's answered.
+    active: Option<Arc<ActiveGuard>>,
+    // Server the request was redirected to, marked down if it can't be reached.
+    upstream: Option<String>,
+    // Set by an authorized CONNECT, for the requests inside its tunnel.
+    authorized: bool,
+}
+
+/// Checks that `addr` is a server requests can be redirected to:
+/// `http://` or `https://`, a host and an optional port, nothing else.
+pub fn check_server(addr: &str) -> Result<(), String> {
+    let addr = addr.replace(' ', "");
+    let uri: Uri = addr
+        .parse()
+        .map_err(|e| format!("Invalid server address {:?}: {}", addr, e))?;
+    match uri.scheme_str() {
+        Some("http" | "https") => {}
+        _ => {
+            return Err(format!(
+                "Invalid server address {:?}: expected http:// or https://",
+                addr
+            ));
+        }
+    }
+    if uri.host().is_none_or(str::is_empty) {
+        return Err(format!("Invalid server address {:?}: missing host", addr));
+    }
+    if uri.path_and_query().is_some_and(|pq| pq.as_str() != "/") {
+        return Err(format!(
+            "Invalid server address {:?}: can't have a path or query",
+            addr
+        ));
+    }
+    Ok(())
+}
+
+pub fn set_proxy_addr(addr: String) {
+    if addr.contains(' ') {
+        let addr2 = addr.replace(' ', "");
+        *SERVER.lock().unwrap() = addr2;
+    } else {
+        *SERVER.lock().unwrap() = addr;
+    }
+
+    tracing::info!("Set server to {}", SERVER.lock().unwrap());
+}
+
+// Upstream server address per game, keyed by `game_key`.
+static GAME_SERVERS: Lazy<Mutex<HashMap<String, String>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn game_key(name: &str) -> String {
+    let name = name.to_lowercase();
+    name.strip_suffix(".exe").unwrap_or(&name).to_string()
+}
+
+/// Registers the upstream used by rules that route to the given game.
+pub fn set_game_server(game: &str, addr: String) {
+    let addr = addr.replace(' ', "");
+    tracing::info!("Set server for {} to {}", game, addr);
+    GAME_SERVERS.lock().unwrap().insert(game_key(game), addr);
+}
+
+fn default_server() -> String {
+    SERVER.lock().unwrap().clone()
+}
+
+fn game_servers() -> HashMap<String, String> {
+    GAME_SERVERS.lock().unwrap().clone()
+}
+
+/// Returns the upstream for a rule: its game's server if set, otherwise `SERVER`.
+/// Games with failover use their currently selected server.
+fn server_for_rule(rule: &InterceptRule) -> String {
+    if let Some(game) = &rule.game {
+        if let Some(addr) = failover::current(Some(game)) {
+            return addr;
+        }
+        match GAME_SERVERS.lock().unwrap().get(&game_key(game)) {
+            Some(addr) => return addr.clone(),
+            None => tracing::warn!("No server configured for game {}, using default", game),
+        }
+    }
+
+    failover::current(None).unwrap_or_else(|| SERVER.lock().unwrap().clone())
+}
+
+/// Whether a CONNECT to `host:port` has to be decrypted, because a rule or
+/// the telemetry block list may answer requests on it.
+fn decrypts(host: &str, port: u16) -> bool {
+    should_decrypt(host, port) || telemetry::blocks_host(host)
+}
+
+impl ProxyHandler {
+    /// Sends a redirected request without dropping its Host, which hudsucker
+    /// does for every request it forwards.
+    async fn send_keeping_host(&mut self, ctx: &HttpContext, req: Request<Body>) -> Response<Body> {
+        match upstream::client().request(req).await {
+            Ok(res) => self.handle_response(ctx, res).await,
+            Err(e) => self.handle_error(ctx, e).await,
+        }
+    }
+}
+
+#[async_trait]
+impl HttpHandler for ProxyHandler {
+    async fn handle_request(
+        &mut self,
+        ctx: &HttpContext,
+        mut req: Request<Body>,
+    ) -> RequestOrResponse {
+        let uri = req.uri().to_string();
+        let is_connect = req.method() == Method::CONNECT;
+
+        if !self.authorized {
+            if let Some(res) = access::refuse_request(ctx.client_addr, req.headers()) {
+                return res.into();
+            }
+            self.authorized = is_connect;
+        }
+        // The credentials are for us, not for the server.
+        req.headers_mut().remove(header::PROXY_AUTHORIZATION);
+
+        if !is_connect {
+            self.active = Some(Arc::new(track_request(ctx.client_addr, &req)));
+        }
+
+        let Some(host) = req.uri().host().map(|h| h.to_string()) else {
+            return req.into();
+        };
+        let port = req
+            .uri()
+            .port_u16()
+            .unwrap_or(if req.uri().scheme() == Some(&Scheme::HTTP) {
+                80
+            } else {
+                443
+            });
+        let path = (!is_connect).then(|| req.uri().path().to_string());
+
+        // Tunnels are opened by hudsucker with a direct connection to what
+        // DNS returns, which doesn't work when we have to go through an
+        // upstream proxy or the host is overridden.
+        if is_connect
+            && !decrypts(&host, port)
+            && (chain::upstream_proxy().is_some() || host_override(&host).is_some())
+        {
+            tracing::debug!("[PROXY] Tunnelling {} ourselves", uri);
+            return chain::tunnel(req, host, port).into();
+        }
+
+        // Telemetry is answered locally, whatever the rules say.
+        if let Some(path) = &path
+            && let Some(res) = telemetry::blocked_response(&host, path)
+        {
+            if !har::is_recording() {
+                return res.into();
+            }
+            let (parts, body) = res.into_parts();
+            let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+            let (_, entry) = har::begin_entry(req, &uri).await;
+            entry.finish_local(parts.status.as_u16(), &parts.headers, &body);
+            return Response::from_parts(parts, Body::from(body)).into();
+        }
+
+        let Some(rule) = match_rule(&host, port, path.as_deref()) else {
+            return req.into();
+        };
+
+        // Handle CONNECTs
+        if is_connect {
+            if rule.action != RuleAction::Pass {
+                tracing::info!("[PROXY] Handling CONNECT for {}", uri);
+            }
+            return req.into();
+        }
+
+        self.transforms = rule.transforms.clone();
+        // Set when the header rules of the server want its Host kept.
+        let mut keep_host = false;
+
+        match rule.action {
+            RuleAction::Pass => {}
+            RuleAction::Block => {
+                tracing::info!("[PROXY] Blocking {}", uri);
+                let res = Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(Body::empty())
+                    .unwrap();
+                if har::is_recording() {
+                    let (_, entry) = har::begin_entry(req, &uri).await;
+                    entry.finish_local(res.status().as_u16(), res.headers(), &[]);
+                }
+                return res.into();
+            }
+            RuleAction::Redirect => {
+                let uri_path_and_query = req
+                    .uri()
+                    .path_and_query()
+                    .map(|pq| pq.as_str())
+                    .unwrap_or("/");
+                let (server, uri_path_and_query) = match rewrite(&host, uri_path_and_query) {
+                    Some(rw) => (
+                        rw.upstream.unwrap_or_else(|| server_for_rule(&rule)),
+                        rw.path_and_query,
+                    ),
+                    None => (server_for_rule(&rule), uri_path_and_query.to_string()),
+                };
+                // Create new URI.
+                let new_uri_str = format!("{}{}", server, uri_path_and_query);
+                let new_uri = match new_uri_str.parse::<Uri>() {
+                    Ok(uri) => uri,
+                    Err(e) => {
+                        tracing::error!(
+                            "[PROXY] Can't redirect {} to {:?}: {}",
+                            uri,
+                            new_uri_str,
+                            e
+                        );
+                        return Response::builder()
+                            .status(StatusCode::BAD_GATEWAY)
+                            .body(Body::empty())
+                            .unwrap()
+                            .into();
+                    }
+                };
+                self.upstream = Some(server);
+
+                tracing::info!("[PROXY] Redirecting {} to {}", uri, new_uri);
+                keep_host = headers::apply(&new_uri, req.headers_mut());
+
+                // hudsucker would connect WebSockets to the original host.
+                if websocket::is_websocket(&req) {
+                    if let Some(res) = mock_websocket(new_uri.path()) {
+                        if har::is_recording() {
+                            *req.uri_mut() = new_uri;
+                            let (_, entry) = har::begin_entry(req, &uri).await;
+                            entry.finish_local(res.status().as_u16(), res.headers(), &[]);
+                        }
+                        return res.into();
+                    }
+                    return websocket::forward(req, new_uri, &uri, keep_host)
+                        .await
+                        .into();
+                }
+
+                if let Some(res) = mock_response(
+                    req.method().as_str(),
+                    new_uri.path(),
+                    new_uri.query().unwrap_or(""),
+                ) {
+                    let res = transform::apply(&rule.transforms, res).await;
+                    *req.uri_mut() = new_uri;
+                    if har::is_recording() {
+                        let (_, entry) = har::begin_entry(req, &uri).await;
+                        return har::record_response(entry, res).into();
+                    }
+                    return res.into();
+                }
+
+                // Set request URI to the new one.
+                *req.uri_mut() = new_uri;
+            }
+        }
+
+        // WebSocket upgrades never reach `handle_response`.
+        let req = if har::is_recording() && !req.headers().contains_key(header::UPGRADE) {
+            let (req, entry) = har::begin_entry(req, &uri).await;
+            self.har = Some(entry);
+            req
+        } else {
+            req
+        };
+
+        if keep_host {
+            return self.send_keeping_host(ctx, req).await.into();
+        }
+        req.into()
+    }
+
+    async fn handle_response(
+        &mut self,
+        _context: &HttpContext,
+        response: Response<Body>,
+    ) -> Response<Body> {
+        self.upstream = None;
+        let response = if self.transforms.is_empty() {
+            response
+        } else {
+            transform::apply(&std::mem::take(&mut self.transforms), response).await
+        };
+
+        self.active = None;
+
+        match self.har.take() {
+            Some(entry) => har::record_response(entry, response),
+            None => response,
+        }
+    }
+
+    async fn handle_error(
+        &mut self,
+        _ctx: &HttpContext,
+        err: hudsucker::hyper::Error,
+    ) -> Response<Body> {
+        self.active = None;
+
+        tracing::error!("[PROXY] Failed to forward request: {}", err);
+        if err.is_connect()
+            && let Some(addr) = self.upstream.take()
+        {
+            failover::report_failure(&addr, &err.to_string());
+        }
+        Response::builder()
+            .status(StatusCode::BAD_GATEWAY)
+            .body(Body::empty())
+            .expect("Failed to build response")
+    }
+
+    async fn should_intercept(&mut self, _ctx: &HttpContext, req: &Request<Body>) -> bool {
+        // Only CONNECTs get here. Hosts without a rule are tunnelled as raw bytes,
+        // so their TLS (and any certificate pinning) stays untouched.
+        let Some(host) = req.uri().host() else {
+            return false;
+        };
+        let decrypt = decrypts(host, req.uri().port_u16().unwrap_or(443));
+        if !decrypt {
+            tracing::debug!("[PROXY] Tunnelling {}", req.uri());
+        }
+        decrypt
+    }
+}
+
+/// File in the prefix holding the thumbprint of the CA installed there.
+const CA_MARKER: &str = ".anime-games-proxy-ca";
+
+const ROOT_STORE_KEY: &str =
+    "HKEY_LOCAL_MACHINE\\Software\\Microsoft\\SystemCertificates\\Root\\Certificates";
+
+/// `CERT_CERT_PROP_ID`, the property holding the encoded certificate.
+const CERT_CERT_PROP_ID: u32 = 32;
+
+/// Windows shows and stores certificates by their SHA-1 hash.
+fn thumbprint(cert: &X509) -> Result<String, Box<dyn Error>> {
+    Ok(hex::encode_upper(cert.digest(MessageDigest::sha1())?))
+}
+
+/// The certificate as a serialized store element, the format of the `Blob`
+/// values in the registry: property id, a reserved 1, length and data.
+fn serialized_cert(der: &[u8]) -> Vec<u8> {
+    let mut blob = Vec::with_capacity(der.len() + 12);
+    blob.extend(CERT_CERT_PROP_ID.to_le_bytes());
+    blob.extend(1u32.to_le_bytes());
+    blob.extend((der.len() as u32).to_le_bytes());
+    blob.extend(der);
+    blob
+}
+
+/*
+ * Installs a certificate into the trusted root store of a Wine prefix, through
+ * the registry module: the registry files are edited directly when possible,
+ * otherwise the change is imported with `regedit` through `runner`.
+ *
+ * A marker file in the prefix records the installed certificate, so this does
+ * nothing if it's already there. If the marker names another certificate (the CA
+ * was rotated) that one is removed from the store.
+ *
+ * Returns whether the certificate was installed.
+ */
+pub fn install_cert_into_wine(
+    cert_path: &Path,
+    wine_prefix: &Path,
+    runner: &WineRunner,
+    force: bool,
+) -> Result<bool, Box<dyn Error>> {
+    let cert_bytes = fs::read(cert_path)?;
+    let x509 = match X509::from_pem(&cert_bytes) {
+        Ok(cert) => cert,
+        Err(_) => X509::from_der(&cert_bytes)?,
+    };
+    let sha1_hex = thumbprint(&x509)?;
+
+    let marker = wine_prefix.join(CA_MARKER);
+    let installed = fs::read_to_string(&marker)
+        .ok()
+        .map(|s| s.trim().to_uppercase())
+        .filter(|s| !s.is_empty());
+    let blob = RegValue::Binary(serialized_cert(&x509.to_der()?));
+    // The marker can outlive the key, e.g. if the registry was reset.
+    let in_registry = || {
+        registry::RegistryFile::open(wine_prefix, registry::Hive::Machine)
+            .map(|file| {
+                file.get(
+                    &format!(
+                        "Software\\Microsoft\\SystemCertificates\\Root\\Certificates\\{}",
+                        sha1_hex
+                    ),
+                    Some("Blob"),
+                )
+                .as_ref()
+                    == Some(&blob)
+            })
+            .unwrap_or(true)
+    };
+    if installed.as_deref() == Some(sha1_hex.as_str()) && !force && in_registry() {
+        tracing::debug!(
+            "[CERT] CA {} is already installed in {}",
+            sha1_hex,
+            wine_prefix.display()
+        );
+        return Ok(false);
+    }
+
+    let mut edits = Vec::new();
+    if let Some(old) = installed.as_deref().filter(|old| *old != sha1_hex) {
+        tracing::info!(
+            "[CERT] The CA was rotated, replacing {} in {}",
+            old,
+            wine_prefix.display()
+        );
+        edits.push(RegEdit::DeleteKey {
+            key: format!("{}\\{}", ROOT_STORE_KEY, old),
+        });
+    }
+    // Earlier versions put the CA into the personal store, where it isn't trusted.
+    edits.push(RegEdit::DeleteKey {
+        key: format!(
+            "HKEY_CURRENT_USER\\Software\\Microsoft\\SystemCertificates\\My\\Certificates\\{}",
+            sha1_hex
+        ),
+    });
+    edits.push(RegEdit::Set {
+        key: format!("{}\\{}", ROOT_STORE_KEY, sha1_hex),
+        name: Some("Blob".to_string()),
+        value: blob,
+    });
+
+    tracing::info!(
+        "[CERT] Installing CA {} into {}",
+        sha1_hex,
+        wine_prefix.display()
+    );
+    match registry::apply(wine_prefix, &edits, Some(runner))? {
+        Applied::Files => tracing::debug!("[CERT] Edited the registry files"),
+        Applied::Regedit => tracing::debug!("[CERT] Imported with regedit using {}", runner),
+    }
+
+    fs::write(&marker, format!("{}\n", sha1_hex))
+        .map_err(|e| format!("Could not write {}: {}", marker.display(), e))?;
+    tracing::info!("[CERT] CA installed into {}", wine_prefix.display());
+    Ok(true)
+}
+
+/// The port the proxy listens on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyPort {
+    Fixed(u16),
+    /// Use the default port if it's free, any free port otherwise.
+    Auto,
+}
+
+impl std::str::FromStr for ProxyPort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("auto") {
+            return Ok(ProxyPort::Auto);
+        }
+        s.parse()
+            .map(ProxyPort::Fixed)
+            .map_err(|_| format!("Invalid proxy port {:?}, expected a number or \"auto\"", s))
+    }
+}
+
+const DEFAULT_PROXY_PORT: u16 = 8080;
+
+/// Binds the proxy's listening socket, so a taken port is reported before anything is launched.
+fn bind_listener(port: ProxyPort) -> Result<std::net::TcpListener, Box<dyn Error>> {
+    let listen = access::listen_addr();
+    let bind = |port: u16| std::net::TcpListener::bind(SocketAddr::new(listen, port));
+
+    let listener = match port {
+        ProxyPort::Fixed(port) => bind(port).map_err(|e| {
+            format!(
+                "Could not listen on {}: {}. Choose another port with --proxy-port, or use --proxy-port auto",
+                SocketAddr::new(listen, port),
+                e
+            )
+        })?,
+        ProxyPort::Auto => bind(DEFAULT_PROXY_PORT).or_else(|e| {
+            tracing::info!(
+                "[PROXY] Port {} is not available ({}), using a free port",
+                DEFAULT_PROXY_PORT,
+                e
+            );
+            bind(0)
+        })?,
+    };
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/**
+ * Starts an HTTP(S) proxy server.
+ * Returns once the proxy is listening, with the port it listens on.
+ */
+pub async fn create_proxy(
+    proxy_port: ProxyPort,
+    shutdown: Shutdown,
+) -> Result<(u16, tokio::task::JoinHandle<()>), Box<dyn Error>> {
+    let listener = bind_listener(proxy_port)?;
+    let listen_addr = listener.local_addr()?;
+    let proxy_port = listen_addr.port();
+
+    let cert_dir = match ca_dir() {
+        Some(dir) => dir,
+        None => {
+            panic!("Could not determine data directory");
+        }
+    };
+    let pk_path = cert_dir.join("private.key");
+    let ca_path = cert_dir.join("cert.crt");
+
+    // Try regenerating the CA stuff if it's missing. If that doesn't work, quit.
+    if !pk_path.exists() || !ca_path.exists() {
+        tracing::info!("No CA certificate found, generating one");
+        if let Err(e) = generate_ca_files(cert_dir.parent().unwrap(), DEFAULT_CA_VALID_DAYS) {
+            tracing::error!("Failed to generate CA certificate: {}", e);
+        }
+    }
+    restrict_key_permissions(&pk_path);
+
+    // Get the certificate and private key.
+    let mut private_key_bytes: &[u8] = &fs::read(&pk_path).expect("Could not read private key");
+    let mut ca_cert_bytes: &[u8] = &fs::read(&ca_path).expect("Could not read certificate");
+
+    // Parse the private key and certificate.
+    let private_key = rustls::PrivateKey(
+        pemfile::pkcs8_private_keys(&mut private_key_bytes)
+            .expect("Failed to parse private key")
+            .remove(0),
+    );
+
+    let ca_cert = rustls::Certificate(
+        pemfile::certs(&mut ca_cert_bytes)
+            .expect("Failed to parse CA certificate")
+            .remove(0),
+    );
+
+    // Create the certificate authority, reusing the leaf certificates of earlier sessions.
+    let authority = CachedAuthority::new(private_key, ca_cert, &cert_dir)
+        .expect("Failed to create Certificate Authority");
+
+    // Create an instance of the proxy.
+    let proxy = ProxyBuilder::new()
+        .with_listener(listener)
+        .with_client(upstream::client())
+        .with_ca(authority)
+        .with_http_handler(ProxyHandler::default())
+        .build();
+
+    tracing::info!("[PROXY] Starting proxy server on {}", listen_addr);
+
+    // Start the proxy.
+    let handle = tokio::spawn(async move {
+        shutdown::serve(proxy.start(shutdown.triggered()), &shutdown).await;
+        tracing::info!("[PROXY] Proxy server stopped");
+    });
+
+    Ok((proxy_port, handle))
+}
+
+/// Keys written by older versions were readable by everyone.
+fn restrict_key_permissions(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+
+    let Ok(metadata) = fs::metadata(path) else {
+        return;
+    };
+    if metadata.permissions().mode() & 0o077 != 0 {
+        tracing::info!("Making {} readable only by you", path.display());
+        if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(0o600)) {
+            tracing::warn!("Failed to change permissions of {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Validity of a newly generated CA.
+pub const DEFAULT_CA_VALID_DAYS: u32 = 3650;
+/// Longest validity of a CA, about 100 years. Dates much further out can't be
+/// written into a certificate.
+pub const MAX_CA_VALID_DAYS: u32 = 36_500;
+
+/// Directory holding the CA certificate (`cert.crt`) and key (`private.key`).
+pub fn ca_dir() -> Option<PathBuf> {
+    Some(data_dir()?.join("anime-games-proxy").join("ca"))
+}
+
+/// Name of this machine, to tell CAs of different installs apart.
+fn host_name() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Writes `data` to `path` with the given mode, replacing it atomically.
+fn write_private(path: &Path, data: &[u8], mode: u32) -> Result<(), Box<dyn Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = path.parent().ok_or("Invalid path")?;
+    let mut file = NamedTempFile::new_in(dir)?;
+    file.write_all(data)?;
+    file.as_file()
+        .set_permissions(fs::Permissions::from_mode(mode))?;
+    file.persist(path)?;
+    Ok(())
+}
+
+/*
+ * Generates a private key and certificate used by the certificate authority.
+ * The common name contains the host name and a random ID, so the CAs of two
+ * installs can be told apart.
+ * Source: https://github.com/zu1k/good-mitm/raw/master/src/ca/gen.rs
+ */
+pub fn generate_ca_files(path: &Path, valid_days: u32) -> Result<(), Box<dyn Error>> {
+    if valid_days > MAX_CA_VALID_DAYS {
+        return Err(format!("A CA can be valid for at most {} days", MAX_CA_VALID_DAYS).into());
+    }
+    let mut params = CertificateParams::default();
+    let mut details = DistinguishedName::new();
+
+    let mut id = [0u8; 4];
+    openssl::rand::rand_bytes(&mut id)?;
+    let mut serial = [0u8; 8];
+    openssl::rand::rand_bytes(&mut serial)?;
+
+    // Set certificate details. Common names are limited to 64 characters.
+    let mut common_name = format!("AnimeGamesProxy {} {}", host_name(), hex::encode(id));
+    if common_name.len() > 64 {
+        common_name = format!("AnimeGamesProxy {}", hex::encode(id));
+    }
+    details.push(DnType::CommonName, common_name);
+    details.push(DnType::OrganizationName, "AnimeGames");
+    details.push(DnType::CountryName, "US");
+    details.push(DnType::LocalityName, "Local");
+
+    // Set details in the parameter.
+    params.distinguished_name = details;
+    // Set other properties.
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![
+        KeyUsagePurpose::DigitalSignature,
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+    ];
+    params.serial_number = Some(u64::from_be_bytes(serial) >> 1);
+
+    // Valid from yesterday, in case the clock is a little behind.
+    let date = |date: chrono::NaiveDate| {
+        use chrono::Datelike;
+        date_time_ymd(date.year(), date.month() as u8, date.day() as u8)
+    };
+    let today = chrono::Utc::now().date_naive();
+    params.not_before = date(today - chrono::Days::new(1));
+    params.not_after = date(today + chrono::Days::new(valid_days.into()));
+
+    // Create certificate.
+    let cert = Certificate::from_params(params)?;
+    let cert_crt = cert.serialize_pem()?;
+    let private_key = cert.serialize_private_key_pem();
+
+    // Make certificate directory.
+    let cert_dir = path.join("ca");
+    fs::create_dir_all(&cert_dir)
+        .map_err(|e| format!("Error creating certificate directory: {}", e))?;
+
+    // Write the private key first, so a new certificate is never paired with an old key.
+    let private_key_path = cert_dir.join("private.key");
+    write_private(&private_key_path, private_key.as_bytes(), 0o600).map_err(|e| {
+        format!(
+            "Error writing private key to {}: {}",
+            private_key_path.display(),
+            e
+        )
+    })?;
+    tracing::info!("Wrote private key to {}", private_key_path.display());
+
+    let cert_path = cert_dir.join("cert.crt");
+    write_private(&cert_path, cert_crt.as_bytes(), 0o644).map_err(|e| {
+        format!(
+            "Error writing certificate to {}: {}",
+            cert_path.display(),
+            e
+        )
+    })?;
+    tracing::info!("Wrote certificate to {}", cert_path.display());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn common_name(path: &Path) -> String {
+        let cert = X509::from_pem(&fs::read(path.join("ca/cert.crt")).unwrap()).unwrap();
+        let entry = cert
+            .subject_name()
+            .entries_by_nid(openssl::nid::Nid::COMMONNAME)
+            .next()
+            .unwrap();
+        entry.data().as_utf8().unwrap().to_string()
+    }
+
+    #[test]
+    fn generated_ca_files() {
+        let (first, second) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        generate_ca_files(first.path(), DEFAULT_CA_VALID_DAYS).unwrap();
+        generate_ca_files(second.path(), DEFAULT_CA_VALID_DAYS).unwrap();
+
+        let name = common_name(first.path());
+        assert!(name.starts_with("AnimeGamesProxy "), "{}", name);
+        assert!(name.len() <= 64);
+        assert_ne!(name, common_name(second.path()));
+
+        let mode = |file: &str| {
+            fs::metadata(first.path().join("ca").join(file))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777
+        };
+        assert_eq!(mode("private.key"), 0o600);
+        assert_eq!(mode("cert.crt"), 0o644);
+    }
+
+    #[test]
+    fn ca_validity_is_bounded() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(generate_ca_files(dir.path(), MAX_CA_VALID_DAYS + 1).is_err());
+        assert!(!dir.path().join("ca").exists());
+    }
+}
@@ -0,0 +1,254 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{error::Error, sync::RwLock};
+
+use super::rules::{HostMatch, HostMatcher};
+
+/// Rewrites the path (and optionally the upstream) of redirected requests.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RewriteRule {
+    /// Only rewrite requests originally sent to this host.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<HostMatch>,
+    /// Regex matched against the path and query, e.g. `^/query_region_list(\?.*)?$`.
+    #[serde(rename = "match")]
+    pub pattern: String,
+    /// Replacement path and query. Capture groups are available as `$1`, `${name}`, ...
+    pub replace: String,
+    /// Upstream to send the rewritten request to, e.g. `https://10.0.0.2:8443`.
+    /// Without it, the upstream picked by the interception rule is kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<String>,
+}
+
+/// The result of applying a rewrite rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rewrite {
+    pub path_and_query: String,
+    pub upstream: Option<String>,
+}
+
+struct CompiledRewrite {
+    host: Option<HostMatcher>,
+    pattern: Regex,
+    replace: String,
+    upstream: Option<String>,
+}
+
+/// Rewrite rules compiled for matching. The first matching rule wins.
+pub struct RewriteSet {
+    rules: Vec<CompiledRewrite>,
+}
+
+impl RewriteSet {
+    /// Compiles the rules, failing on the first one that is invalid.
+    pub fn compile(rules: &[RewriteRule]) -> Result<Self, Box<dyn Error>> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let host = rule
+                    .host
+                    .as_ref()
+                    .map(HostMatcher::new)
+                    .transpose()
+                    .map_err(|e| {
+                        format!("Invalid host regex in rewrite {:?}: {}", rule.pattern, e)
+                    })?;
+                let pattern = Regex::new(&rule.pattern)
+                    .map_err(|e| format!("Invalid regex in rewrite {:?}: {}", rule.pattern, e))?;
+                check_replace(&rule.replace)
+                    .map_err(|e| format!("Invalid replace in rewrite {:?}: {}", rule.pattern, e))?;
+                if let Some(upstream) = &rule.upstream {
+                    super::check_server(upstream).map_err(|e| {
+                        format!("Invalid upstream in rewrite {:?}: {}", rule.pattern, e)
+                    })?;
+                }
+                Ok(CompiledRewrite {
+                    host,
+                    pattern,
+                    replace: rule.replace.clone(),
+                    upstream: rule.upstream.clone(),
+                })
+            })
+            .collect::<Result<_, Box<dyn Error>>>()?;
+
+        Ok(Self { rules })
+    }
+
+    /// Applies the first rule matching `host` and `path_and_query`.
+    pub fn apply(&self, host: &str, path_and_query: &str) -> Option<Rewrite> {
+        let host = host.to_lowercase();
+        let rule = self.rules.iter().find(|rule| {
+            rule.host.as_ref().is_none_or(|h| h.matches(&host))
+                && rule.pattern.is_match(path_and_query)
+        })?;
+
+        let mut path_and_query = rule
+            .pattern
+            .replace(path_and_query, rule.replace.as_str())
+            .into_owned();
+        if !path_and_query.starts_with('/') {
+            path_and_query.insert(0, '/');
+        }
+
+        Some(Rewrite {
+            path_and_query,
+            upstream: rule.upstream.clone(),
+        })
+    }
+}
+
+/// `$1`, `${name}` and `$$` in a replacement.
+static CAPTURE_REF: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\$(\$|\{[^}]*\}|[0-9A-Za-z_]+)").unwrap());
+
+/// Checks that a replacement gives a valid path and query. Captures come from
+/// the request's own path and query, so only the text around them is checked.
+fn check_replace(replace: &str) -> Result<(), String> {
+    let literal = CAPTURE_REF.replace_all(replace, "");
+    // The URI parser lets some of these through, but servers don't.
+    if let Some(c) = literal
+        .chars()
+        .find(|c| !c.is_ascii_graphic() || "\"<>\\^`{|}".contains(*c))
+    {
+        return Err(format!("{:?} isn't allowed in a URI", c));
+    }
+    let separator = if literal.starts_with('/') { "" } else { "/" };
+    format!("http://localhost{}{}", separator, literal)
+        .parse::<hudsucker::hyper::Uri>()
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+static REWRITES: Lazy<RwLock<RewriteSet>> =
+    Lazy::new(|| RwLock::new(RewriteSet { rules: Vec::new() }));
+
+/// Sets the rewrite rules, keeping the old ones if any of the new is invalid.
+pub fn set_rewrites(rules: &[RewriteRule]) -> Result<(), Box<dyn Error>> {
+    replace_rewrites(RewriteSet::compile(rules)?);
+    Ok(())
+}
+
+pub fn replace_rewrites(compiled: RewriteSet) {
+    tracing::info!("Loaded {} rewrite rules", compiled.rules.len());
+    *REWRITES.write().unwrap() = compiled;
+}
+
+/// Rewrites a redirected request, if any rewrite rule matches it.
+pub fn rewrite(host: &str, path_and_query: &str) -> Option<Rewrite> {
+    REWRITES.read().unwrap().apply(host, path_and_query)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(pattern: &str, replace: &str) -> RewriteRule {
+        RewriteRule {
+            host: None,
+            pattern: pattern.to_string(),
+            replace: replace.to_string(),
+            upstream: None,
+        }
+    }
+
+    fn apply(rules: &[RewriteRule], host: &str, path_and_query: &str) -> Option<Rewrite> {
+        RewriteSet::compile(rules)
+            .unwrap()
+            .apply(host, path_and_query)
+    }
+
+    #[test]
+    fn rewrites_prefix() {
+        let rules = [rule("^/old/", "/new/")];
+        let rw = apply(&rules, "example.com", "/old/list").unwrap();
+        assert_eq!(rw.path_and_query, "/new/list");
+        assert_eq!(rw.upstream, None);
+        assert_eq!(apply(&rules, "example.com", "/other/old/list"), None);
+    }
+
+    #[test]
+    fn rewrites_regex() {
+        let rules = [rule(
+            r"^/query_(?P<kind>\w+)_list(\?.*)?$",
+            "/query/${kind}$2",
+        )];
+        let rw = apply(&rules, "example.com", "/query_region_list?version=1").unwrap();
+        assert_eq!(rw.path_and_query, "/query/region?version=1");
+        assert_eq!(apply(&rules, "example.com", "/query_region"), None);
+    }
+
+    #[test]
+    fn keeps_query() {
+        let rules = [rule("^/a", "/b")];
+        let rw = apply(&rules, "example.com", "/a/c?x=1&y=%20").unwrap();
+        assert_eq!(rw.path_and_query, "/b/c?x=1&y=%20");
+    }
+
+    #[test]
+    fn adds_leading_slash() {
+        let rules = [rule("^/a$", "b")];
+        assert_eq!(
+            apply(&rules, "example.com", "/a").unwrap().path_and_query,
+            "/b"
+        );
+    }
+
+    #[test]
+    fn overrides_upstream() {
+        let rules = [RewriteRule {
+            upstream: Some("https://10.0.0.2:8443".to_string()),
+            ..rule("^/dispatch", "/dispatch")
+        }];
+        let rw = apply(&rules, "example.com", "/dispatch?a=b").unwrap();
+        assert_eq!(rw.upstream.as_deref(), Some("https://10.0.0.2:8443"));
+        assert_eq!(rw.path_and_query, "/dispatch?a=b");
+    }
+
+    #[test]
+    fn matches_host() {
+        let rules = [
+            RewriteRule {
+                host: Some(HostMatch::Suffix("mihoyo.com".to_string())),
+                ..rule("^/", "/first/")
+            },
+            rule("^/", "/second/"),
+        ];
+        assert_eq!(
+            apply(&rules, "API.mihoyo.com", "/x")
+                .unwrap()
+                .path_and_query,
+            "/first/x"
+        );
+        assert_eq!(
+            apply(&rules, "example.com", "/x").unwrap().path_and_query,
+            "/second/x"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        let invalid = [
+            rule("(", "/"),
+            rule("^/", "/a b"),
+            rule("^/", "/\"quoted\""),
+            RewriteRule {
+                upstream: Some("10.0.0.2:8443".to_string()),
+                ..rule("^/", "/")
+            },
+            RewriteRule {
+                upstream: Some("https://10.0.0.2/path".to_string()),
+                ..rule("^/", "/")
+            },
+        ];
+        for rule in invalid {
+            assert!(
+                RewriteSet::compile(std::slice::from_ref(&rule)).is_err(),
+                "{:?}",
+                rule
+            );
+        }
+        assert!(RewriteSet::compile(&[rule("^/(.*)$", "/v1/$1?from=${0}")]).is_ok());
+    }
+}
@@ -0,0 +1,366 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::sync::RwLock;
+
+use super::transform::ResponseTransform;
+
+/// How a rule matches the request host.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HostMatch {
+    /// Host must be exactly this name.
+    Exact(String),
+    /// Host must be this domain or one of its subdomains.
+    Suffix(String),
+    /// Whole host must match this regular expression.
+    Regex(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    /// Send the request to the private server.
+    Redirect,
+    /// Forward the request to its original destination.
+    Pass,
+    /// Answer the request locally without contacting anyone.
+    Block,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InterceptRule {
+    pub host: HostMatch,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path_prefix: Option<String>,
+    pub action: RuleAction,
+    /// Name of the game in `games` whose server receives redirected requests.
+    /// Without it, the default server is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub game: Option<String>,
+    /// Changes applied, in order, to responses of matched requests.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transforms: Vec<ResponseTransform>,
+}
+
+impl InterceptRule {
+    fn redirect_suffix(domain: &str) -> Self {
+        Self {
+            host: HostMatch::Suffix(domain.to_string()),
+            port: None,
+            path_prefix: None,
+            action: RuleAction::Redirect,
+            game: None,
+            transforms: Vec::new(),
+        }
+    }
+}
+
+/// The domains that used to be hardcoded in `ProxyHandler`.
+pub fn default_rules() -> Vec<InterceptRule> {
+    [
+        "hoyoverse.com",
+        "mihoyo.com",
+        "yuanshen.com",
+        "starrails.com",
+        "bhsr.com",
+        "bh3.com",
+        "honkaiimpact3.com",
+        "zenlesszonezero.com",
+        "stellasora.global",
+        "yostarplat.com",
+    ]
+    .iter()
+    .map(|domain| InterceptRule::redirect_suffix(domain))
+    .collect()
+}
+
+pub(super) enum HostMatcher {
+    Exact(String),
+    Suffix(String),
+    Regex(Regex),
+}
+
+impl HostMatcher {
+    pub(super) fn new(host: &HostMatch) -> Result<Self, regex::Error> {
+        Ok(match host {
+            HostMatch::Exact(name) => HostMatcher::Exact(name.to_lowercase()),
+            HostMatch::Suffix(domain) => {
+                HostMatcher::Suffix(domain.trim_start_matches('.').to_lowercase())
+            }
+            HostMatch::Regex(pattern) => {
+                HostMatcher::Regex(Regex::new(&format!("^(?:{})$", pattern))?)
+            }
+        })
+    }
+
+    /// Checks a host, which must already be lowercase.
+    pub(super) fn matches(&self, host: &str) -> bool {
+        match self {
+            HostMatcher::Exact(name) => host == name,
+            HostMatcher::Suffix(domain) => {
+                host == domain
+                    || (host.ends_with(domain.as_str())
+                        && host[..host.len() - domain.len()].ends_with('.'))
+            }
+            HostMatcher::Regex(re) => re.is_match(host),
+        }
+    }
+}
+
+struct CompiledRule {
+    rule: InterceptRule,
+    host: HostMatcher,
+}
+
+/// Rules compiled for matching. The first matching rule wins.
+pub struct RuleSet {
+    rules: Vec<CompiledRule>,
+}
+
+impl RuleSet {
+    /// Compiles the rules, skipping (and logging) any with an invalid regex.
+    pub fn compile(rules: &[InterceptRule]) -> Self {
+        let rules = rules
+            .iter()
+            .filter_map(|rule| {
+                let host = match HostMatcher::new(&rule.host) {
+                    Ok(host) => host,
+                    Err(e) => {
+                        tracing::error!("Skipping rule with invalid host regex: {}", e);
+                        return None;
+                    }
+                };
+                Some(CompiledRule {
+                    rule: rule.clone(),
+                    host,
+                })
+            })
+            .collect();
+
+        Self { rules }
+    }
+
+    /// Finds the first rule matching the request.
+    ///
+    /// `path` is `None` for CONNECT requests, where the path isn't known yet;
+    /// path-restricted rules are then treated as matching.
+    pub fn find(&self, host: &str, port: u16, path: Option<&str>) -> Option<&InterceptRule> {
+        let host = host.to_lowercase();
+        self.rules
+            .iter()
+            .find(|compiled| {
+                let rule = &compiled.rule;
+                compiled.host.matches(&host)
+                    && rule.port.is_none_or(|p| p == port)
+                    && match (&rule.path_prefix, path) {
+                        (Some(prefix), Some(path)) => path.starts_with(prefix.as_str()),
+                        _ => true,
+                    }
+            })
+            .map(|compiled| &compiled.rule)
+    }
+
+    /// Whether any request to `host:port` may be redirected or blocked. Rules
+    /// are checked up to the first one covering every path.
+    pub fn decrypts(&self, host: &str, port: u16) -> bool {
+        let host = host.to_lowercase();
+        for compiled in &self.rules {
+            let rule = &compiled.rule;
+            if !compiled.host.matches(&host) || rule.port.is_some_and(|p| p != port) {
+                continue;
+            }
+            if rule.action != RuleAction::Pass {
+                return true;
+            }
+            if rule.path_prefix.is_none() {
+                return false;
+            }
+        }
+        false
+    }
+}
+
+static RULES: Lazy<RwLock<RuleSet>> = Lazy::new(|| RwLock::new(RuleSet::compile(&default_rules())));
+
+pub fn set_rules(rules: &[InterceptRule]) {
+    replace_rules(RuleSet::compile(rules));
+}
+
+pub fn replace_rules(compiled: RuleSet) {
+    tracing::info!("Loaded {} interception rules", compiled.rules.len());
+    *RULES.write().unwrap() = compiled;
+}
+
+/// Returns a copy of the first rule matching the request, if any.
+pub fn match_rule(host: &str, port: u16, path: Option<&str>) -> Option<InterceptRule> {
+    RULES.read().unwrap().find(host, port, path).cloned()
+}
+
+/// Whether a CONNECT to `host:port` has to be decrypted, i.e. whether any
+/// request on it may be redirected or blocked. Everything else is tunnelled.
+pub fn should_decrypt(host: &str, port: u16) -> bool {
+    RULES.read().unwrap().decrypts(host, port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(host: HostMatch, action: RuleAction) -> InterceptRule {
+        InterceptRule {
+            host,
+            port: None,
+            path_prefix: None,
+            action,
+            game: None,
+            transforms: Vec::new(),
+        }
+    }
+
+    fn exact(name: &str) -> HostMatch {
+        HostMatch::Exact(name.to_string())
+    }
+
+    fn action(rules: &RuleSet, host: &str, port: u16, path: Option<&str>) -> Option<RuleAction> {
+        rules.find(host, port, path).map(|rule| rule.action)
+    }
+
+    #[test]
+    fn host_matching() {
+        let rules = RuleSet::compile(&[
+            rule(exact("Api.Example.com"), RuleAction::Block),
+            rule(
+                HostMatch::Suffix(".example.com".to_string()),
+                RuleAction::Redirect,
+            ),
+            rule(
+                HostMatch::Regex(r"dispatch\d+\.test|cdn\.test".to_string()),
+                RuleAction::Pass,
+            ),
+        ]);
+
+        assert_eq!(
+            action(&rules, "API.example.com", 443, None),
+            Some(RuleAction::Block)
+        );
+        assert_eq!(
+            action(&rules, "example.com", 443, None),
+            Some(RuleAction::Redirect)
+        );
+        assert_eq!(
+            action(&rules, "a.b.example.com", 443, None),
+            Some(RuleAction::Redirect)
+        );
+        assert_eq!(action(&rules, "badexample.com", 443, None), None);
+        assert_eq!(action(&rules, "example.com.evil", 443, None), None);
+
+        assert_eq!(
+            action(&rules, "dispatch12.test", 443, None),
+            Some(RuleAction::Pass)
+        );
+        assert_eq!(
+            action(&rules, "cdn.test", 443, None),
+            Some(RuleAction::Pass)
+        );
+        // Regexes match the whole host, also across alternations.
+        assert_eq!(action(&rules, "dispatch12.test.evil", 443, None), None);
+        assert_eq!(action(&rules, "evil-dispatch1.test", 443, None), None);
+        assert_eq!(action(&rules, "cdn.test.evil", 443, None), None);
+    }
+
+    #[test]
+    fn port_and_path_filters() {
+        let rules = RuleSet::compile(&[
+            InterceptRule {
+                port: Some(8443),
+                ..rule(exact("api.example.com"), RuleAction::Block)
+            },
+            InterceptRule {
+                path_prefix: Some("/account/".to_string()),
+                ..rule(exact("api.example.com"), RuleAction::Redirect)
+            },
+        ]);
+
+        assert_eq!(
+            action(&rules, "api.example.com", 8443, Some("/")),
+            Some(RuleAction::Block)
+        );
+        assert_eq!(
+            action(&rules, "api.example.com", 443, Some("/account/login")),
+            Some(RuleAction::Redirect)
+        );
+        assert_eq!(action(&rules, "api.example.com", 443, Some("/news")), None);
+        // CONNECT doesn't know the path yet.
+        assert_eq!(
+            action(&rules, "api.example.com", 443, None),
+            Some(RuleAction::Redirect)
+        );
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let rules = RuleSet::compile(&[
+            rule(HostMatch::Regex("(".to_string()), RuleAction::Block),
+            rule(exact("api.example.com"), RuleAction::Pass),
+            rule(
+                HostMatch::Suffix("example.com".to_string()),
+                RuleAction::Redirect,
+            ),
+        ]);
+
+        assert_eq!(rules.rules.len(), 2);
+        assert_eq!(
+            action(&rules, "api.example.com", 443, None),
+            Some(RuleAction::Pass)
+        );
+        assert_eq!(
+            action(&rules, "www.example.com", 443, None),
+            Some(RuleAction::Redirect)
+        );
+    }
+
+    #[test]
+    fn decrypts_for_redirects_after_path_passes() {
+        let rules = RuleSet::compile(&[
+            InterceptRule {
+                path_prefix: Some("/static".to_string()),
+                ..rule(exact("api.example.com"), RuleAction::Pass)
+            },
+            rule(exact("api.example.com"), RuleAction::Redirect),
+        ]);
+        assert!(rules.decrypts("api.example.com", 443));
+        assert_eq!(
+            rules
+                .find("api.example.com", 443, Some("/static/a.png"))
+                .unwrap()
+                .action,
+            RuleAction::Pass
+        );
+        assert_eq!(
+            rules
+                .find("api.example.com", 443, Some("/login"))
+                .unwrap()
+                .action,
+            RuleAction::Redirect
+        );
+    }
+
+    #[test]
+    fn stops_at_pass_for_every_path() {
+        let rules = RuleSet::compile(&[
+            rule(exact("api.example.com"), RuleAction::Pass),
+            rule(exact("api.example.com"), RuleAction::Redirect),
+            InterceptRule {
+                port: Some(8443),
+                ..rule(exact("cdn.example.com"), RuleAction::Block)
+            },
+        ]);
+        assert!(!rules.decrypts("api.example.com", 443));
+        assert!(rules.decrypts("cdn.example.com", 8443));
+        assert!(!rules.decrypts("cdn.example.com", 443));
+        assert!(!rules.decrypts("other.example.com", 443));
+    }
+}
@@ -0,0 +1,199 @@
+/*
+ * Stopping the proxy.
+ *
+ * A `Shutdown` is shared by whoever decides when to stop (the run loop once
+ * the game has exited, or the signal handlers of `proxy` mode) and the proxy.
+ * Once it's triggered, the proxy and the SOCKS5 listener stop accepting
+ * connections, and the proxy waits up to DRAIN_TIMEOUT for the requests in
+ * flight to be answered, so their HAR entries are complete when the recording
+ * is written.
+ */
+
+use std::{future::Future, sync::Arc, time::Duration};
+use tokio::{sync::watch, time::Instant};
+
+use super::{control, har};
+
+/// How long requests in flight get to finish.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Tells the proxy to stop. Clones share the same state.
+#[derive(Clone)]
+pub struct Shutdown(Arc<watch::Sender<bool>>);
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self(Arc::new(watch::Sender::new(false)))
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn trigger(&self) {
+        self.0.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Completes once `trigger` has been called.
+    pub async fn triggered(&self) {
+        let mut receiver = self.0.subscribe();
+        // Can't fail, `self` keeps the sender alive.
+        let _ = receiver.wait_for(|triggered| *triggered).await;
+    }
+}
+
+/// Requests that haven't been answered yet, or whose response body is still
+/// being recorded.
+fn in_flight() -> usize {
+    control::active_requests() + har::pending_bodies()
+}
+
+/// Runs `server` until `shutdown` is triggered, then drains it: waits for it
+/// to close its connections and for the requests inside tunnels to finish.
+pub(super) async fn serve<F>(server: F, shutdown: &Shutdown)
+where
+    F: Future<Output = Result<(), hudsucker::Error>>,
+{
+    tokio::pin!(server);
+
+    let mut result = None;
+    tokio::select! {
+        biased;
+        _ = shutdown.triggered() => {}
+        stopped = &mut server => result = Some(stopped),
+    }
+    if !shutdown.is_triggered() {
+        if let Some(Err(e)) = result {
+            tracing::error!("[PROXY] Error running proxy: {}", e);
+        }
+        return;
+    }
+
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    let pending = in_flight();
+    if pending > 0 {
+        tracing::info!(
+            "[PROXY] Stopping, waiting up to {}s for {} requests to finish",
+            DRAIN_TIMEOUT.as_secs(),
+            pending
+        );
+    } else {
+        tracing::info!("[PROXY] Stopping");
+    }
+
+    let result = match result {
+        Some(result) => result,
+        None => match tokio::time::timeout_at(deadline, &mut server).await {
+            Ok(result) => result,
+            Err(_) => {
+                tracing::warn!(
+                    "[PROXY] Closing {} requests that didn't finish in time",
+                    in_flight()
+                );
+                return;
+            }
+        },
+    };
+    if let Err(e) = result {
+        tracing::error!("[PROXY] Error running proxy: {}", e);
+    }
+
+    // Tunnels aren't tracked by the server, so wait for their requests too.
+    while in_flight() > 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let pending = in_flight();
+    if pending > 0 {
+        tracing::warn!(
+            "[PROXY] Closing {} requests that didn't finish in time",
+            pending
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hudsucker::hyper::{Body, Request};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// A server that stops `after` the shutdown, or never.
+    async fn server(shutdown: Shutdown, after: Option<Duration>) -> Result<(), hudsucker::Error> {
+        shutdown.triggered().await;
+        match after {
+            Some(after) => tokio::time::sleep(after).await,
+            None => std::future::pending().await,
+        }
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_for_requests_in_flight() {
+        let shutdown = Shutdown::new();
+        let request = Request::get("http://example.com/")
+            .body(Body::empty())
+            .unwrap();
+        let guard = control::track_request(([127, 0, 0, 1], 50000).into(), &request);
+        let finished = Arc::new(AtomicBool::new(false));
+        tokio::spawn({
+            let finished = finished.clone();
+            async move {
+                tokio::time::sleep(Duration::from_secs(3)).await;
+                finished.store(true, Ordering::SeqCst);
+                drop(guard);
+            }
+        });
+
+        let start = Instant::now();
+        shutdown.trigger();
+        serve(
+            server(shutdown.clone(), Some(Duration::from_secs(1))),
+            &shutdown,
+        )
+        .await;
+
+        assert!(finished.load(Ordering::SeqCst));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(3), "{:?}", elapsed);
+        assert!(elapsed < DRAIN_TIMEOUT, "{:?}", elapsed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cuts_off_stalled_server() {
+        let shutdown = Shutdown::new();
+        let start = Instant::now();
+        tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                shutdown.trigger();
+            }
+        });
+        serve(server(shutdown.clone(), None), &shutdown).await;
+
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= DRAIN_TIMEOUT + Duration::from_secs(1),
+            "{:?}",
+            elapsed
+        );
+        assert!(
+            elapsed < DRAIN_TIMEOUT + Duration::from_secs(2),
+            "{:?}",
+            elapsed
+        );
+    }
+
+    #[tokio::test]
+    async fn stops_without_shutdown_when_server_ends() {
+        let shutdown = Shutdown::new();
+        serve(async { Ok(()) }, &shutdown).await;
+        assert!(!shutdown.is_triggered());
+    }
+}
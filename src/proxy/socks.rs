@@ -0,0 +1,711 @@
+/*
+ * SOCKS5 front end for programs that ignore `http_proxy`/`https_proxy`.
+ *
+ * TCP connections are handed to the HTTP proxy, so they go through the same
+ * rules and the same MITM CA: TLS and other traffic as a CONNECT tunnel, plain
+ * HTTP as proxied requests. UDP ASSOCIATE is relayed as-is when enabled, only
+ * datagrams to blocked hosts are dropped.
+ */
+
+use hudsucker::hyper::{
+    self, Body, Request, Response, StatusCode, Uri, client::conn::SendRequest, server::conn::Http,
+    service::service_fn,
+};
+use std::{
+    error::Error,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::Mutex,
+};
+
+use super::access::{self, ClientKind};
+use super::hosts::host_override;
+use super::rules::{RuleAction, match_rule};
+use super::shutdown::Shutdown;
+
+const SOCKS_VERSION: u8 = 0x05;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_PASSWORD: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+/// Version of the username/password subnegotiation (RFC 1929).
+const PASSWORD_VERSION: u8 = 0x01;
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_GENERAL_FAILURE: u8 = 0x01;
+const REPLY_NOT_ALLOWED: u8 = 0x02;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// How long to wait for the client to speak first before treating the
+/// connection as an opaque tunnel.
+const SNIFF_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Address {
+    Ip(IpAddr),
+    Domain(String),
+}
+
+impl Address {
+    fn host(&self) -> String {
+        match self {
+            Address::Ip(ip) => ip.to_string(),
+            Address::Domain(domain) => domain.clone(),
+        }
+    }
+}
+
+async fn read_address(stream: &mut TcpStream, address_type: u8) -> io::Result<Option<Address>> {
+    Ok(match address_type {
+        0x01 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip).await?;
+            Some(Address::Ip(IpAddr::from(ip)))
+        }
+        0x04 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip).await?;
+            Some(Address::Ip(IpAddr::from(ip)))
+        }
+        0x03 => {
+            let len = stream.read_u8().await? as usize;
+            let mut domain = vec![0u8; len];
+            stream.read_exact(&mut domain).await?;
+            String::from_utf8(domain).ok().map(Address::Domain)
+        }
+        _ => None,
+    })
+}
+
+fn encode_address(addr: SocketAddr) -> Vec<u8> {
+    let mut out = Vec::new();
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            out.push(0x01);
+            out.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            out.push(0x04);
+            out.extend_from_slice(&ip.octets());
+        }
+    }
+    out.extend_from_slice(&addr.port().to_be_bytes());
+    out
+}
+
+async fn reply(stream: &mut TcpStream, code: u8, bound: SocketAddr) -> io::Result<()> {
+    let mut message = vec![SOCKS_VERSION, code, 0x00];
+    message.extend(encode_address(bound));
+    stream.write_all(&message).await
+}
+
+fn unspecified() -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+}
+
+/// Reads the server name from a TLS ClientHello, if the whole hello is in `data`.
+fn sni_from_client_hello(data: &[u8]) -> Option<String> {
+    // TLS record header (5) + handshake header (4) + version (2) + random (32).
+    let mut pos = 5 + 4 + 2 + 32;
+    if data.first()? != &0x16 || data.get(5)? != &0x01 {
+        return None;
+    }
+
+    let session_id_len = *data.get(pos)? as usize;
+    pos += 1 + session_id_len;
+    let cipher_suites_len = u16::from_be_bytes([*data.get(pos)?, *data.get(pos + 1)?]) as usize;
+    pos += 2 + cipher_suites_len;
+    let compression_len = *data.get(pos)? as usize;
+    pos += 1 + compression_len;
+
+    let extensions_len = u16::from_be_bytes([*data.get(pos)?, *data.get(pos + 1)?]) as usize;
+    pos += 2;
+    let extensions_end = (pos + extensions_len).min(data.len());
+
+    while pos + 4 <= extensions_end {
+        let kind = u16::from_be_bytes([data[pos], data[pos + 1]]);
+        let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        pos += 4;
+        if kind == 0x0000 {
+            // server_name_list: list length (2), name type (1), name length (2), name.
+            let name_len = u16::from_be_bytes([*data.get(pos + 3)?, *data.get(pos + 4)?]) as usize;
+            let name = data.get(pos + 5..pos + 5 + name_len)?;
+            return String::from_utf8(name.to_vec()).ok();
+        }
+        pos += len;
+    }
+    None
+}
+
+/// Whether `data` starts like an HTTP/1 request line, e.g. `POST /path HTTP/1.1`.
+fn looks_like_http(data: &[u8]) -> bool {
+    let method_len = data.iter().take_while(|b| b.is_ascii_uppercase()).count();
+    method_len > 0 && data.get(method_len) == Some(&b' ')
+}
+
+/// Opens a CONNECT tunnel through the HTTP proxy.
+async fn connect_via_proxy(proxy_port: u16, authority: &str) -> io::Result<TcpStream> {
+    let mut upstream = TcpStream::connect(access::local_addr(proxy_port)).await?;
+    upstream
+        .write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", authority).as_bytes())
+        .await?;
+
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > 16 * 1024 {
+            return Err(io::Error::other("response to CONNECT is too long"));
+        }
+        head.push(upstream.read_u8().await?);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let code = head.split_whitespace().nth(1).unwrap_or_default();
+    if code.starts_with('2') {
+        return Ok(upstream);
+    }
+    let kind = match code {
+        "403" | "407" => io::ErrorKind::PermissionDenied,
+        _ => io::ErrorKind::Other,
+    };
+    Err(io::Error::new(
+        kind,
+        format!(
+            "proxy refused CONNECT to {}: {}",
+            authority,
+            head.lines().next().unwrap_or_default()
+        ),
+    ))
+}
+
+/// The SOCKS reply for a CONNECT the HTTP proxy refused.
+fn failure_reply(e: &io::Error) -> u8 {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => REPLY_NOT_ALLOWED,
+        _ => REPLY_GENERAL_FAILURE,
+    }
+}
+
+fn authority(host: &str, port: u16) -> String {
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(_)) => format!("[{}]:{}", host, port),
+        _ => format!("{}:{}", host, port),
+    }
+}
+
+/// Serves plain HTTP from the client by sending each request to the HTTP proxy.
+/// hudsucker only recognises plain HTTP in a tunnel when it starts with `GET`.
+async fn serve_http(
+    client: TcpStream,
+    proxy_port: u16,
+    authority: String,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let upstream = TcpStream::connect(access::local_addr(proxy_port)).await?;
+    let (sender, connection) = hyper::client::conn::handshake(upstream).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            tracing::debug!("[SOCKS] Proxy connection closed: {}", e);
+        }
+    });
+    let sender: Arc<Mutex<SendRequest<Body>>> = Arc::new(Mutex::new(sender));
+
+    let service = service_fn(move |mut req: Request<Body>| {
+        let sender = sender.clone();
+        let authority = authority.clone();
+        async move {
+            // Requests to the proxy need the absolute form, `http://host/path`.
+            let host = req
+                .headers()
+                .get(hyper::header::HOST)
+                .and_then(|h| h.to_str().ok())
+                .map(|h| h.to_string())
+                .unwrap_or(authority);
+            let path = req
+                .uri()
+                .path_and_query()
+                .map(|pq| pq.as_str())
+                .unwrap_or("/");
+            *req.uri_mut() = match format!("http://{}{}", host, path).parse::<Uri>() {
+                Ok(uri) => uri,
+                Err(_) => {
+                    return Ok::<_, hyper::Error>(
+                        Response::builder()
+                            .status(StatusCode::BAD_REQUEST)
+                            .body(Body::empty())
+                            .unwrap(),
+                    );
+                }
+            };
+
+            let response = {
+                let mut sender = sender.lock().await;
+                std::future::poll_fn(|cx| sender.poll_ready(cx)).await?;
+                sender.send_request(req)
+            };
+            response.await
+        }
+    });
+
+    Http::new()
+        .http1_only(true)
+        .http1_preserve_header_case(true)
+        .serve_connection(client, service)
+        .await?;
+    Ok(())
+}
+
+/// Handles a CONNECT from a SOCKS client to `address:port`.
+async fn connect(
+    mut client: TcpStream,
+    address: Address,
+    port: u16,
+    proxy_port: u16,
+) -> io::Result<()> {
+    // With a name, the tunnel is opened before answering, so the client learns
+    // when the proxy refuses it. An IP target only gets its name from what the
+    // client sends first, which it doesn't until it has the answer.
+    let tunnel = match &address {
+        Address::Domain(domain) => {
+            match connect_via_proxy(proxy_port, &authority(domain, port)).await {
+                Ok(tunnel) => Some(tunnel),
+                Err(e) => {
+                    reply(&mut client, failure_reply(&e), unspecified()).await?;
+                    return Err(e);
+                }
+            }
+        }
+        Address::Ip(_) => None,
+    };
+    reply(&mut client, REPLY_SUCCEEDED, unspecified()).await?;
+
+    // Look at what the client sends first, without consuming it. Clients that
+    // resolve names themselves only give us an IP, but the name is usually in
+    // the TLS server name or the Host header.
+    let mut peeked = vec![0u8; 4096];
+    let len = tokio::time::timeout(SNIFF_TIMEOUT, client.peek(&mut peeked))
+        .await
+        .unwrap_or(Ok(0))?;
+    let peeked = &peeked[..len];
+
+    let host = match (&address, sni_from_client_hello(peeked)) {
+        (Address::Ip(_), Some(name)) => name,
+        _ => address.host(),
+    };
+    let authority = authority(&host, port);
+
+    // Plain HTTP is sent as requests instead, so an open tunnel is just dropped.
+    if looks_like_http(peeked) {
+        tracing::debug!("[SOCKS] Serving HTTP for {}", authority);
+        if let Err(e) = serve_http(client, proxy_port, authority.clone()).await {
+            tracing::debug!("[SOCKS] HTTP connection to {} closed: {}", authority, e);
+        }
+        return Ok(());
+    }
+
+    tracing::debug!("[SOCKS] Tunnelling {}", authority);
+    let mut upstream = match tunnel {
+        Some(tunnel) => tunnel,
+        None => connect_via_proxy(proxy_port, &authority).await?,
+    };
+    if let Err(e) = tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+        tracing::debug!("[SOCKS] Tunnel to {} closed: {}", authority, e);
+    }
+    Ok(())
+}
+
+/// Relays UDP datagrams for one client until its TCP connection closes.
+async fn udp_associate(mut client: TcpStream) -> io::Result<()> {
+    let client_ip = client.peer_addr()?.ip();
+    // Bind where the client reached us, so the address in the reply is reachable.
+    let relay = UdpSocket::bind(SocketAddr::new(client.local_addr()?.ip(), 0)).await?;
+    let outbound = UdpSocket::bind(unspecified()).await?;
+    reply(&mut client, REPLY_SUCCEEDED, relay.local_addr()?).await?;
+
+    let mut client_addr: Option<SocketAddr> = None;
+    let mut from_client = vec![0u8; 65536];
+    let mut from_remote = vec![0u8; 65536];
+    let mut control = [0u8; 1];
+
+    loop {
+        tokio::select! {
+            // The association ends with the TCP connection.
+            read = client.read(&mut control) => {
+                if matches!(read, Ok(0) | Err(_)) {
+                    return Ok(());
+                }
+            }
+            received = relay.recv_from(&mut from_client) => {
+                let (len, from) = received?;
+                if from.ip() != client_ip {
+                    continue;
+                }
+                client_addr = Some(from);
+                let Some((target, payload)) = parse_udp_request(&from_client[..len]).await else {
+                    continue;
+                };
+                outbound.send_to(payload, target).await?;
+            }
+            received = outbound.recv_from(&mut from_remote) => {
+                let (len, from) = received?;
+                let Some(client_addr) = client_addr else {
+                    continue;
+                };
+                let mut datagram = vec![0x00, 0x00, 0x00];
+                datagram.extend(encode_address(from));
+                datagram.extend_from_slice(&from_remote[..len]);
+                relay.send_to(&datagram, client_addr).await?;
+            }
+        }
+    }
+}
+
+/// Parses a SOCKS5 UDP request header. Returns `None` for fragments, unknown
+/// address types and blocked hosts.
+async fn parse_udp_request(data: &[u8]) -> Option<(SocketAddr, &[u8])> {
+    // RSV (2), FRAG (1), ATYP (1), address, port (2), data.
+    if data.len() < 4 || data[2] != 0x00 {
+        return None;
+    }
+    let (host, rest) = match data[3] {
+        0x01 => (
+            IpAddr::from(<[u8; 4]>::try_from(data.get(4..8)?).ok()?).to_string(),
+            &data[8..],
+        ),
+        0x04 => (
+            IpAddr::from(<[u8; 16]>::try_from(data.get(4..20)?).ok()?).to_string(),
+            &data[20..],
+        ),
+        0x03 => {
+            let len = *data.get(4)? as usize;
+            (
+                String::from_utf8(data.get(5..5 + len)?.to_vec()).ok()?,
+                &data[5 + len..],
+            )
+        }
+        _ => return None,
+    };
+    let port = u16::from_be_bytes([*rest.first()?, *rest.get(1)?]);
+    let payload = &rest[2..];
+
+    if let Some(rule) = match_rule(&host, port, None)
+        && rule.action == RuleAction::Block
+    {
+        tracing::debug!("[SOCKS] Dropping UDP datagram to {}:{}", host, port);
+        return None;
+    }
+
+    let target = match host_override(&host) {
+        Some(ip) => SocketAddr::new(ip, port),
+        None => tokio::net::lookup_host((host.as_str(), port))
+            .await
+            .ok()?
+            .next()?,
+    };
+    Some((target, payload))
+}
+
+/// Reads a username/password subnegotiation and checks the credentials.
+async fn authenticate(client: &mut TcpStream) -> io::Result<bool> {
+    let version = client.read_u8().await?;
+    if version != PASSWORD_VERSION {
+        return Err(io::Error::other(format!(
+            "unsupported authentication version {}",
+            version
+        )));
+    }
+    let mut username = vec![0u8; client.read_u8().await? as usize];
+    client.read_exact(&mut username).await?;
+    let mut password = vec![0u8; client.read_u8().await? as usize];
+    client.read_exact(&mut password).await?;
+
+    let valid = access::check_credentials(&username, &password);
+    client
+        .write_all(&[PASSWORD_VERSION, if valid { 0x00 } else { 0x01 }])
+        .await?;
+    Ok(valid)
+}
+
+async fn handle_client(mut client: TcpStream, proxy_port: u16, udp: bool) -> io::Result<()> {
+    let client_ip = client.peer_addr()?.ip().to_canonical();
+    let kind = access::classify(client_ip);
+    if kind == ClientKind::Refused {
+        tracing::warn!(
+            "[ACCESS] Refused SOCKS5 client {}, not in an allowed network",
+            client_ip
+        );
+        return Ok(());
+    }
+
+    // Greeting: local clients need no authentication, shared ones a password.
+    let version = client.read_u8().await?;
+    if version != SOCKS_VERSION {
+        return Err(io::Error::other(format!(
+            "unsupported SOCKS version {}",
+            version
+        )));
+    }
+    let method_count = client.read_u8().await? as usize;
+    let mut methods = vec![0u8; method_count];
+    client.read_exact(&mut methods).await?;
+    let method = match kind {
+        ClientKind::Local => METHOD_NO_AUTH,
+        _ => METHOD_PASSWORD,
+    };
+    if !methods.contains(&method) {
+        client
+            .write_all(&[SOCKS_VERSION, METHOD_NONE_ACCEPTABLE])
+            .await?;
+        return Ok(());
+    }
+    client.write_all(&[SOCKS_VERSION, method]).await?;
+    if method == METHOD_PASSWORD && !authenticate(&mut client).await? {
+        tracing::warn!("[ACCESS] Wrong SOCKS5 credentials from {}", client_ip);
+        return Ok(());
+    }
+
+    let mut request = [0u8; 4];
+    client.read_exact(&mut request).await?;
+    let [_, command, _, address_type] = request;
+    let Some(address) = read_address(&mut client, address_type).await? else {
+        return reply(&mut client, REPLY_ADDRESS_NOT_SUPPORTED, unspecified()).await;
+    };
+    let port = client.read_u16().await?;
+
+    match command {
+        CMD_CONNECT => connect(client, address, port, proxy_port).await,
+        CMD_UDP_ASSOCIATE if udp => udp_associate(client).await,
+        CMD_UDP_ASSOCIATE => reply(&mut client, REPLY_NOT_ALLOWED, unspecified()).await,
+        _ => reply(&mut client, REPLY_COMMAND_NOT_SUPPORTED, unspecified()).await,
+    }
+}
+
+/// Starts the SOCKS5 listener, forwarding into the HTTP proxy on `proxy_port`.
+/// Returns the port it listens on. Stops accepting connections once `shutdown`
+/// is triggered.
+pub fn start_socks(
+    socks_port: u16,
+    proxy_port: u16,
+    udp: bool,
+    shutdown: Shutdown,
+) -> Result<u16, Box<dyn Error>> {
+    let addr = SocketAddr::new(access::listen_addr(), socks_port);
+    let listener = std::net::TcpListener::bind(addr)
+        .map_err(|e| format!("Could not listen on SOCKS address {}: {}", addr, e))?;
+    listener.set_nonblocking(true)?;
+    let addr = listener.local_addr()?;
+    let socks_port = addr.port();
+    let listener = TcpListener::from_std(listener)?;
+
+    tracing::info!(
+        "[SOCKS] Starting SOCKS5 server on {}{}",
+        addr,
+        if udp { " (with UDP)" } else { "" }
+    );
+
+    tokio::spawn(async move {
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = shutdown.triggered() => break,
+            };
+            let (client, client_addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::error!("[SOCKS] Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            tokio::spawn(async move {
+                if let Err(e) = handle_client(client, proxy_port, udp).await {
+                    tracing::debug!("[SOCKS] Connection from {} failed: {}", client_addr, e);
+                }
+            });
+        }
+    });
+
+    Ok(socks_port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_hello(server_name: Option<&str>) -> Vec<u8> {
+        let mut extensions = Vec::new();
+        // supported_versions, before the server name.
+        extensions.extend_from_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+        if let Some(name) = server_name {
+            let name = name.as_bytes();
+            extensions.extend_from_slice(&[0x00, 0x00]);
+            extensions.extend_from_slice(&(name.len() as u16 + 5).to_be_bytes());
+            extensions.extend_from_slice(&(name.len() as u16 + 3).to_be_bytes());
+            extensions.push(0x00);
+            extensions.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            extensions.extend_from_slice(name);
+        }
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0x42; 32]);
+        hello.extend_from_slice(&[0x04, 1, 2, 3, 4]);
+        hello.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+        hello.extend_from_slice(&[0x01, 0x00]);
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend(extensions);
+
+        let mut handshake = vec![0x01, 0x00];
+        handshake.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+        handshake.extend(hello);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend(handshake);
+        record
+    }
+
+    #[test]
+    fn server_name() {
+        let hello = client_hello(Some("dispatch.example.com"));
+        assert_eq!(
+            sni_from_client_hello(&hello).as_deref(),
+            Some("dispatch.example.com")
+        );
+        assert_eq!(sni_from_client_hello(&client_hello(None)), None);
+
+        // Cut off anywhere, including inside the name.
+        for len in 0..hello.len() {
+            assert_eq!(sni_from_client_hello(&hello[..len]), None, "{}", len);
+        }
+
+        let mut not_handshake = hello.clone();
+        not_handshake[0] = 0x17;
+        assert_eq!(sni_from_client_hello(&not_handshake), None);
+        let mut not_hello = hello.clone();
+        not_hello[5] = 0x02;
+        assert_eq!(sni_from_client_hello(&not_hello), None);
+    }
+
+    #[test]
+    fn http_detection() {
+        assert!(looks_like_http(b"GET / HTTP/1.1\r\n"));
+        assert!(looks_like_http(b"POST /api HTTP/1.1\r\n"));
+        assert!(looks_like_http(b"OPTIONS * HTTP/1.1\r\n"));
+        assert!(!looks_like_http(b""));
+        assert!(!looks_like_http(b"GET"));
+        assert!(!looks_like_http(b"get / HTTP/1.1\r\n"));
+        assert!(!looks_like_http(b" / HTTP/1.1\r\n"));
+        assert!(!looks_like_http(&client_hello(Some("example.com"))));
+    }
+
+    #[tokio::test]
+    async fn udp_requests() {
+        let v4 = [0, 0, 0, 0x01, 127, 0, 0, 1, 0x1f, 0x90, b'h', b'i'];
+        assert_eq!(
+            parse_udp_request(&v4).await,
+            Some((SocketAddr::from(([127, 0, 0, 1], 8080)), &b"hi"[..]))
+        );
+
+        let mut v6 = vec![0, 0, 0, 0x04];
+        v6.extend_from_slice(&[0; 15]);
+        v6.extend_from_slice(&[1, 0x00, 0x35]);
+        assert_eq!(
+            parse_udp_request(&v6).await,
+            Some((
+                SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, 53)),
+                &b""[..]
+            ))
+        );
+
+        let mut domain = vec![0, 0, 0, 0x03, 9];
+        domain.extend_from_slice(b"127.0.0.1");
+        domain.extend_from_slice(&[0x00, 0x35, b'x']);
+        assert_eq!(
+            parse_udp_request(&domain).await,
+            Some((SocketAddr::from(([127, 0, 0, 1], 53)), &b"x"[..]))
+        );
+
+        // Truncated anywhere before the port ends.
+        for len in 0..10 {
+            assert_eq!(parse_udp_request(&v4[..len]).await, None, "{}", len);
+        }
+        for len in 0..domain.len() - 1 {
+            assert_eq!(parse_udp_request(&domain[..len]).await, None, "{}", len);
+        }
+
+        let mut fragment = v4;
+        fragment[2] = 0x01;
+        assert_eq!(parse_udp_request(&fragment).await, None);
+        let mut unknown = v4;
+        unknown[3] = 0x02;
+        assert_eq!(parse_udp_request(&unknown).await, None);
+        let bad_name = [0, 0, 0, 0x03, 2, 0xff, 0xfe, 0x00, 0x35];
+        assert_eq!(parse_udp_request(&bad_name).await, None);
+    }
+
+    /// A stand-in for the HTTP proxy that answers every CONNECT with `status`.
+    async fn fake_proxy(status: &'static str) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    head.push(stream.read_u8().await.unwrap());
+                }
+                let response = format!("HTTP/1.1 {}\r\n\r\n", status);
+                stream.write_all(response.as_bytes()).await.unwrap();
+                let _ = stream.read_u8().await;
+            }
+        });
+        port
+    }
+
+    async fn socks_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn refused_connect_is_reported() {
+        for (status, code) in [
+            ("403 Forbidden", REPLY_NOT_ALLOWED),
+            ("502 Bad Gateway", REPLY_GENERAL_FAILURE),
+        ] {
+            let proxy_port = fake_proxy(status).await;
+            let (mut client, server) = socks_pair().await;
+            let domain = Address::Domain("blocked.example.com".to_string());
+            assert!(connect(server, domain, 443, proxy_port).await.is_err());
+
+            let mut answer = [0u8; 10];
+            client.read_exact(&mut answer).await.unwrap();
+            assert_eq!(answer, [SOCKS_VERSION, code, 0, 0x01, 0, 0, 0, 0, 0, 0]);
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_tunnels_after_reply() {
+        let proxy_port = fake_proxy("200 Connection established").await;
+        let (mut client, server) = socks_pair().await;
+        let domain = Address::Domain("game.example.com".to_string());
+        let task = tokio::spawn(connect(server, domain, 443, proxy_port));
+
+        let mut answer = [0u8; 10];
+        client.read_exact(&mut answer).await.unwrap();
+        assert_eq!(answer[1], REPLY_SUCCEEDED);
+        client
+            .write_all(&client_hello(Some("game.example.com")))
+            .await
+            .unwrap();
+        drop(client);
+        assert!(task.await.unwrap().is_ok());
+    }
+}
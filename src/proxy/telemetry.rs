@@ -0,0 +1,354 @@
+/*
+ * Answers telemetry and log-upload requests locally.
+ *
+ * Private servers don't implement these endpoints, and the 404s make the
+ * game retry and slow down startup. Matching requests are checked before the
+ * interception rules and get a canned response instead.
+ */
+
+use hudsucker::hyper::{Body, Response, StatusCode, header};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, RwLock},
+};
+
+use super::rules::{HostMatch, HostMatcher};
+
+/// A telemetry endpoint. At least one of `host` and `path_prefix` must be set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TelemetryEndpoint {
+    /// Matches any host if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<HostMatch>,
+    /// Matches any path if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path_prefix: Option<String>,
+}
+
+/// The response given to blocked telemetry requests.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CannedResponse {
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    pub body: String,
+}
+
+impl Default for CannedResponse {
+    fn default() -> Self {
+        Self {
+            status: 200,
+            content_type: Some("application/json".to_string()),
+            body: "{}".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    /// Block the built-in list of telemetry endpoints.
+    pub builtin: bool,
+    /// More endpoints to block.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub block: Vec<TelemetryEndpoint>,
+    pub response: CannedResponse,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            builtin: true,
+            block: Vec::new(),
+            response: CannedResponse::default(),
+        }
+    }
+}
+
+/// Known crash-report, log-upload and APM endpoints.
+fn builtin_endpoints() -> Vec<TelemetryEndpoint> {
+    let hosts = [
+        "log-upload.mihoyo.com",
+        "log-upload-os.mihoyo.com",
+        "log-upload-os.hoyoverse.com",
+        "sdk-log-upload.mihoyo.com",
+        "sdk-log-upload-os.hoyoverse.com",
+        "apm-log-upload.mihoyo.com",
+        "apm-log-upload-os.hoyoverse.com",
+        "devlog-upload.mihoyo.com",
+        "devlog-upload-os.hoyoverse.com",
+        "uspider.yuanshen.com",
+        "overseauspider.yuanshen.com",
+    ]
+    .into_iter()
+    .map(|host| TelemetryEndpoint {
+        host: Some(HostMatch::Exact(host.to_string())),
+        path_prefix: None,
+    });
+
+    let paths = [
+        "/crash/dataUpload",
+        "/sdk/dataUpload",
+        "/apm/dataUpload",
+        "/common/h5log/log/batch",
+    ]
+    .into_iter()
+    .map(|path| TelemetryEndpoint {
+        host: None,
+        path_prefix: Some(path.to_string()),
+    });
+
+    hosts.chain(paths).collect()
+}
+
+struct CompiledEndpoint {
+    host: Option<HostMatcher>,
+    path_prefix: Option<String>,
+}
+
+pub(super) struct Blocklist {
+    endpoints: Vec<CompiledEndpoint>,
+    response: CannedResponse,
+}
+
+impl Blocklist {
+    pub(super) fn compile(config: &TelemetryConfig) -> Self {
+        let builtin = if config.builtin {
+            builtin_endpoints()
+        } else {
+            Vec::new()
+        };
+
+        let endpoints = builtin
+            .iter()
+            .chain(&config.block)
+            .filter_map(|endpoint| {
+                if endpoint.host.is_none() && endpoint.path_prefix.is_none() {
+                    tracing::error!("Skipping telemetry endpoint without host or path_prefix");
+                    return None;
+                }
+                let host = match endpoint.host.as_ref().map(HostMatcher::new).transpose() {
+                    Ok(host) => host,
+                    Err(e) => {
+                        tracing::error!(
+                            "Skipping telemetry endpoint with invalid host regex: {}",
+                            e
+                        );
+                        return None;
+                    }
+                };
+                Some(CompiledEndpoint {
+                    host,
+                    path_prefix: endpoint.path_prefix.clone(),
+                })
+            })
+            .collect();
+
+        Self {
+            endpoints,
+            response: config.response.clone(),
+        }
+    }
+
+    fn matches(&self, host: &str, path: &str) -> bool {
+        self.endpoints.iter().any(|endpoint| {
+            endpoint.host.as_ref().is_none_or(|h| h.matches(host))
+                && endpoint
+                    .path_prefix
+                    .as_ref()
+                    .is_none_or(|prefix| path.starts_with(prefix.as_str()))
+        })
+    }
+
+    /// Whether any endpoint is on `host`, so its HTTPS traffic has to be decrypted.
+    fn has_host(&self, host: &str) -> bool {
+        self.endpoints
+            .iter()
+            .any(|endpoint| endpoint.host.as_ref().is_some_and(|h| h.matches(host)))
+    }
+}
+
+static BLOCKLIST: Lazy<RwLock<Blocklist>> =
+    Lazy::new(|| RwLock::new(Blocklist::compile(&TelemetryConfig::default())));
+
+/// Blocked requests per host in this session.
+static HITS: Lazy<Mutex<BTreeMap<String, u64>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+pub fn set_telemetry(config: &TelemetryConfig) {
+    replace_telemetry(Blocklist::compile(config));
+}
+
+pub(super) fn replace_telemetry(blocklist: Blocklist) {
+    tracing::info!("Blocking {} telemetry endpoints", blocklist.endpoints.len());
+    *BLOCKLIST.write().unwrap() = blocklist;
+}
+
+pub(super) fn blocks_host(host: &str) -> bool {
+    BLOCKLIST.read().unwrap().has_host(&host.to_lowercase())
+}
+
+/// The canned response for a telemetry request, or `None` if it isn't one.
+pub(super) fn blocked_response(host: &str, path: &str) -> Option<Response<Body>> {
+    let host = host.to_lowercase();
+    let blocklist = BLOCKLIST.read().unwrap();
+    if !blocklist.matches(&host, path) {
+        return None;
+    }
+
+    tracing::debug!("[TELEMETRY] Blocked {}{}", host, path);
+    *HITS.lock().unwrap().entry(host).or_default() += 1;
+
+    Some(canned_response(&blocklist.response))
+}
+
+fn canned_response(canned: &CannedResponse) -> Response<Body> {
+    let status = StatusCode::from_u16(canned.status).unwrap_or(StatusCode::OK);
+    // 204 and 304 responses can't have a body.
+    if status == StatusCode::NO_CONTENT || status == StatusCode::NOT_MODIFIED {
+        return Response::builder()
+            .status(status)
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    let mut builder = Response::builder().status(status);
+    if let Some(content_type) = &canned.content_type {
+        builder = builder.header(header::CONTENT_TYPE, content_type.as_str());
+    }
+    builder
+        .body(Body::from(canned.body.clone()))
+        .unwrap_or_else(|e| {
+            tracing::error!("[TELEMETRY] Invalid canned response: {}", e);
+            Response::builder()
+                .status(status)
+                .body(Body::empty())
+                .unwrap()
+        })
+}
+
+/// Blocked requests per host so far.
+pub fn telemetry_hits() -> BTreeMap<String, u64> {
+    HITS.lock().unwrap().clone()
+}
+
+/// Logs how many telemetry requests were blocked this session.
+pub fn log_telemetry_summary() {
+    let hits = telemetry_hits();
+    if hits.is_empty() {
+        return;
+    }
+    let total: u64 = hits.values().sum();
+    tracing::info!(
+        "[TELEMETRY] Blocked {} telemetry requests this session:",
+        total
+    );
+    for (host, count) in hits {
+        tracing::info!("[TELEMETRY]   {:>6}  {}", count, host);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn body(res: Response<Body>) -> Vec<u8> {
+        hudsucker::hyper::body::to_bytes(res.into_body())
+            .await
+            .unwrap()
+            .to_vec()
+    }
+
+    #[test]
+    fn matches_endpoints() {
+        let blocklist = Blocklist::compile(&TelemetryConfig {
+            builtin: false,
+            block: vec![
+                TelemetryEndpoint {
+                    host: Some(HostMatch::Suffix("stats.example.com".to_string())),
+                    path_prefix: None,
+                },
+                TelemetryEndpoint {
+                    host: Some(HostMatch::Exact("api.example.com".to_string())),
+                    path_prefix: Some("/log/".to_string()),
+                },
+                TelemetryEndpoint {
+                    host: None,
+                    path_prefix: Some("/crash/".to_string()),
+                },
+                // Skipped: matches everything.
+                TelemetryEndpoint {
+                    host: None,
+                    path_prefix: None,
+                },
+            ],
+            response: CannedResponse::default(),
+        });
+
+        assert_eq!(blocklist.endpoints.len(), 3);
+        assert!(blocklist.matches("eu.stats.example.com", "/anything"));
+        assert!(blocklist.matches("api.example.com", "/log/upload"));
+        assert!(!blocklist.matches("api.example.com", "/login"));
+        assert!(blocklist.matches("other.example.com", "/crash/report"));
+        assert!(!blocklist.matches("other.example.com", "/"));
+
+        assert!(blocklist.has_host("stats.example.com"));
+        assert!(blocklist.has_host("api.example.com"));
+        assert!(!blocklist.has_host("other.example.com"));
+    }
+
+    #[test]
+    fn builtin_endpoints_can_be_turned_off() {
+        let builtin = Blocklist::compile(&TelemetryConfig::default());
+        assert!(builtin.matches("log-upload-os.hoyoverse.com", "/"));
+        assert!(builtin.matches("any.example.com", "/sdk/dataUpload"));
+
+        let off = Blocklist::compile(&TelemetryConfig {
+            builtin: false,
+            ..TelemetryConfig::default()
+        });
+        assert!(!off.matches("log-upload-os.hoyoverse.com", "/"));
+    }
+
+    #[tokio::test]
+    async fn canned_responses() {
+        let res = canned_response(&CannedResponse::default());
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(body(res).await, b"{}");
+
+        for status in [204, 304] {
+            let res = canned_response(&CannedResponse {
+                status,
+                content_type: Some("text/plain".to_string()),
+                body: "ignored".to_string(),
+            });
+            assert_eq!(res.status().as_u16(), status);
+            assert!(!res.headers().contains_key(header::CONTENT_TYPE));
+            assert!(body(res).await.is_empty());
+        }
+
+        let res = canned_response(&CannedResponse {
+            status: 1000,
+            content_type: None,
+            body: "ok".to_string(),
+        });
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(!res.headers().contains_key(header::CONTENT_TYPE));
+        assert_eq!(body(res).await, b"ok");
+    }
+
+    #[test]
+    fn counts_hits_per_host() {
+        assert!(blocked_response("Hits.Example.com", "/apm/dataUpload?x=1").is_some());
+        assert!(blocked_response("hits.example.com", "/crash/dataUpload").is_some());
+        assert!(blocked_response("hits.example.com", "/index.html").is_none());
+        assert!(blocked_response("other-hits.example.com", "/sdk/dataUpload").is_some());
+
+        let hits = telemetry_hits();
+        assert_eq!(hits.get("hits.example.com"), Some(&2));
+        assert_eq!(hits.get("other-hits.example.com"), Some(&1));
+    }
+}
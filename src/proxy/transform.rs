@@ -0,0 +1,463 @@
+use flate2::{
+    Compression,
+    read::{DeflateDecoder, GzDecoder, ZlibDecoder},
+    write::{GzEncoder, ZlibEncoder},
+};
+use hudsucker::hyper::{
+    Body, HeaderMap, Response, StatusCode,
+    header::{self, HeaderName, HeaderValue},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::{Read, Write};
+
+/// A change made to responses of requests matched by a rule.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseTransform {
+    /// Sets a value in a JSON body. `path` is dot-separated, e.g. `data.region_list.0.dispatch_url`.
+    JsonSet {
+        path: String,
+        value: Value,
+    },
+    /// Removes a value from a JSON body.
+    JsonDelete {
+        path: String,
+    },
+    /// Replaces every occurrence of `from` with `to` in a text body.
+    Replace {
+        from: String,
+        to: String,
+    },
+    /// Adds a header, replacing any existing value.
+    HeaderAdd {
+        name: String,
+        value: String,
+    },
+    HeaderRemove {
+        name: String,
+    },
+    /// Overrides the status code.
+    Status {
+        code: u16,
+    },
+}
+
+impl ResponseTransform {
+    fn touches_body(&self) -> bool {
+        matches!(
+            self,
+            ResponseTransform::JsonSet { .. }
+                | ResponseTransform::JsonDelete { .. }
+                | ResponseTransform::Replace { .. }
+        )
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Identity,
+    Gzip,
+    Deflate,
+}
+
+fn decode(body: &[u8], encoding: Encoding) -> std::io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    match encoding {
+        Encoding::Identity => out.extend_from_slice(body),
+        Encoding::Gzip => {
+            GzDecoder::new(body).read_to_end(&mut out)?;
+        }
+        // "deflate" is supposed to be zlib-wrapped, but some servers send raw deflate.
+        Encoding::Deflate => {
+            if ZlibDecoder::new(body).read_to_end(&mut out).is_err() {
+                out.clear();
+                DeflateDecoder::new(body).read_to_end(&mut out)?;
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Reads `Content-Encoding`. Returns `None` (and logs) for encodings we can't handle.
+fn content_encoding(headers: &HeaderMap) -> Option<Encoding> {
+    match headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_lowercase())
+        .as_deref()
+    {
+        None | Some("identity") => Some(Encoding::Identity),
+        Some("gzip") | Some("x-gzip") => Some(Encoding::Gzip),
+        Some("deflate") => Some(Encoding::Deflate),
+        Some(other) => {
+            tracing::warn!("[TRANSFORM] Unsupported content encoding {}", other);
+            None
+        }
+    }
+}
+
+/// Decodes a body according to its `Content-Encoding` header.
+pub(super) fn decode_content(body: &[u8], headers: &HeaderMap) -> Option<Vec<u8>> {
+    decode(body, content_encoding(headers)?).ok()
+}
+
+fn encode(body: &[u8], encoding: Encoding) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Identity => Ok(body.to_vec()),
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        Encoding::Deflate => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+    }
+}
+
+fn json_set(root: &mut Value, path: &str, value: Value) {
+    let mut current = root;
+    let mut segments = path.split('.').peekable();
+    while let Some(segment) = segments.next() {
+        let last = segments.peek().is_none();
+        current = match current {
+            Value::Array(items) => match segment.parse::<usize>() {
+                Ok(i) if i < items.len() => &mut items[i],
+                _ => {
+                    tracing::warn!("[TRANSFORM] No array element {} in {}", segment, path);
+                    return;
+                }
+            },
+            Value::Object(map) => map
+                .entry(segment)
+                .or_insert_with(|| Value::Object(Default::default())),
+            _ => {
+                tracing::warn!("[TRANSFORM] Cannot set {}: not an object or array", path);
+                return;
+            }
+        };
+        if last {
+            *current = value;
+            return;
+        }
+    }
+}
+
+fn json_delete(root: &mut Value, path: &str) {
+    let (parent_path, key) = match path.rsplit_once('.') {
+        Some((parent, key)) => (Some(parent), key),
+        None => (None, path),
+    };
+
+    let mut parent = Some(root);
+    for segment in parent_path.into_iter().flat_map(|p| p.split('.')) {
+        parent = parent.and_then(|value| match value {
+            Value::Object(map) => map.get_mut(segment),
+            Value::Array(items) => segment.parse::<usize>().ok().and_then(|i| items.get_mut(i)),
+            _ => None,
+        });
+    }
+
+    match parent {
+        Some(Value::Object(map)) => {
+            map.remove(key);
+        }
+        Some(Value::Array(items)) => {
+            if let Ok(i) = key.parse::<usize>()
+                && i < items.len()
+            {
+                items.remove(i);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Applies the body transforms to a decoded body.
+/// Returns `None` if the body can't be transformed (e.g. it isn't JSON for a JSON transform).
+fn transform_body(body: Vec<u8>, transforms: &[ResponseTransform]) -> Option<Vec<u8>> {
+    let mut body = body;
+    for transform in transforms {
+        match transform {
+            ResponseTransform::JsonSet { .. } | ResponseTransform::JsonDelete { .. } => {
+                let mut json: Value = match serde_json::from_slice(&body) {
+                    Ok(json) => json,
+                    Err(e) => {
+                        tracing::warn!("[TRANSFORM] Response body is not JSON: {}", e);
+                        return None;
+                    }
+                };
+                match transform {
+                    ResponseTransform::JsonSet { path, value } => {
+                        json_set(&mut json, path, value.clone())
+                    }
+                    ResponseTransform::JsonDelete { path } => json_delete(&mut json, path),
+                    _ => unreachable!(),
+                }
+                body = serde_json::to_vec(&json).ok()?;
+            }
+            ResponseTransform::Replace { from, to } => {
+                let text = match String::from_utf8(body) {
+                    Ok(text) => text,
+                    Err(_) => {
+                        tracing::warn!("[TRANSFORM] Response body is not text");
+                        return None;
+                    }
+                };
+                body = text.replace(from.as_str(), to).into_bytes();
+            }
+            _ => {}
+        }
+    }
+    Some(body)
+}
+
+/// Runs the transforms over a response. Compressed bodies are decoded first
+/// and re-encoded afterwards.
+pub async fn apply(transforms: &[ResponseTransform], res: Response<Body>) -> Response<Body> {
+    let (mut parts, body) = res.into_parts();
+    // Read before the header transforms, which may change or remove it.
+    let encoding = content_encoding(&parts.headers);
+
+    for transform in transforms {
+        match transform {
+            ResponseTransform::HeaderAdd { name, value } => {
+                match (
+                    HeaderName::try_from(name.as_str()),
+                    HeaderValue::try_from(value.as_str()),
+                ) {
+                    (Ok(name), Ok(value)) => {
+                        parts.headers.insert(name, value);
+                    }
+                    _ => tracing::warn!("[TRANSFORM] Invalid header {}: {}", name, value),
+                }
+            }
+            ResponseTransform::HeaderRemove { name } => {
+                parts.headers.remove(name.as_str());
+            }
+            ResponseTransform::Status { code } => match StatusCode::from_u16(*code) {
+                Ok(status) => parts.status = status,
+                Err(_) => tracing::warn!("[TRANSFORM] Invalid status code {}", code),
+            },
+            _ => {}
+        }
+    }
+
+    let Some(encoding) = encoding else {
+        return Response::from_parts(parts, body);
+    };
+    // The body is sent in the encoding the headers name after the transforms.
+    let target = content_encoding(&parts.headers).unwrap_or(encoding);
+    if target == encoding && !transforms.iter().any(ResponseTransform::touches_body) {
+        return Response::from_parts(parts, body);
+    }
+
+    let raw = match hudsucker::hyper::body::to_bytes(body).await {
+        Ok(raw) => raw,
+        Err(e) => {
+            tracing::error!("[TRANSFORM] Failed to read response body: {}", e);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+
+    let transformed = decode(&raw, encoding)
+        .map_err(|e| tracing::warn!("[TRANSFORM] Failed to decode response body: {}", e))
+        .ok()
+        .map(|decoded| transform_body(decoded.clone(), transforms).unwrap_or(decoded))
+        .and_then(|body| {
+            encode(&body, target)
+                .map_err(|e| tracing::warn!("[TRANSFORM] Failed to encode response body: {}", e))
+                .ok()
+        });
+
+    let body = match transformed {
+        Some(body) => body,
+        None => raw.to_vec(),
+    };
+    parts
+        .headers
+        .insert(header::CONTENT_LENGTH, body.len().into());
+    parts.headers.remove(header::TRANSFER_ENCODING);
+
+    Response::from_parts(parts, Body::from(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::DeflateEncoder;
+    use serde_json::json;
+
+    fn response(encoding: Option<&str>, body: Vec<u8>) -> Response<Body> {
+        let mut res = Response::builder()
+            .header(header::CONTENT_LENGTH, body.len())
+            .header(header::CONTENT_TYPE, "application/json");
+        if let Some(encoding) = encoding {
+            res = res.header(header::CONTENT_ENCODING, encoding);
+        }
+        res.body(Body::from(body)).unwrap()
+    }
+
+    async fn run(
+        transforms: &[ResponseTransform],
+        res: Response<Body>,
+    ) -> (StatusCode, HeaderMap, Vec<u8>) {
+        let (parts, body) = apply(transforms, res).await.into_parts();
+        let body = hudsucker::hyper::body::to_bytes(body)
+            .await
+            .unwrap()
+            .to_vec();
+        (parts.status, parts.headers, body)
+    }
+
+    fn content_length(headers: &HeaderMap) -> usize {
+        headers[header::CONTENT_LENGTH]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    fn set(path: &str, value: Value) -> ResponseTransform {
+        ResponseTransform::JsonSet {
+            path: path.to_string(),
+            value,
+        }
+    }
+
+    #[tokio::test]
+    async fn gzip_round_trip() {
+        let body = encode(
+            br#"{"data":{"url":"https://old.example.com"}}"#,
+            Encoding::Gzip,
+        )
+        .unwrap();
+        let (_, headers, body) = run(
+            &[set("data.url", json!("http://127.0.0.1:21000"))],
+            response(Some("gzip"), body),
+        )
+        .await;
+
+        assert_eq!(headers[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(content_length(&headers), body.len());
+        let json: Value = serde_json::from_slice(&decode(&body, Encoding::Gzip).unwrap()).unwrap();
+        assert_eq!(json, json!({"data": {"url": "http://127.0.0.1:21000"}}));
+    }
+
+    #[tokio::test]
+    async fn deflate_round_trip() {
+        let transforms = [ResponseTransform::Replace {
+            from: "old".to_string(),
+            to: "new-and-longer".to_string(),
+        }];
+
+        let zlib = encode(br#"{"host":"old"}"#, Encoding::Deflate).unwrap();
+        let mut raw = DeflateEncoder::new(Vec::new(), Compression::default());
+        raw.write_all(br#"{"host":"old"}"#).unwrap();
+        let raw = raw.finish().unwrap();
+
+        for body in [zlib, raw] {
+            let (_, headers, body) = run(&transforms, response(Some("deflate"), body)).await;
+            assert_eq!(content_length(&headers), body.len());
+            assert_eq!(
+                decode(&body, Encoding::Deflate).unwrap(),
+                br#"{"host":"new-and-longer"}"#
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn encoding_is_read_before_header_transforms() {
+        let body = encode(br#"{"a":1}"#, Encoding::Gzip).unwrap();
+        let (_, headers, body) = run(
+            &[
+                ResponseTransform::HeaderRemove {
+                    name: "content-encoding".to_string(),
+                },
+                set("a", json!(2)),
+            ],
+            response(Some("gzip"), body),
+        )
+        .await;
+
+        assert!(!headers.contains_key(header::CONTENT_ENCODING));
+        assert_eq!(body, br#"{"a":2}"#);
+        assert_eq!(content_length(&headers), body.len());
+    }
+
+    #[tokio::test]
+    async fn header_and_status_transforms() {
+        let (status, headers, body) = run(
+            &[
+                ResponseTransform::HeaderAdd {
+                    name: "content-type".to_string(),
+                    value: "text/plain".to_string(),
+                },
+                ResponseTransform::HeaderAdd {
+                    name: "x-bad header".to_string(),
+                    value: "ignored".to_string(),
+                },
+                ResponseTransform::HeaderRemove {
+                    name: "x-remove".to_string(),
+                },
+                ResponseTransform::Status { code: 404 },
+                ResponseTransform::Status { code: 1000 },
+            ],
+            Response::builder()
+                .header("x-remove", "1")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from("unchanged"))
+                .unwrap(),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(headers[header::CONTENT_TYPE], "text/plain");
+        assert!(!headers.contains_key("x-remove"));
+        assert!(!headers.contains_key("x-bad header"));
+        assert_eq!(body, b"unchanged");
+    }
+
+    #[tokio::test]
+    async fn untransformable_body_is_kept() {
+        let (_, headers, body) =
+            run(&[set("a", json!(1))], response(None, b"not json".to_vec())).await;
+        assert_eq!(body, b"not json");
+        assert_eq!(content_length(&headers), body.len());
+    }
+
+    #[test]
+    fn json_set_paths() {
+        let mut json = json!({"data": {"list": [{"url": "a"}, {"url": "b"}]}});
+        json_set(&mut json, "data.list.1.url", json!("c"));
+        json_set(&mut json, "data.new.nested", json!(true));
+        json_set(&mut json, "data.list.5.url", json!("ignored"));
+        json_set(&mut json, "data.list.0.url.deeper", json!("ignored"));
+
+        assert_eq!(
+            json,
+            json!({"data": {
+                "list": [{"url": "a"}, {"url": "c"}],
+                "new": {"nested": true},
+            }})
+        );
+    }
+
+    #[test]
+    fn json_delete_paths() {
+        let mut json = json!({"data": {"list": [1, 2, 3], "keep": 1, "drop": 2}});
+        json_delete(&mut json, "data.drop");
+        json_delete(&mut json, "data.list.1");
+        json_delete(&mut json, "data.list.9");
+        json_delete(&mut json, "data.missing.key");
+        json_delete(&mut json, "missing");
+
+        assert_eq!(json, json!({"data": {"list": [1, 3], "keep": 1}}));
+
+        json_delete(&mut json, "data");
+        assert_eq!(json, json!({}));
+    }
+}
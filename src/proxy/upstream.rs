@@ -0,0 +1,478 @@
+/*
+ * Connections from the proxy to upstream servers.
+ *
+ * Private servers often use a self-signed or internal-CA certificate, so each
+ * server can have its own TLS trust settings. Servers without settings are
+ * checked against the public web roots, like before.
+ */
+
+use hudsucker::hyper::{Client, Uri, client::HttpConnector, http::uri::Scheme, service::Service};
+use hyper_rustls::MaybeHttpsStream;
+use once_cell::sync::Lazy;
+use rustls::{
+    Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName,
+    client::{ServerCertVerified, ServerCertVerifier},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    error::Error,
+    future::Future,
+    path::PathBuf,
+    pin::Pin,
+    sync::{Arc, RwLock},
+    task::{Context, Poll},
+    time::SystemTime,
+};
+use tokio::net::TcpStream;
+use tokio_rustls::{TlsConnector, client::TlsStream};
+
+/// TLS settings for connections to one upstream server.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UpstreamTls {
+    /// PEM file with extra CA certificates to trust, in addition to the public roots.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_bundle: Option<PathBuf>,
+    /// SHA-256 fingerprint of the server's certificate, in hex (colons allowed).
+    /// When set, only this certificate is accepted and the CA chain isn't checked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pinned_sha256: Option<String>,
+    /// Name sent as SNI and checked against the certificate, instead of the server address.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_name: Option<String>,
+    /// Accept any certificate. Only for testing.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub insecure: bool,
+}
+
+struct UpstreamTarget {
+    config: Arc<ClientConfig>,
+    server_name: Option<ServerName>,
+    insecure: bool,
+}
+
+/// TLS settings by upstream authority (`host:port`).
+static TARGETS: Lazy<RwLock<HashMap<String, Arc<UpstreamTarget>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+fn web_roots() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    roots
+}
+
+fn client_config(roots: RootCertStore) -> ClientConfig {
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    config
+}
+
+/// Accepts only a certificate with the given SHA-256 fingerprint.
+struct PinnedVerifier {
+    fingerprint: [u8; 32],
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = openssl::sha::sha256(&end_entity.0);
+        if openssl::memcmp::eq(&fingerprint, &self.fingerprint) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            tracing::error!(
+                "[UPSTREAM] Certificate of {:?} does not match the pinned fingerprint (got {})",
+                server_name,
+                hex::encode(fingerprint)
+            );
+            Err(rustls::Error::InvalidCertificateData(
+                "certificate does not match the pinned fingerprint".to_string(),
+            ))
+        }
+    }
+}
+
+/// Accepts any certificate.
+struct InsecureVerifier;
+
+impl ServerCertVerifier for InsecureVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        tracing::warn!(
+            "[UPSTREAM] NOT verifying the certificate of {:?} (insecure mode)",
+            server_name
+        );
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+fn parse_fingerprint(fingerprint: &str) -> Result<[u8; 32], Box<dyn Error>> {
+    let hex_digits: String = fingerprint
+        .chars()
+        .filter(|c| *c != ':' && !c.is_whitespace())
+        .collect();
+    let bytes = hex::decode(&hex_digits)
+        .map_err(|e| format!("Invalid certificate fingerprint {}: {}", fingerprint, e))?;
+    bytes.try_into().map_err(|_| {
+        format!(
+            "Invalid certificate fingerprint {}: expected 32 bytes (SHA-256)",
+            fingerprint
+        )
+        .into()
+    })
+}
+
+impl UpstreamTarget {
+    fn new(tls: &UpstreamTls) -> Result<Self, Box<dyn Error>> {
+        let mut roots = web_roots();
+        if let Some(path) = &tls.ca_bundle {
+            let pem = std::fs::read(path)
+                .map_err(|e| format!("Could not read CA bundle {}: {}", path.display(), e))?;
+            let certs = rustls_pemfile::certs(&mut pem.as_slice())?;
+            if certs.is_empty() {
+                return Err(format!("No certificates found in {}", path.display()).into());
+            }
+            for cert in certs {
+                roots.add(&Certificate(cert))?;
+            }
+        }
+
+        let mut config = client_config(roots);
+        if tls.insecure {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(InsecureVerifier));
+        } else if let Some(fingerprint) = &tls.pinned_sha256 {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(PinnedVerifier {
+                    fingerprint: parse_fingerprint(fingerprint)?,
+                }));
+        }
+
+        let server_name = match &tls.server_name {
+            Some(name) => Some(
+                ServerName::try_from(name.as_str())
+                    .map_err(|_| format!("Invalid server name {}", name))?,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            config: Arc::new(config),
+            server_name,
+            insecure: tls.insecure,
+        })
+    }
+}
+
+pub(super) fn authority_key(uri: &Uri) -> Option<String> {
+    let port = uri.port_u16().unwrap_or(match uri.scheme() {
+        Some(scheme) if *scheme == Scheme::HTTP => 80,
+        _ => 443,
+    });
+    Some(format!("{}:{}", uri.host()?.to_lowercase(), port))
+}
+
+/// Uses `tls` for connections to `server` (`scheme://host:port`).
+pub fn set_upstream_tls(server: &str, tls: &UpstreamTls) -> Result<(), Box<dyn Error>> {
+    let uri: Uri = server.parse()?;
+    let key = authority_key(&uri).ok_or_else(|| format!("Invalid server address {}", server))?;
+
+    if *tls == UpstreamTls::default() {
+        TARGETS.write().unwrap().remove(&key);
+        return Ok(());
+    }
+
+    let target = UpstreamTarget::new(tls)?;
+    if target.insecure {
+        tracing::warn!("[UPSTREAM] ==================================================");
+        tracing::warn!("[UPSTREAM] TLS certificate checks are DISABLED for {}", key);
+        tracing::warn!("[UPSTREAM] Anyone on the network can intercept this traffic");
+        tracing::warn!("[UPSTREAM] ==================================================");
+    } else {
+        tracing::info!("[UPSTREAM] Using custom TLS settings for {}", key);
+    }
+    TARGETS.write().unwrap().insert(key, Arc::new(target));
+    Ok(())
+}
+
+/// Verifies servers without custom settings against the public web roots.
+static DEFAULT_CONFIG: Lazy<Arc<ClientConfig>> = Lazy::new(|| Arc::new(client_config(web_roots())));
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Runs the TLS handshake over `tcp` with the settings for `uri`'s server.
+pub async fn tls_connect(tcp: TcpStream, uri: &Uri) -> Result<TlsStream<TcpStream>, BoxError> {
+    let target = authority_key(uri).and_then(|key| TARGETS.read().unwrap().get(&key).cloned());
+    let (config, server_name) = match target {
+        Some(target) => (target.config.clone(), target.server_name.clone()),
+        None => (DEFAULT_CONFIG.clone(), None),
+    };
+    let server_name = match server_name {
+        Some(name) => name,
+        None => {
+            let host = uri.host().unwrap_or_default();
+            // IPv6 hosts come bracketed in URIs.
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            ServerName::try_from(host).map_err(|_| format!("Invalid server name {}", host))?
+        }
+    };
+
+    Ok(TlsConnector::from(config).connect(server_name, tcp).await?)
+}
+
+/// Connector for the proxy's HTTP client that applies the per-server TLS settings.
+#[derive(Clone)]
+pub struct UpstreamConnector {
+    http: HttpConnector,
+}
+
+impl UpstreamConnector {
+    pub fn new() -> Self {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        Self { http }
+    }
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = MaybeHttpsStream<TcpStream>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let is_https = uri.scheme() == Some(&Scheme::HTTPS);
+        // Through the upstream proxy if there is one, or to the overridden
+        // address of the host. Directly otherwise.
+        let host = uri.host().unwrap_or_default();
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let direct = if super::chain::upstream_proxy().is_some()
+            || super::hosts::host_override(host).is_some()
+        {
+            None
+        } else {
+            Some(self.http.call(uri.clone()))
+        };
+
+        Box::pin(async move {
+            let tcp = match direct {
+                Some(connecting) => connecting.await?,
+                None => {
+                    let host = uri.host().ok_or("Missing host")?;
+                    let host = host.trim_start_matches('[').trim_end_matches(']');
+                    let port = uri.port_u16().unwrap_or(if is_https { 443 } else { 80 });
+                    super::chain::dial(host, port).await?
+                }
+            };
+            if !is_https {
+                return Ok(MaybeHttpsStream::Http(tcp));
+            }
+
+            let tls = tls_connect(tcp, &uri).await?;
+            Ok(MaybeHttpsStream::Https(tls))
+        })
+    }
+}
+
+/// Client for requests to upstream servers, shared by hudsucker and the
+/// requests the handler sends itself.
+static CLIENT: Lazy<Client<UpstreamConnector>> = Lazy::new(|| {
+    Client::builder()
+        .http1_title_case_headers(true)
+        .http1_preserve_header_case(true)
+        .build(UpstreamConnector::new())
+});
+
+pub(super) fn client() -> Client<UpstreamConnector> {
+    CLIENT.clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{PrivateKey, ServerConfig};
+    use std::io::Write;
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    /// A certificate and its key, in DER.
+    struct TestCert {
+        der: Vec<u8>,
+        key: Vec<u8>,
+    }
+
+    /// A self-signed certificate for `names`.
+    fn certificate(names: &[&str]) -> TestCert {
+        let cert = rcgen::generate_simple_self_signed(
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+        // Every serialization is signed again, so it's done once.
+        TestCert {
+            der: cert.serialize_der().unwrap(),
+            key: cert.serialize_private_key_der(),
+        }
+    }
+
+    /// Accepts one TLS connection with `cert`. Resolves to the SNI the client
+    /// sent, or `None` if the handshake failed.
+    async fn serve(cert: &TestCert) -> (u16, tokio::task::JoinHandle<Option<String>>) {
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![Certificate(cert.der.clone())],
+                PrivateKey(cert.key.clone()),
+            )
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let tls = TlsAcceptor::from(Arc::new(config)).accept(tcp).await.ok()?;
+            Some(
+                tls.get_ref()
+                    .1
+                    .sni_hostname()
+                    .unwrap_or_default()
+                    .to_string(),
+            )
+        });
+        (port, server)
+    }
+
+    /// Connects to the stand-in server on `port` as `https://localhost:<port>`,
+    /// with `tls` as the settings for it.
+    async fn connect(port: u16, tls: &UpstreamTls) -> Result<(), BoxError> {
+        let server = format!("https://localhost:{}", port);
+        set_upstream_tls(&server, tls).unwrap();
+        let tcp = TcpStream::connect(("127.0.0.1", port)).await?;
+        tls_connect(tcp, &server.parse().unwrap()).await.map(|_| ())
+    }
+
+    fn ca_bundle(cert: &TestCert) -> tempfile::NamedTempFile {
+        let pem = openssl::x509::X509::from_der(&cert.der)
+            .unwrap()
+            .to_pem()
+            .unwrap();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&pem).unwrap();
+        file
+    }
+
+    #[test]
+    fn parses_fingerprints() {
+        let hex = "ab".repeat(32);
+        let with_colons = vec!["AB"; 32].join(":");
+        let spaced = format!(" {} \n{}", &hex[..32], &hex[32..]);
+        for fingerprint in [&hex, &with_colons, &spaced] {
+            assert_eq!(parse_fingerprint(fingerprint).unwrap(), [0xab; 32]);
+        }
+        assert!(parse_fingerprint(&"ab".repeat(20)).is_err());
+        assert!(parse_fingerprint(&"ab".repeat(33)).is_err());
+        assert!(parse_fingerprint(&"zz".repeat(32)).is_err());
+        assert!(parse_fingerprint("abc").is_err());
+    }
+
+    #[tokio::test]
+    async fn default_rejects_self_signed() {
+        let (port, server) = serve(&certificate(&["localhost"])).await;
+        assert!(connect(port, &UpstreamTls::default()).await.is_err());
+        assert_eq!(server.await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn ca_bundle_accepts_its_certificate() {
+        let cert = certificate(&["localhost"]);
+        let bundle = ca_bundle(&cert);
+        let (port, server) = serve(&cert).await;
+        let tls = UpstreamTls {
+            ca_bundle: Some(bundle.path().to_path_buf()),
+            ..Default::default()
+        };
+        connect(port, &tls).await.unwrap();
+        assert_eq!(server.await.unwrap().as_deref(), Some("localhost"));
+    }
+
+    #[tokio::test]
+    async fn ca_bundle_checks_the_name() {
+        let cert = certificate(&["game.internal"]);
+        let bundle = ca_bundle(&cert);
+        let (port, _) = serve(&cert).await;
+        let tls = UpstreamTls {
+            ca_bundle: Some(bundle.path().to_path_buf()),
+            ..Default::default()
+        };
+        assert!(connect(port, &tls).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn server_name_overrides_sni() {
+        let cert = certificate(&["game.internal"]);
+        let bundle = ca_bundle(&cert);
+        let (port, server) = serve(&cert).await;
+        let tls = UpstreamTls {
+            ca_bundle: Some(bundle.path().to_path_buf()),
+            server_name: Some("game.internal".to_string()),
+            ..Default::default()
+        };
+        connect(port, &tls).await.unwrap();
+        assert_eq!(server.await.unwrap().as_deref(), Some("game.internal"));
+    }
+
+    #[tokio::test]
+    async fn pin_accepts_matching_certificate() {
+        let cert = certificate(&["other.example"]);
+        let fingerprint = hex::encode(openssl::sha::sha256(&cert.der));
+        let (port, server) = serve(&cert).await;
+        let tls = UpstreamTls {
+            pinned_sha256: Some(fingerprint),
+            ..Default::default()
+        };
+        connect(port, &tls).await.unwrap();
+        assert!(server.await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn pin_rejects_other_certificate() {
+        let cert = certificate(&["localhost"]);
+        let (port, server) = serve(&cert).await;
+        let tls = UpstreamTls {
+            pinned_sha256: Some("00".repeat(32)),
+            ..Default::default()
+        };
+        assert!(connect(port, &tls).await.is_err());
+        assert_eq!(server.await.unwrap(), None);
+    }
+}
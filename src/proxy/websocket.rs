@@ -0,0 +1,376 @@
+/*
+ * WebSocket forwarding for redirected hosts.
+ *
+ * hudsucker relays WebSockets to their original destination with its own
+ * connection. Upgrades on hosts with a redirect rule are answered here
+ * instead: the handshake is repeated against the private server through the
+ * upstream connector (so per-server TLS settings and the upstream proxy
+ * apply), and frames are pumped between both sides. Frames can be logged and
+ * are written to the HAR recording.
+ */
+
+use hudsucker::{
+    futures::{SinkExt, StreamExt},
+    hyper::{
+        Body, Request, Response, StatusCode, Uri, header, http::uri::Scheme, service::Service,
+    },
+};
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Instant,
+};
+use tokio_tungstenite::{
+    WebSocketStream,
+    tungstenite::{
+        Message,
+        handshake::{client::generate_key, derive_accept_key},
+        protocol::Role,
+    },
+};
+
+use super::{har, upstream::UpstreamConnector};
+
+/// Longest text frame shown in the log.
+const MAX_LOGGED_TEXT: usize = 200;
+
+static LOG_FRAMES: AtomicBool = AtomicBool::new(false);
+
+/// Logs every WebSocket frame forwarded to or from the private server.
+pub fn set_websocket_logging(enabled: bool) {
+    LOG_FRAMES.store(enabled, Ordering::Relaxed);
+}
+
+pub(super) fn is_websocket(req: &Request<Body>) -> bool {
+    req.headers()
+        .get(header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Headers that belong to the connection to us, or that describe extensions
+/// tungstenite can't speak, and so aren't passed on.
+fn is_hop_header(name: &header::HeaderName) -> bool {
+    name == header::HOST
+        || name == header::SEC_WEBSOCKET_KEY
+        || name == header::SEC_WEBSOCKET_EXTENSIONS
+        || name == header::PROXY_AUTHORIZATION
+        || name.as_str() == "proxy-connection"
+}
+
+/// Connects the WebSocket in `req` to `upstream` and answers the client's handshake.
+/// The server gets the Host of `req` if `keep_host` is set (see `headers::apply`),
+/// its own address otherwise.
+pub(super) async fn forward(
+    mut req: Request<Body>,
+    upstream: Uri,
+    original_url: &str,
+    keep_host: bool,
+) -> Response<Body> {
+    let Some(key) = req.headers().get(header::SEC_WEBSOCKET_KEY).cloned() else {
+        return status(StatusCode::BAD_REQUEST);
+    };
+    let on_upgrade = hudsucker::hyper::upgrade::on(&mut req);
+    let start = Instant::now();
+
+    let ws_scheme = if upstream.scheme() == Some(&Scheme::HTTPS) {
+        "wss"
+    } else {
+        "ws"
+    };
+    let authority = upstream
+        .authority()
+        .map(|a| a.to_string())
+        .unwrap_or_default();
+    let path_and_query = upstream
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or("/");
+    let ws_uri = format!("{}://{}{}", ws_scheme, authority, path_and_query);
+    let host = match req.headers().get(header::HOST) {
+        Some(host) if keep_host => host.clone(),
+        _ => match header::HeaderValue::from_str(&authority) {
+            Ok(host) => host,
+            Err(_) => return status(StatusCode::BAD_GATEWAY),
+        },
+    };
+
+    // The upstream handshake gets its own key, as tungstenite checks the
+    // server's answer against it.
+    let mut builder = Request::builder()
+        .uri(ws_uri.as_str())
+        .header(header::HOST, host)
+        .header(header::SEC_WEBSOCKET_KEY, generate_key());
+    for (name, value) in req.headers() {
+        if !is_hop_header(name) {
+            builder = builder.header(name, value);
+        }
+    }
+    let upstream_req = match builder.body(()) {
+        Ok(upstream_req) => upstream_req,
+        Err(e) => {
+            tracing::error!("[WS] Invalid request for {}: {}", ws_uri, e);
+            return status(StatusCode::BAD_REQUEST);
+        }
+    };
+
+    let stream = match UpstreamConnector::new().call(upstream.clone()).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            tracing::error!("[WS] Failed to connect to {}: {}", ws_uri, e);
+            return status(StatusCode::BAD_GATEWAY);
+        }
+    };
+    let (server, handshake) = match tokio_tungstenite::client_async(upstream_req, stream).await {
+        Ok(connected) => connected,
+        Err(e) => {
+            tracing::error!("[WS] Handshake with {} failed: {}", ws_uri, e);
+            return status(StatusCode::BAD_GATEWAY);
+        }
+    };
+    let wait = start.elapsed().as_secs_f64() * 1000.0;
+    tracing::info!("[WS] Connected {} to {}", original_url, ws_uri);
+
+    let mut res = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::UPGRADE, "websocket")
+        .header(header::CONNECTION, "Upgrade")
+        .header(
+            header::SEC_WEBSOCKET_ACCEPT,
+            derive_accept_key(key.as_bytes()),
+        )
+        .body(Body::empty())
+        .unwrap();
+    if let Some(protocol) = handshake.headers().get(header::SEC_WEBSOCKET_PROTOCOL) {
+        res.headers_mut()
+            .insert(header::SEC_WEBSOCKET_PROTOCOL, protocol.clone());
+    }
+
+    let entry = if har::is_recording() {
+        *req.uri_mut() = upstream;
+        Some(har::begin_entry(req, original_url).await.1)
+    } else {
+        None
+    };
+    let response_headers = res.headers().clone();
+    let url = original_url.to_string();
+
+    tokio::spawn(async move {
+        let upgraded = match on_upgrade.await {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                tracing::error!("[WS] Upgrade error: {}", e);
+                return;
+            }
+        };
+        let client = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+        let messages = relay(client, server, &url, entry.is_some()).await;
+        tracing::info!("[WS] Closed {}", url);
+        if let Some(entry) = entry {
+            entry.finish_websocket(wait, &response_headers, messages);
+        }
+    });
+
+    res
+}
+
+fn log_frame(url: &str, sent: bool, message: &Message) {
+    if !LOG_FRAMES.load(Ordering::Relaxed) {
+        return;
+    }
+    let arrow = if sent { "->" } else { "<-" };
+    match message {
+        Message::Text(text) => {
+            let mut end = text.len().min(MAX_LOGGED_TEXT);
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            let more = if end < text.len() { "..." } else { "" };
+            tracing::info!("[WS] {} {} {}{}", arrow, url, &text[..end], more);
+        }
+        Message::Binary(data) => {
+            tracing::info!("[WS] {} {} ({} bytes binary)", arrow, url, data.len())
+        }
+        Message::Close(frame) => tracing::info!("[WS] {} {} close {:?}", arrow, url, frame),
+        _ => tracing::debug!("[WS] {} {} {:?}", arrow, url, message),
+    }
+}
+
+fn har_message(sent: bool, message: &Message) -> Option<har::WebSocketMessage> {
+    match message {
+        Message::Text(text) => Some(har::WebSocketMessage::new(sent, false, text.as_bytes())),
+        Message::Binary(data) => Some(har::WebSocketMessage::new(sent, true, data)),
+        _ => None,
+    }
+}
+
+/// Pumps frames both ways until either side closes, and returns the data
+/// frames if `record` is set.
+async fn relay<C, S>(
+    client: WebSocketStream<C>,
+    server: WebSocketStream<S>,
+    url: &str,
+    record: bool,
+) -> Vec<har::WebSocketMessage>
+where
+    C: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let (mut client_tx, mut client_rx) = client.split();
+    let (mut server_tx, mut server_rx) = server.split();
+    let mut messages = Vec::new();
+
+    loop {
+        let (sent, message) = tokio::select! {
+            message = client_rx.next() => (true, message),
+            message = server_rx.next() => (false, message),
+        };
+        let message = match message {
+            Some(Ok(message)) => message,
+            Some(Err(e)) => {
+                tracing::debug!("[WS] {} error: {}", url, e);
+                break;
+            }
+            None => break,
+        };
+
+        log_frame(url, sent, &message);
+        if record && let Some(recorded) = har_message(sent, &message) {
+            messages.push(recorded);
+        }
+        let forwarded = if sent {
+            server_tx.send(message).await
+        } else {
+            client_tx.send(message).await
+        };
+        if let Err(e) = forwarded {
+            tracing::debug!("[WS] {} error: {}", url, e);
+            break;
+        }
+    }
+
+    let _ = client_tx.close().await;
+    let _ = server_tx.close().await;
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::headers::{self, HeaderSource, HostHeader, UpstreamHeaders};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// A WebSocket server for one handshake. Returns the Host and the
+    /// `x-secret` header it got.
+    async fn server() -> (Uri, tokio::task::JoinHandle<(String, Option<String>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("http://{}/ws", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let task = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await.unwrap());
+            }
+            let head = String::from_utf8(head).unwrap();
+            let header = |name: &str| {
+                head.lines()
+                    .filter_map(|line| line.split_once(':'))
+                    .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                    .map(|(_, value)| value.trim().to_string())
+            };
+            let accept = derive_accept_key(header("sec-websocket-key").unwrap().as_bytes());
+            let response = format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                accept
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            (header("host").unwrap_or_default(), header("x-secret"))
+        });
+        (uri, task)
+    }
+
+    fn upgrade_request(upstream: &Uri) -> Request<Body> {
+        Request::builder()
+            .uri(format!("http://game.example.com{}", upstream.path()))
+            .header(header::HOST, "game.example.com")
+            .header(header::UPGRADE, "websocket")
+            .header(header::CONNECTION, "Upgrade")
+            .header(header::SEC_WEBSOCKET_VERSION, "13")
+            .header(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    async fn forward_with(
+        headers: &UpstreamHeaders,
+    ) -> (Response<Body>, (String, Option<String>), Uri) {
+        let (upstream, task) = server().await;
+        let server = format!("http://{}", upstream.authority().unwrap());
+        headers::set_upstream_headers(&server, headers).unwrap();
+
+        let mut req = upgrade_request(&upstream);
+        let keep_host = headers::apply(&upstream, req.headers_mut());
+        let res = forward(
+            req,
+            upstream.clone(),
+            "http://game.example.com/ws",
+            keep_host,
+        )
+        .await;
+        (res, task.await.unwrap(), upstream)
+    }
+
+    #[tokio::test]
+    async fn upgrade_gets_server_address() {
+        let (res, (host, secret), upstream) = forward_with(&UpstreamHeaders::default()).await;
+        assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(
+            res.headers()[header::SEC_WEBSOCKET_ACCEPT],
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        assert_eq!(host, upstream.authority().unwrap().as_str());
+        assert_eq!(secret, None);
+    }
+
+    #[tokio::test]
+    async fn upgrade_keeps_host() {
+        let keep = UpstreamHeaders {
+            host: HostHeader::Keep,
+            set: [(
+                "x-secret".to_string(),
+                HeaderSource::Value("s3".to_string()),
+            )]
+            .into(),
+            ..UpstreamHeaders::default()
+        };
+        let (res, (host, secret), _) = forward_with(&keep).await;
+        assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(host, "game.example.com");
+        assert_eq!(secret.as_deref(), Some("s3"));
+    }
+
+    #[tokio::test]
+    async fn upgrade_gets_set_host() {
+        let set = UpstreamHeaders {
+            set: [(
+                "host".to_string(),
+                HeaderSource::Value("lobby.lan".to_string()),
+            )]
+            .into(),
+            ..UpstreamHeaders::default()
+        };
+        let (_, (host, _), _) = forward_with(&set).await;
+        assert_eq!(host, "lobby.lan");
+    }
+}
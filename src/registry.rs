@@ -0,0 +1,1096 @@
+/*
+ * Reading and editing the registry of a Wine prefix.
+ *
+ * Wine keeps the registry in text files in the prefix: `system.reg` for
+ * HKEY_LOCAL_MACHINE and `user.reg` for HKEY_CURRENT_USER. They are only read
+ * when wineserver starts and written back when it exits, so they can be edited
+ * directly while no wineserver runs for the prefix. Keys and values that aren't
+ * changed are written back exactly as they were.
+ *
+ * If wineserver is running, or the prefix hasn't been set up yet, the changes
+ * are imported with `regedit` instead.
+ */
+
+use std::{
+    error::Error,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+
+use crate::get_wine::WineRunner;
+
+/// Seconds between 1601-01-01 (FILETIME) and 1970-01-01.
+const FILETIME_UNIX_OFFSET: u64 = 11_644_473_600;
+
+/// Hex data lines are wrapped after this many characters, like Wine does.
+const HEX_LINE_WIDTH: usize = 76;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hive {
+    /// HKEY_LOCAL_MACHINE, `system.reg`
+    Machine,
+    /// HKEY_CURRENT_USER, `user.reg`
+    User,
+}
+
+impl Hive {
+    pub fn file_name(self) -> &'static str {
+        match self {
+            Hive::Machine => "system.reg",
+            Hive::User => "user.reg",
+        }
+    }
+
+    fn root_name(self) -> &'static str {
+        match self {
+            Hive::Machine => "HKEY_LOCAL_MACHINE",
+            Hive::User => "HKEY_CURRENT_USER",
+        }
+    }
+}
+
+/// Splits `HKEY_LOCAL_MACHINE\Software\...` (or HKLM, HKCU, HKCR) into the hive
+/// and the key path inside it.
+pub fn split_key(full_path: &str) -> Result<(Hive, String), Box<dyn Error>> {
+    let (root, rest) = full_path.split_once('\\').unwrap_or((full_path, ""));
+    let rest = rest.trim_matches('\\');
+    match root.to_ascii_uppercase().as_str() {
+        "HKEY_LOCAL_MACHINE" | "HKLM" => Ok((Hive::Machine, rest.to_string())),
+        "HKEY_CURRENT_USER" | "HKCU" => Ok((Hive::User, rest.to_string())),
+        "HKEY_CLASSES_ROOT" | "HKCR" => Ok((
+            Hive::Machine,
+            format!("Software\\Classes\\{}", rest)
+                .trim_end_matches('\\')
+                .to_string(),
+        )),
+        _ => Err(format!("Unsupported registry root in {}", full_path).into()),
+    }
+}
+
+/// A registry value. In the config it's written as `{"type": "dword", "data": 1}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum RegValue {
+    /// REG_SZ
+    String(String),
+    /// REG_EXPAND_SZ
+    ExpandString(String),
+    /// REG_DWORD
+    Dword(u32),
+    /// REG_BINARY
+    Binary(Vec<u8>),
+    /// Any other type, as raw data
+    Other { kind: u32, data: Vec<u8> },
+}
+
+impl RegValue {
+    /// The value as written in Wine's registry files.
+    fn to_wine(&self) -> String {
+        match self {
+            RegValue::String(s) => format!("\"{}\"", escape(s, '"')),
+            RegValue::ExpandString(s) => format!("str(2):\"{}\"", escape(s, '"')),
+            RegValue::Dword(v) => format!("dword:{:08x}", v),
+            RegValue::Binary(data) => hex_data("hex:", data),
+            RegValue::Other { kind, data } => hex_data(&format!("hex({:x}):", kind), data),
+        }
+    }
+
+    /// The value as written in a `regedit` import file.
+    fn to_regedit(&self) -> String {
+        match self {
+            RegValue::String(s) => format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"")),
+            RegValue::ExpandString(s) => hex_data("hex(2):", &utf16_z(s)),
+            RegValue::Dword(v) => format!("dword:{:08x}", v),
+            RegValue::Binary(data) => hex_data("hex:", data),
+            RegValue::Other { kind, data } => hex_data(&format!("hex({:x}):", kind), data),
+        }
+    }
+
+    /// Parses the text after `=` in a registry file.
+    fn parse(text: &str) -> Result<Self, Box<dyn Error>> {
+        if text.starts_with('"') {
+            return Ok(RegValue::String(parse_quoted(text)?.0));
+        }
+        let (prefix, data) = text
+            .split_once(':')
+            .ok_or_else(|| format!("Invalid value: {}", text))?;
+        match prefix {
+            "dword" => Ok(RegValue::Dword(u32::from_str_radix(data.trim(), 16)?)),
+            "hex" => Ok(RegValue::Binary(parse_hex(data)?)),
+            "str(2)" => Ok(RegValue::ExpandString(parse_quoted(data)?.0)),
+            _ => {
+                let kind = prefix
+                    .strip_prefix("hex(")
+                    .or_else(|| prefix.strip_prefix("str("))
+                    .and_then(|k| k.strip_suffix(')'))
+                    .ok_or_else(|| format!("Unknown value type: {}", prefix))?;
+                let kind = u32::from_str_radix(kind, 16)?;
+                let data = if prefix.starts_with("str(") {
+                    utf16_z(&parse_quoted(data)?.0)
+                } else {
+                    parse_hex(data)?
+                };
+                Ok(RegValue::Other { kind, data })
+            }
+        }
+    }
+}
+
+/// A NUL-terminated UTF-16LE string, as stored by Windows.
+fn utf16_z(s: &str) -> Vec<u8> {
+    s.encode_utf16()
+        .chain(std::iter::once(0))
+        .flat_map(|unit| unit.to_le_bytes())
+        .collect()
+}
+
+fn hex_data(prefix: &str, data: &[u8]) -> String {
+    let mut out = prefix.to_string();
+    let mut width = out.len();
+    for (i, byte) in data.iter().enumerate() {
+        out.push_str(&format!("{:02x}", byte));
+        width += 2;
+        if i + 1 < data.len() {
+            out.push(',');
+            width += 1;
+            if width >= HEX_LINE_WIDTH {
+                out.push_str("\\\n  ");
+                width = 2;
+            }
+        }
+    }
+    out
+}
+
+fn parse_hex(data: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    data.split(',')
+        .map(|byte| byte.trim_matches(|c: char| c == '\\' || c.is_whitespace()))
+        .filter(|byte| !byte.is_empty())
+        .map(|byte| Ok(u8::from_str_radix(byte, 16)?))
+        .collect()
+}
+
+/// Escapes a string the way Wine writes names and data.
+fn escape(s: &str, quote: char) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\0' => out.push_str("\\0"),
+            c if c == quote => {
+                out.push('\\');
+                out.push(c);
+            }
+            c if (c as u32) < 0x20 || (c as u32) > 0x7e => {
+                let mut units = [0u16; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    out.push_str(&format!("\\x{:04x}", unit));
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Undoes `escape`. Also reads the shorter escapes older Wine versions wrote.
+fn unescape(s: &str) -> String {
+    let mut units: Vec<u16> = Vec::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0u16; 2];
+            units.extend_from_slice(c.encode_utf16(&mut buf));
+            continue;
+        }
+        let Some(next) = chars.next() else {
+            units.push('\\' as u16);
+            break;
+        };
+        let unit = match next {
+            'n' => '\n' as u16,
+            'r' => '\r' as u16,
+            't' => '\t' as u16,
+            'a' => 0x07,
+            'b' => 0x08,
+            'e' => 0x1b,
+            'f' => 0x0c,
+            'v' => 0x0b,
+            'x' => {
+                let mut value = 0u16;
+                for _ in 0..4 {
+                    match chars.peek().and_then(|c| c.to_digit(16)) {
+                        Some(digit) => {
+                            value = value * 16 + digit as u16;
+                            chars.next();
+                        }
+                        None => break,
+                    }
+                }
+                value
+            }
+            '0'..='7' => {
+                let mut value = next.to_digit(8).unwrap() as u16;
+                for _ in 0..2 {
+                    match chars.peek().and_then(|c| c.to_digit(8)) {
+                        Some(digit) => {
+                            value = value * 8 + digit as u16;
+                            chars.next();
+                        }
+                        None => break,
+                    }
+                }
+                value
+            }
+            other => {
+                let mut buf = [0u16; 2];
+                units.extend_from_slice(other.encode_utf16(&mut buf));
+                continue;
+            }
+        };
+        units.push(unit);
+    }
+    String::from_utf16_lossy(&units)
+}
+
+/// Reads a quoted string at the start of `text`. Returns it and the rest.
+fn parse_quoted(text: &str) -> Result<(String, &str), Box<dyn Error>> {
+    let inner = text
+        .strip_prefix('"')
+        .ok_or_else(|| format!("Expected a quoted string: {}", text))?;
+    let mut escaped = false;
+    for (i, c) in inner.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return Ok((unescape(&inner[..i]), &inner[i + 1..])),
+            _ => {}
+        }
+    }
+    Err(format!("Unterminated string: {}", text).into())
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    /// `None` for the default value (`@`).
+    name: Option<String>,
+    /// Everything after `=`, continuation lines included.
+    raw: String,
+}
+
+impl Entry {
+    fn name_matches(&self, name: Option<&str>) -> bool {
+        match (&self.name, name) {
+            (None, None) => true,
+            (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+            _ => false,
+        }
+    }
+
+    fn to_line(&self) -> String {
+        match &self.name {
+            Some(name) => format!("\"{}\"={}", escape(name, '"'), self.raw),
+            None => format!("@={}", self.raw),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Key {
+    path: String,
+    /// The `[...] <time>` line as read.
+    header: String,
+    /// `#time=`, `#class=` and similar lines.
+    meta: Vec<String>,
+    values: Vec<Entry>,
+    changed: bool,
+}
+
+impl Key {
+    fn new(path: &str) -> Self {
+        Key {
+            path: path.to_string(),
+            header: String::new(),
+            meta: Vec::new(),
+            values: Vec::new(),
+            changed: true,
+        }
+    }
+
+    fn write(&self, out: &mut String, now: u64) {
+        out.push('\n');
+        if self.changed {
+            let filetime = (now + FILETIME_UNIX_OFFSET) * 10_000_000;
+            out.push_str(&format!("[{}] {}\n", escape_key(&self.path), now));
+            out.push_str(&format!("#time={:x}\n", filetime));
+            for line in self.meta.iter().filter(|l| !l.starts_with("#time=")) {
+                out.push_str(line);
+                out.push('\n');
+            }
+        } else {
+            out.push_str(&self.header);
+            out.push('\n');
+            for line in &self.meta {
+                out.push_str(line);
+                out.push('\n');
+            }
+        }
+        for entry in &self.values {
+            out.push_str(&entry.to_line());
+            out.push('\n');
+        }
+    }
+}
+
+fn escape_key(path: &str) -> String {
+    escape(path, ']').replace('[', "\\[")
+}
+
+fn same_key(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+fn is_subkey(path: &str, parent: &str) -> bool {
+    path.len() > parent.len()
+        && path.as_bytes()[parent.len()] == b'\\'
+        && same_key(&path[..parent.len()], parent)
+}
+
+/// One of the registry files of a prefix.
+#[derive(Debug, Clone)]
+pub struct RegistryFile {
+    path: PathBuf,
+    /// Lines before the first key (`WINE REGISTRY Version 2`, `#arch=...`).
+    header: Vec<String>,
+    keys: Vec<Key>,
+}
+
+impl RegistryFile {
+    pub fn open(prefix: &Path, hive: Hive) -> Result<Self, Box<dyn Error>> {
+        Self::load(&prefix.join(hive.file_name()))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        Self::parse(path, &text).map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    fn parse(path: &Path, text: &str) -> Result<Self, Box<dyn Error>> {
+        let mut file = RegistryFile {
+            path: path.to_path_buf(),
+            header: Vec::new(),
+            keys: Vec::new(),
+        };
+        if !text.starts_with("WINE REGISTRY Version 2") {
+            return Err("not a Wine registry file".into());
+        }
+
+        let mut lines = text.lines().enumerate();
+        while let Some((number, line)) = lines.next() {
+            if line.starts_with('[') {
+                let (name, _) = parse_key_name(line)
+                    .ok_or_else(|| format!("line {}: invalid key", number + 1))?;
+                let mut key = Key::new(&name);
+                key.header = line.to_string();
+                key.changed = false;
+                file.keys.push(key);
+            } else if let Some(key) = file.keys.last_mut() {
+                if line.is_empty() {
+                    continue;
+                }
+                if line.starts_with('#') {
+                    key.meta.push(line.to_string());
+                    continue;
+                }
+                let (name, rest) = if let Some(rest) = line.strip_prefix('@') {
+                    (None, rest)
+                } else {
+                    let (name, rest) =
+                        parse_quoted(line).map_err(|e| format!("line {}: {}", number + 1, e))?;
+                    (Some(name), rest)
+                };
+                let mut raw = rest
+                    .strip_prefix('=')
+                    .ok_or_else(|| format!("line {}: expected '='", number + 1))?
+                    .to_string();
+                // Hex data continues on the next line after a trailing backslash.
+                while raw.starts_with("hex") && raw.ends_with('\\') {
+                    let Some((_, next)) = lines.next() else {
+                        break;
+                    };
+                    raw.push('\n');
+                    raw.push_str(next);
+                }
+                key.values.push(Entry { name, raw });
+            } else {
+                file.header.push(line.to_string());
+            }
+        }
+        while file.header.last().is_some_and(|l| l.is_empty()) {
+            file.header.pop();
+        }
+        Ok(file)
+    }
+
+    pub fn get(&self, key: &str, name: Option<&str>) -> Option<RegValue> {
+        let key = self.keys.iter().find(|k| same_key(&k.path, key))?;
+        let entry = key.values.iter().find(|e| e.name_matches(name))?;
+        RegValue::parse(&entry.raw).ok()
+    }
+
+    /// Sets a value, creating the key if needed.
+    pub fn set(&mut self, key: &str, name: Option<&str>, value: &RegValue) {
+        let index = match self.keys.iter().position(|k| same_key(&k.path, key)) {
+            Some(index) => index,
+            None => {
+                self.keys.push(Key::new(key));
+                self.keys.len() - 1
+            }
+        };
+        let key = &mut self.keys[index];
+        let raw = value.to_wine();
+        match key.values.iter_mut().find(|e| e.name_matches(name)) {
+            Some(entry) if entry.raw == raw => return,
+            Some(entry) => entry.raw = raw,
+            None => key.values.push(Entry {
+                name: name.map(str::to_string),
+                raw,
+            }),
+        }
+        key.changed = true;
+    }
+
+    /// Returns whether the value existed.
+    pub fn delete_value(&mut self, key: &str, name: Option<&str>) -> bool {
+        let Some(key) = self.keys.iter_mut().find(|k| same_key(&k.path, key)) else {
+            return false;
+        };
+        let before = key.values.len();
+        key.values.retain(|e| !e.name_matches(name));
+        let deleted = key.values.len() != before;
+        key.changed |= deleted;
+        deleted
+    }
+
+    /// Deletes the key and its subkeys. Returns whether it existed.
+    pub fn delete_key(&mut self, key: &str) -> bool {
+        let before = self.keys.len();
+        self.keys
+            .retain(|k| !same_key(&k.path, key) && !is_subkey(&k.path, key));
+        self.keys.len() != before
+    }
+
+    fn to_text(&self) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let mut out = String::new();
+        for line in &self.header {
+            out.push_str(line);
+            out.push('\n');
+        }
+        for key in &self.keys {
+            key.write(&mut out, now);
+        }
+        out
+    }
+
+    /// Writes the file back. It is replaced in one step, so Wine never sees it
+    /// half written.
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        let mut temp = NamedTempFile::new_in(dir)?;
+        temp.write_all(self.to_text().as_bytes())?;
+        temp.as_file().sync_all()?;
+        if let Ok(metadata) = fs::metadata(&self.path) {
+            fs::set_permissions(temp.path(), metadata.permissions())?;
+        }
+        temp.persist(&self.path)
+            .map_err(|e| format!("Could not write {}: {}", self.path.display(), e))?;
+        Ok(())
+    }
+}
+
+/// Reads `[name] <time>`. Returns the unescaped name and the rest of the line.
+fn parse_key_name(line: &str) -> Option<(String, &str)> {
+    let inner = line.strip_prefix('[')?;
+    let mut escaped = false;
+    for (i, c) in inner.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            ']' => return Some((unescape(&inner[..i]), &inner[i + 1..])),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// A change to the registry. Keys are full paths like
+/// `HKEY_LOCAL_MACHINE\Software\Wine`.
+#[derive(Debug, Clone)]
+pub enum RegEdit {
+    Set {
+        key: String,
+        name: Option<String>,
+        value: RegValue,
+    },
+    DeleteValue {
+        key: String,
+        name: Option<String>,
+    },
+    DeleteKey {
+        key: String,
+    },
+}
+
+impl RegEdit {
+    fn key(&self) -> &str {
+        match self {
+            RegEdit::Set { key, .. }
+            | RegEdit::DeleteValue { key, .. }
+            | RegEdit::DeleteKey { key } => key,
+        }
+    }
+}
+
+/// A registry change from a game's config, made before the game starts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegistryTweak {
+    /// Full key path, e.g. `HKEY_CURRENT_USER\Software\Wine\Direct3D`.
+    pub key: String,
+    /// Name of the value. Without it, the key's default value.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The data to set. Without it the value is deleted, or the whole key if
+    /// there's no `name` either.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<RegValue>,
+}
+
+impl RegistryTweak {
+    pub fn edit(&self) -> RegEdit {
+        let key = self.key.clone();
+        match (&self.name, &self.value) {
+            (name, Some(value)) => RegEdit::Set {
+                key,
+                name: name.clone(),
+                value: value.clone(),
+            },
+            (Some(name), None) => RegEdit::DeleteValue {
+                key,
+                name: Some(name.clone()),
+            },
+            (None, None) => RegEdit::DeleteKey { key },
+        }
+    }
+}
+
+const DLL_OVERRIDES_KEY: &str = "HKEY_CURRENT_USER\\Software\\Wine\\DllOverrides";
+
+/// Edits setting Wine's DLL overrides, e.g. `"dxgi": "native,builtin"`. An
+/// empty mode removes the override.
+pub fn dll_override_edits<'a>(
+    overrides: impl IntoIterator<Item = (&'a String, &'a String)>,
+) -> Vec<RegEdit> {
+    overrides
+        .into_iter()
+        .map(|(dll, mode)| {
+            let key = DLL_OVERRIDES_KEY.to_string();
+            let name = Some(dll.to_string());
+            if mode.trim().is_empty() {
+                RegEdit::DeleteValue { key, name }
+            } else {
+                RegEdit::Set {
+                    key,
+                    name,
+                    value: RegValue::String(mode.trim().to_string()),
+                }
+            }
+        })
+        .collect()
+}
+
+/// How `apply` made the changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Applied {
+    /// The registry files were edited.
+    Files,
+    /// The changes were imported with `regedit`.
+    Regedit,
+}
+
+/// Whether a wineserver is running for `prefix`. Its socket lives in a
+/// directory named after the prefix's device and inode.
+pub fn wineserver_running(prefix: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    let Ok(metadata) = fs::metadata(prefix) else {
+        return false;
+    };
+    let uid = unsafe { libc::getuid() };
+    Path::new(&format!("/tmp/.wine-{}", uid))
+        .join(format!("server-{:x}-{:x}", metadata.dev(), metadata.ino()))
+        .join("socket")
+        .exists()
+}
+
+fn apply_to_files(prefix: &Path, edits: &[RegEdit]) -> Result<(), Box<dyn Error>> {
+    let mut files: Vec<(Hive, RegistryFile, bool)> = Vec::new();
+    for edit in edits {
+        let (hive, key) = split_key(edit.key())?;
+        let index = match files.iter().position(|(h, _, _)| *h == hive) {
+            Some(index) => index,
+            None => {
+                files.push((hive, RegistryFile::open(prefix, hive)?, false));
+                files.len() - 1
+            }
+        };
+        let (_, file, changed) = &mut files[index];
+        match edit {
+            RegEdit::Set { name, value, .. } => {
+                file.set(&key, name.as_deref(), value);
+                *changed = true;
+            }
+            RegEdit::DeleteValue { name, .. } => {
+                *changed |= file.delete_value(&key, name.as_deref());
+            }
+            RegEdit::DeleteKey { .. } => *changed |= file.delete_key(&key),
+        }
+    }
+    for (_, file, changed) in &files {
+        if *changed {
+            file.save()?;
+        }
+    }
+    Ok(())
+}
+
+/// The edits as a `regedit` import file.
+fn regedit_script(edits: &[RegEdit]) -> Result<String, Box<dyn Error>> {
+    let mut script = String::from("Windows Registry Editor Version 5.00\n");
+    let mut current: Option<&str> = None;
+    for edit in edits {
+        let (hive, key) = split_key(edit.key())?;
+        let full = format!("{}\\{}", hive.root_name(), key);
+        let full = full.trim_end_matches('\\');
+        let name_text = |name: &Option<String>| match name {
+            Some(name) => format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\"")),
+            None => "@".to_string(),
+        };
+        match edit {
+            RegEdit::DeleteKey { .. } => {
+                script.push_str(&format!("\n[-{}]\n", full));
+                current = None;
+                continue;
+            }
+            _ if current != Some(edit.key()) => {
+                script.push_str(&format!("\n[{}]\n", full));
+                current = Some(edit.key());
+            }
+            _ => {}
+        }
+        match edit {
+            RegEdit::Set { name, value, .. } => {
+                script.push_str(&format!("{}={}\n", name_text(name), value.to_regedit()));
+            }
+            RegEdit::DeleteValue { name, .. } => {
+                script.push_str(&format!("{}=-\n", name_text(name)));
+            }
+            RegEdit::DeleteKey { .. } => {}
+        }
+    }
+    script.push('\n');
+    Ok(script)
+}
+
+/// Imports the edits into `prefix` by running `regedit` with `runner`.
+pub fn apply_with_regedit(
+    prefix: &Path,
+    runner: &WineRunner,
+    edits: &[RegEdit],
+) -> Result<(), Box<dyn Error>> {
+    let script = regedit_script(edits)?;
+    let mut utf16le: Vec<u8> = vec![0xFF, 0xFE];
+    for code_unit in script.replace('\n', "\r\n").encode_utf16() {
+        utf16le.extend(&code_unit.to_le_bytes());
+    }
+    let mut tempfile = NamedTempFile::new()?;
+    tempfile.write_all(&utf16le)?;
+
+    // regedit resolves the file as a Windows path; Z: is the Unix root.
+    let reg_path = format!("Z:{}", tempfile.path().to_string_lossy().replace('/', "\\"));
+
+    // Wine sets up an empty prefix on first use.
+    fs::create_dir_all(prefix)?;
+    let status = runner
+        .command(prefix, "regedit")
+        .arg(&reg_path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::inherit())
+        .status()
+        .map_err(|e| format!("Could not run {}: {}", runner, e))?;
+    if !status.success() {
+        return Err(format!("regedit exited with {}", status).into());
+    }
+    Ok(())
+}
+
+/// Applies the edits to the registry of `prefix`. The registry files are
+/// edited directly if the prefix is set up and no wineserver is running for
+/// it; otherwise the edits are imported with `regedit` through `runner`.
+pub fn apply(
+    prefix: &Path,
+    edits: &[RegEdit],
+    runner: Option<&WineRunner>,
+) -> Result<Applied, Box<dyn Error>> {
+    let initialized = [Hive::Machine, Hive::User]
+        .iter()
+        .all(|hive| prefix.join(hive.file_name()).is_file());
+    let running = wineserver_running(prefix);
+
+    if initialized && !running {
+        match apply_to_files(prefix, edits) {
+            Ok(()) => return Ok(Applied::Files),
+            Err(e) if runner.is_some() => {
+                tracing::warn!(
+                    "[REGISTRY] Could not edit the registry files in {}, using regedit: {}",
+                    prefix.display(),
+                    e
+                );
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    let runner = runner.ok_or_else(|| {
+        if running {
+            format!(
+                "wineserver is running for {}; close the game or give a runner",
+                prefix.display()
+            )
+        } else {
+            format!(
+                "{} isn't set up yet; give a runner to create it",
+                prefix.display()
+            )
+        }
+    })?;
+    apply_with_regedit(prefix, runner, edits)?;
+    Ok(Applied::Regedit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYSTEM_REG: &str = r#"WINE REGISTRY Version 2
+;; All keys relative to \\Machine
+
+#arch=win64
+
+[Software\\Microsoft\\SystemCertificates\\Root\\Certificates] 1700000000
+#time=1da1a2b3c4d5e6f
+
+[Software\\Microsoft\\Windows NT\\CurrentVersion] 1700000000
+#time=1da1a2b3c4d5e6f
+"CurrentBuild"="19045"
+"CurrentMajorVersionNumber"=dword:0000000a
+"DigitalProductId"=hex:a4,00,00,00,03,00,00,00,30,30,33,33,30,2d,38,30,30,30,30,2d,\
+  30,30,30,30,30,2d,41,41,34,30,37,00
+"PathName"=str(2):"C:\\windows"
+"Multi"=hex(7):61,00,00,00,00,00
+
+[Software\\Wine] 1700000000
+#time=1da1a2b3c4d5e6f
+@="default"
+"Name \"quoted\""="caf\x00e9"
+"#;
+
+    const USER_REG: &str = r#"WINE REGISTRY Version 2
+;; All keys relative to \\User\\S-1-5-21-0-0-0-1000
+
+#arch=win64
+
+[Software\\Wine\\DllOverrides] 1700000000
+#time=1da1a2b3c4d5e6f
+"dxgi"="native,builtin"
+"winhttp"="native,builtin"
+
+[Software\\Wine\\Direct3D] 1700000000
+#time=1da1a2b3c4d5e6f
+"#;
+
+    fn parse(text: &str) -> RegistryFile {
+        RegistryFile::parse(Path::new("system.reg"), text).unwrap()
+    }
+
+    fn prefix() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("system.reg"), SYSTEM_REG).unwrap();
+        fs::write(dir.path().join("user.reg"), USER_REG).unwrap();
+        dir
+    }
+
+    #[test]
+    fn writes_unchanged_files_back_as_read() {
+        assert_eq!(parse(SYSTEM_REG).to_text(), SYSTEM_REG);
+        assert_eq!(parse(USER_REG).to_text(), USER_REG);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(RegistryFile::parse(Path::new("x.reg"), "REGEDIT4\n").is_err());
+        assert!(
+            RegistryFile::parse(
+                Path::new("x.reg"),
+                "WINE REGISTRY Version 2\n\n[Software] 1\n\"a\"\n"
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn reads_values() {
+        let file = parse(SYSTEM_REG);
+        let key = "Software\\Microsoft\\Windows NT\\CurrentVersion";
+        assert_eq!(
+            file.get(key, Some("currentbuild")),
+            Some(RegValue::String("19045".to_string()))
+        );
+        assert_eq!(
+            file.get(key, Some("CurrentMajorVersionNumber")),
+            Some(RegValue::Dword(10))
+        );
+        let Some(RegValue::Binary(data)) = file.get(key, Some("DigitalProductId")) else {
+            panic!("expected binary data");
+        };
+        assert_eq!(data.len(), 32);
+        assert_eq!(data.last(), Some(&0));
+        assert_eq!(
+            file.get(key, Some("PathName")),
+            Some(RegValue::ExpandString("C:\\windows".to_string()))
+        );
+        assert_eq!(
+            file.get(key, Some("Multi")),
+            Some(RegValue::Other {
+                kind: 7,
+                data: vec![0x61, 0, 0, 0, 0, 0],
+            })
+        );
+        assert_eq!(
+            file.get("SOFTWARE\\WINE", None),
+            Some(RegValue::String("default".to_string()))
+        );
+        assert_eq!(
+            file.get("Software\\Wine", Some("Name \"quoted\"")),
+            Some(RegValue::String("café".to_string()))
+        );
+        assert_eq!(file.get("Software\\Wine", Some("Missing")), None);
+        assert_eq!(file.get("Software\\Missing", None), None);
+    }
+
+    #[test]
+    fn round_trips_edits() {
+        let mut file = parse(SYSTEM_REG);
+        let values = [
+            ("String", RegValue::String("a \"b\" \\ c\nü".to_string())),
+            (
+                "Expand",
+                RegValue::ExpandString("%SystemRoot%\\x".to_string()),
+            ),
+            ("Dword", RegValue::Dword(0xdeadbeef)),
+            ("Binary", RegValue::Binary((0..=255).collect())),
+            (
+                "Other",
+                RegValue::Other {
+                    kind: 0xb,
+                    data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+                },
+            ),
+        ];
+        for (name, value) in &values {
+            file.set("Software\\New\\Key", Some(name), value);
+        }
+        file.set(
+            "Software\\Wine",
+            None,
+            &RegValue::String("changed".to_string()),
+        );
+
+        let text = file.to_text();
+        // Keys that weren't changed are kept as they were.
+        assert!(text.contains(
+            "[Software\\\\Microsoft\\\\Windows NT\\\\CurrentVersion] 1700000000\n#time=1da1a2b3c4d5e6f\n"
+        ));
+        assert!(!text.contains("[Software\\\\Wine] 1700000000"));
+
+        let file = parse(&text);
+        for (name, value) in &values {
+            assert_eq!(
+                file.get("Software\\New\\Key", Some(name)).as_ref(),
+                Some(value)
+            );
+        }
+        assert_eq!(
+            file.get("Software\\Wine", None),
+            Some(RegValue::String("changed".to_string()))
+        );
+        assert_eq!(parse(&file.to_text()).to_text(), file.to_text());
+    }
+
+    #[test]
+    fn setting_the_same_value_changes_nothing() {
+        let mut file = parse(USER_REG);
+        file.set(
+            "Software\\Wine\\DllOverrides",
+            Some("dxgi"),
+            &RegValue::String("native,builtin".to_string()),
+        );
+        assert_eq!(file.to_text(), USER_REG);
+    }
+
+    #[test]
+    fn deletes_values_and_keys() {
+        let mut file = parse(SYSTEM_REG);
+        assert!(file.delete_value("Software\\Wine", Some("name \"QUOTED\"")));
+        assert!(!file.delete_value("Software\\Wine", Some("Missing")));
+        assert!(file.delete_value("Software\\Wine", None));
+        assert!(file.delete_key("Software\\Microsoft"));
+        assert!(!file.delete_key("Software\\Microsoft"));
+
+        let file = parse(&file.to_text());
+        assert_eq!(file.get("Software\\Wine", None), None);
+        assert_eq!(
+            file.get(
+                "Software\\Microsoft\\Windows NT\\CurrentVersion",
+                Some("CurrentBuild")
+            ),
+            None
+        );
+        assert_eq!(file.keys.len(), 1);
+    }
+
+    #[test]
+    fn applies_edits_to_a_prefix() {
+        let prefix = prefix();
+        let overrides = [
+            ("d3d11".to_string(), "native".to_string()),
+            ("winhttp".to_string(), String::new()),
+        ];
+        let mut edits = dll_override_edits(overrides.iter().map(|(k, v)| (k, v)));
+        edits.push(RegEdit::Set {
+            key: "HKLM\\Software\\Wine".to_string(),
+            name: Some("Version".to_string()),
+            value: RegValue::String("win10".to_string()),
+        });
+        edits.push(RegEdit::DeleteKey {
+            key: "HKEY_CURRENT_USER\\Software\\Wine\\Direct3D".to_string(),
+        });
+        apply_to_files(prefix.path(), &edits).unwrap();
+
+        let user = RegistryFile::open(prefix.path(), Hive::User).unwrap();
+        let overrides = "Software\\Wine\\DllOverrides";
+        assert_eq!(
+            user.get(overrides, Some("d3d11")),
+            Some(RegValue::String("native".to_string()))
+        );
+        assert_eq!(
+            user.get(overrides, Some("dxgi")),
+            Some(RegValue::String("native,builtin".to_string()))
+        );
+        assert_eq!(user.get(overrides, Some("winhttp")), None);
+        assert_eq!(user.keys.len(), 1);
+
+        let system = RegistryFile::open(prefix.path(), Hive::Machine).unwrap();
+        assert_eq!(
+            system.get("Software\\Wine", Some("Version")),
+            Some(RegValue::String("win10".to_string()))
+        );
+        assert_eq!(system.keys.len(), 3);
+    }
+
+    #[test]
+    fn leaves_files_alone_without_changes() {
+        let prefix = prefix();
+        let edits = [RegEdit::DeleteValue {
+            key: "HKCU\\Software\\Wine\\DllOverrides".to_string(),
+            name: Some("missing".to_string()),
+        }];
+        apply_to_files(prefix.path(), &edits).unwrap();
+        assert_eq!(
+            fs::read_to_string(prefix.path().join("user.reg")).unwrap(),
+            USER_REG
+        );
+    }
+
+    #[test]
+    fn writes_regedit_scripts() {
+        let edits = [
+            RegEdit::Set {
+                key: "HKCU\\Software\\Wine\\DllOverrides".to_string(),
+                name: Some("dxgi".to_string()),
+                value: RegValue::String("native".to_string()),
+            },
+            RegEdit::DeleteValue {
+                key: "HKCU\\Software\\Wine\\DllOverrides".to_string(),
+                name: Some("winhttp".to_string()),
+            },
+            RegEdit::Set {
+                key: "HKCR\\.foo".to_string(),
+                name: None,
+                value: RegValue::Dword(1),
+            },
+            RegEdit::DeleteKey {
+                key: "HKLM\\Software\\Old".to_string(),
+            },
+        ];
+        assert_eq!(
+            regedit_script(&edits).unwrap(),
+            "Windows Registry Editor Version 5.00\n\
+             \n[HKEY_CURRENT_USER\\Software\\Wine\\DllOverrides]\n\
+             \"dxgi\"=\"native\"\n\
+             \"winhttp\"=-\n\
+             \n[HKEY_LOCAL_MACHINE\\Software\\Classes\\.foo]\n\
+             @=dword:00000001\n\
+             \n[-HKEY_LOCAL_MACHINE\\Software\\Old]\n\n"
+        );
+    }
+
+    #[test]
+    fn reads_tweaks_from_config() {
+        let tweaks: Vec<RegistryTweak> = serde_json::from_str(
+            r#"[
+                {"key": "HKCU\\Software\\Game", "name": "Mode", "value": {"type": "dword", "data": 0}},
+                {"key": "HKCU\\Software\\Game", "name": "Old"},
+                {"key": "HKCU\\Software\\Game\\Cache"}
+            ]"#,
+        )
+        .unwrap();
+        let edits: Vec<RegEdit> = tweaks.iter().map(RegistryTweak::edit).collect();
+        assert!(matches!(
+            &edits[0],
+            RegEdit::Set { name: Some(name), value: RegValue::Dword(0), .. } if name == "Mode"
+        ));
+        assert!(matches!(
+            &edits[1],
+            RegEdit::DeleteValue { name: Some(name), .. } if name == "Old"
+        ));
+        assert!(matches!(
+            &edits[2],
+            RegEdit::DeleteKey { key } if key == "HKCU\\Software\\Game\\Cache"
+        ));
+    }
+}
@@ -1,19 +1,68 @@
 use std::{path::PathBuf, process::Stdio};
 use tokio::process::Command as TokioCommand;
+use tokio::signal::unix::{Signal, SignalKind, signal};
 
 use crate::{
-    config::Config,
+    config::{Config, ConfigGame},
     game::{genshin::GenshinPatcher, starrail},
-    get_wine::{RunnerType, find_proton_dirs, find_wine_binaries},
+    get_wine::{RunnerType, WineRunner, find_proton_dirs, find_wine_binaries, wine_binary},
+    proxy::{ca_dir, install_cert_into_wine},
+    registry::{self, RegistryTweak},
     umu_run::UmuRun,
     utils::{GameType, select_with_arrows},
 };
 
+/// Makes the game's prefix trust the proxy CA. Failing isn't fatal, the CA may
+/// have been installed by hand.
+fn install_ca_into_prefix(runner: &WineRunner) {
+    let Some(prefix) = std::env::var_os("WINEPREFIX").map(PathBuf::from) else {
+        return;
+    };
+    let Some(cert_path) = ca_dir().map(|dir| dir.join("cert.crt")) else {
+        return;
+    };
+    if let Err(e) = install_cert_into_wine(&cert_path, &prefix, runner, false) {
+        tracing::warn!(
+            "Failed to install the proxy CA into {}: {}",
+            prefix.display(),
+            e
+        );
+        tracing::warn!("HTTPS requests from the game may fail. Continuing...");
+    }
+}
+
+/// Sets the game's DLL overrides and registry tweaks in its prefix. Failing
+/// isn't fatal, the game may run without them.
+fn apply_registry_tweaks(game: &ConfigGame, runner: &WineRunner) {
+    let Some(prefix) = std::env::var_os("WINEPREFIX").map(PathBuf::from) else {
+        return;
+    };
+    let mut edits = registry::dll_override_edits(&game.dll_overrides);
+    edits.extend(game.registry.iter().map(RegistryTweak::edit));
+    if edits.is_empty() {
+        return;
+    }
+    match registry::apply(&prefix, &edits, Some(runner)) {
+        Ok(_) => tracing::info!(
+            "Applied {} registry changes to {}",
+            edits.len(),
+            prefix.display()
+        ),
+        Err(e) => tracing::warn!(
+            "Failed to apply the registry changes to {}: {}",
+            prefix.display(),
+            e
+        ),
+    }
+}
+
 pub async fn execute_command(
     modified_args: Vec<String>,
     proxy: String,
+    socks_proxy: Option<String>,
     game_info: &crate::utils::GameInfo,
     wrapper: bool,
+    install_ca: bool,
 ) -> Result<i32, Box<dyn std::error::Error>> {
     let umu_run = UmuRun::default();
     let mut final_args = modified_args;
@@ -101,7 +150,7 @@ pub async fn execute_command(
                     "Using Wine/direct execution for command: {}",
                     final_args.join(" ")
                 );
-                final_args.insert(0, runner_dir.to_string_lossy().to_string());
+                final_args.insert(0, wine_binary(runner_dir).to_string_lossy().to_string());
             }
 
             // Determine and set WINEPREFIX: prefer configured per-game wineprefix,
@@ -161,9 +210,28 @@ pub async fn execute_command(
                     }
                 }
             }
+
+            let runner = if selected_runner_type == Some(RunnerType::Proton) {
+                WineRunner::Proton {
+                    umu_run: umu_run.binary.clone(),
+                    proton: runner_dir.clone(),
+                }
+            } else {
+                WineRunner::Wine(wine_binary(runner_dir))
+            };
+            if install_ca {
+                install_ca_into_prefix(&runner);
+            }
+            if let Some(game) = config.game_for_exe(&game_info.game_exe) {
+                apply_registry_tweaks(game, &runner);
+            }
         } else {
             tracing::info!("No wine/proton runner selected. Proceeding without runner.");
         }
+    } else if install_ca {
+        tracing::info!(
+            "Not installing the proxy CA in wrapper mode, use `anime-games-linux cert trust` if the game rejects it."
+        );
     }
 
     let mut genshin_patcher: Option<GenshinPatcher> = None;
@@ -208,12 +276,22 @@ pub async fn execute_command(
 
     tracing::info!("Executing command: {}", final_args.join(" "));
 
+    let mut proxy_env = vec![
+        ("http_proxy", proxy.clone()),
+        ("https_proxy", proxy.clone()),
+    ];
+    // For programs that only understand SOCKS.
+    if let Some(socks_proxy) = socks_proxy {
+        proxy_env.push(("ALL_PROXY", socks_proxy.clone()));
+        proxy_env.push(("all_proxy", socks_proxy));
+    }
+
+    let mut sigterm =
+        signal(SignalKind::terminate()).expect("Failed to install SIGTERM signal handler");
+
     let mut child = TokioCommand::new(&final_args[0])
         .args(&final_args[1..])
-        .envs(vec![
-            ("http_proxy", proxy.clone()),
-            ("https_proxy", proxy.clone()),
-        ])
+        .envs(proxy_env)
         .stdin(Stdio::inherit())
         .stdout(Stdio::inherit())
         .stderr(Stdio::inherit())
@@ -224,6 +302,7 @@ pub async fn execute_command(
     tracing::info!("Started process ({})", child_pid);
 
     // Wait for either the child to exit or Ctrl-C. On Ctrl-C, forward SIGINT to child.
+    // SIGTERM is handled the same way.
     //
     // Rationale: previously the wrapper would block waiting for the child and then
     // enter a long polling loop for the real game process (StarRail.exe). That
@@ -238,8 +317,8 @@ pub async fn execute_command(
             tracing::info!("Command exited with status: {}", code);
             code
         }
-        _ = tokio::signal::ctrl_c() => {
-            tracing::info!("Received Ctrl-C: forwarding SIGINT to child (pid={})", child_pid);
+        _ = interrupted(&mut sigterm) => {
+            tracing::info!("Interrupted: forwarding SIGINT to child (pid={})", child_pid);
             if child_pid != 0 {
                 unsafe { libc::kill(child_pid as i32, libc::SIGINT); }
             }
@@ -267,8 +346,8 @@ pub async fn execute_command(
             }
             tokio::select! {
                 _ = tokio::time::sleep(tokio::time::Duration::from_secs(5)) => {},
-                _ = tokio::signal::ctrl_c() => {
-                    tracing::info!("Received Ctrl-C while waiting for game to start");
+                _ = interrupted(&mut sigterm) => {
+                    tracing::info!("Interrupted while waiting for game to start");
                     return Ok(exit_code);
                 }
             }
@@ -291,8 +370,8 @@ pub async fn execute_command(
                 }
                 tokio::select! {
                     _ = tokio::time::sleep(tokio::time::Duration::from_secs(5)) => {},
-                    _ = tokio::signal::ctrl_c() => {
-                        tracing::info!("Received Ctrl-C while monitoring game process");
+                    _ = interrupted(&mut sigterm) => {
+                        tracing::info!("Interrupted while monitoring game process");
                         break;
                     }
                 }
@@ -314,3 +393,11 @@ pub async fn execute_command(
 
     Ok(exit_code)
 }
+
+/// Completes on Ctrl-C or SIGTERM.
+async fn interrupted(sigterm: &mut Signal) {
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
+    }
+}